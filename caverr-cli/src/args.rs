@@ -1,8 +1,18 @@
-use crate::args::Command::{Decrypt, Encrypt};
+use crate::args::Command::{Decrypt, Diff, Encrypt, KeyInfo, Prune, Snapshots, Verify};
 use crate::Command::GenKeys;
+use caverr_lib::cipher::CipherSuite;
+use caverr_lib::compression::Compression;
+use caverr_lib::padding::Padding;
+use caverr_lib::retention::Retention;
+use caverr_lib::verify::Comparison;
+use caverr_lib::worker::rsa::{MAX_KEY_BITS, MIN_KEY_BITS};
 use clap::Parser;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::SystemTime;
+
+/// Path standing for stdin as the source and stdout as the target.
+pub(super) const STDIO: &str = "-";
 
 #[derive(Parser, Debug)]
 pub(super) struct Args {
@@ -10,55 +20,546 @@ pub(super) struct Args {
     #[clap(short, long, value_parser)]
     pub(super) command: Command,
 
-    /// Key / password / setup  file
+    /// Key / password / setup  file. When encrypting it may be given many times,
+    /// every public key found in the given files becomes a recipient
     #[clap(short, long, value_parser)]
-    pub(super) key: Option<PathBuf>,
+    pub(super) key: Vec<PathBuf>,
 
-    /// Source file / directory
+    /// Source file / directory, `-` for stdin. The backup directory when verifying
     #[clap(short, long, value_parser)]
     pub(super) source: Option<PathBuf>,
 
-    /// Target directory, must exist
+    /// Target directory, must exist. `-` for stdout, or a file when the source is stdin. When
+    /// verifying, the directory source files are found in at their original paths, `/` by default
     #[clap(short, long, value_parser)]
     pub(super) target: Option<PathBuf>,
+
+    /// Cipher for file content when encrypting: aes-256-gcm (default) or chacha20-poly1305
+    #[clap(long, value_parser)]
+    pub(super) cipher: Option<CipherSuite>,
+
+    /// Pad file content when encrypting to hide its size: padme, power-of-two or none (default)
+    #[clap(long, value_parser)]
+    pub(super) padding: Option<Padding>,
+
+    /// Compress file content when encrypting: zstd or none (default). Files that are compressed
+    /// already (jpg, zip, mp4...) are left as they are
+    #[clap(long, value_parser)]
+    pub(super) compression: Option<Compression>,
+
+    /// Size of generated RSA keys in bits, 4096 by default
+    #[clap(long, value_parser)]
+    pub(super) bits: Option<usize>,
+
+    /// Write generated keys to `public.pem` and `private.pem` in this directory instead of stdout
+    #[clap(long, value_parser)]
+    pub(super) out_dir: Option<PathBuf>,
+
+    /// Protect the generated private key with a passphrase
+    #[clap(long, action)]
+    pub(super) encrypt_key: bool,
+
+    /// Encrypt or decrypt with a passphrase instead of RSA keys
+    #[clap(long, action)]
+    pub(super) symmetric: bool,
+
+    /// Encrypt file and directory names in the target directory
+    #[clap(long, action)]
+    pub(super) encrypt_names: bool,
+
+    /// Key for names when encrypting them with RSA keys, created when missing. Keep it
+    /// private, it's needed to recognize files encrypted before
+    #[clap(long, value_parser)]
+    pub(super) names_key: Option<PathBuf>,
+
+    /// Split content into chunks stored once in a repository in the target directory, so
+    /// unchanged parts of files aren't stored again
+    #[clap(long, action)]
+    pub(super) repository: bool,
+
+    /// Key for the repository when encrypting with RSA keys, created when missing. Keep it
+    /// private, it's needed to recognize chunks stored before
+    #[clap(long, value_parser)]
+    pub(super) repository_key: Option<PathBuf>,
+
+    /// Keep a journal in the target directory while encrypting, so a run started after an
+    /// interrupted one skips files it encrypted and resumes large files where it stopped
+    #[clap(long, action)]
+    pub(super) journal: bool,
+
+    /// Key for the journal when encrypting with RSA keys, created when missing. Keep it
+    /// private, it's needed to resume encrypting large files
+    #[clap(long, value_parser)]
+    pub(super) journal_key: Option<PathBuf>,
+
+    /// Index of encrypted files telling unchanged ones by their state and content hash, kept
+    /// in the user's cache directory by default
+    #[clap(long, value_parser)]
+    pub(super) index: Option<PathBuf>,
+
+    /// Hash content of every file when encrypting to tell whether it changed, even when its
+    /// size and times didn't change
+    #[clap(long, action)]
+    pub(super) checksum: bool,
+
+    /// Keep replaced encrypted files as versions named after the time they were written,
+    /// until pruned. Snapshots can only be restored from versions kept this way
+    #[clap(long, action)]
+    pub(super) versions: bool,
+
+    /// Delete encrypted files whose source files are gone when encrypting. They are kept as
+    /// versions until pruned when `versions` is given
+    #[clap(long, action)]
+    pub(super) delete: bool,
+
+    /// Most encrypted files to delete in a run, 100 by default. Nothing is deleted when
+    /// there are more
+    #[clap(long, value_parser)]
+    pub(super) max_delete: Option<usize>,
+
+    /// Snapshot to restore when decrypting, or to compare with `diff` (given twice to compare
+    /// two snapshots). Either a full id or its unique prefix
+    #[clap(long, value_parser)]
+    pub(super) snapshot: Vec<String>,
+
+    /// Restore files as they were at this time when decrypting, e.g. `2026-10-13` (end of the
+    /// day) or `2026-10-13 18:00:00`, in UTC. Taken from the last snapshot before it
+    #[clap(long, value_parser = parse_time)]
+    pub(super) at: Option<SystemTime>,
+
+    /// Number of the last versions of every encrypted file and of snapshots to keep when pruning
+    #[clap(long, value_parser)]
+    pub(super) keep_last: Option<usize>,
+
+    /// Number of days to keep the last version of when pruning
+    #[clap(long, value_parser)]
+    pub(super) keep_daily: Option<usize>,
+
+    /// Number of weeks to keep the last version of when pruning
+    #[clap(long, value_parser)]
+    pub(super) keep_weekly: Option<usize>,
+
+    /// Number of months to keep the last version of when pruning
+    #[clap(long, value_parser)]
+    pub(super) keep_monthly: Option<usize>,
+
+    /// Only list what pruning would remove
+    #[clap(long, action)]
+    pub(super) dry_run: bool,
+
+    /// Compare decrypted content with its source file when verifying: bytes or hash
+    #[clap(long, value_parser)]
+    pub(super) compare: Option<Comparison>,
+
+    /// Read passphrase from this file descriptor instead of `CAVERR_PASSPHRASE` or a prompt
+    #[clap(long, value_parser)]
+    pub(super) passphrase_fd: Option<u32>,
 }
 
 pub(crate) fn validate_args(args: &Args) -> Result<(), String> {
     match args.command {
         GenKeys => validate_get_keys(args),
-        Decrypt | Encrypt => validate_transform(args),
+        Decrypt => validate_decrypt(args),
+        Encrypt => validate_encrypt(args),
+        KeyInfo => validate_key_info(args),
+        Snapshots => validate_snapshots(args),
+        Diff => validate_diff(args),
+        Prune => validate_prune(args),
+        Verify => validate_verify(args),
+    }
+}
+
+fn validate_encrypt(args: &Args) -> Result<(), String> {
+    if args.encrypt_key {
+        Err("Error: `encrypt-key` argument given when encrypting".into())
+    } else if args.passphrase_fd.is_some() && !args.symmetric {
+        Err("Error: `passphrase-fd` argument given without `symmetric`".into())
+    } else if args.names_key.is_some() && !args.encrypt_names {
+        Err("Error: `names-key` argument given without `encrypt-names`".into())
+    } else if args.names_key.is_some() && args.symmetric {
+        Err("Error: `names-key` argument given with `symmetric`".into())
+    } else if args.encrypt_names && !args.symmetric && args.names_key.is_none() {
+        Err("Error: `names-key` argument not given".into())
+    } else if args.repository_key.is_some() && !args.repository {
+        Err("Error: `repository-key` argument given without `repository`".into())
+    } else if args.repository_key.is_some() && args.symmetric {
+        Err("Error: `repository-key` argument given with `symmetric`".into())
+    } else if args.repository && !args.symmetric && args.repository_key.is_none() {
+        Err("Error: `repository-key` argument not given".into())
+    } else if args.journal_key.is_some() && !args.journal {
+        Err("Error: `journal-key` argument given without `journal`".into())
+    } else if args.journal_key.is_some() && args.symmetric {
+        Err("Error: `journal-key` argument given with `symmetric`".into())
+    } else if args.journal && !args.symmetric && args.journal_key.is_none() {
+        Err("Error: `journal-key` argument not given".into())
+    } else if args.max_delete.is_some() && !args.delete {
+        Err("Error: `max-delete` argument given without `delete`".into())
+    } else if !args.snapshot.is_empty() {
+        Err("Error: `snapshot` argument given when encrypting".into())
+    } else if args.at.is_some() {
+        Err("Error: `at` argument given when encrypting".into())
+    } else {
+        validate_transform(args)
+    }
+}
+
+fn validate_decrypt(args: &Args) -> Result<(), String> {
+    if args.key.len() > 1 {
+        Err("Error: only one `key` argument allowed when decrypting".into())
+    } else if args.cipher.is_some() {
+        Err("Error: `cipher` argument given when decrypting".into())
+    } else if args.padding.is_some() {
+        Err("Error: `padding` argument given when decrypting".into())
+    } else if args.compression.is_some() {
+        Err("Error: `compression` argument given when decrypting".into())
+    } else if args.encrypt_key {
+        Err("Error: `encrypt-key` argument given when decrypting".into())
+    } else if args.encrypt_names || args.names_key.is_some() {
+        Err("Error: names are decrypted without `encrypt-names` and `names-key` arguments".into())
+    } else if args.repository || args.repository_key.is_some() {
+        Err(
+            "Error: repositories are found without `repository` and `repository-key` arguments"
+                .into(),
+        )
+    } else if args.delete || args.max_delete.is_some() {
+        Err("Error: `delete` argument given when decrypting".into())
+    } else if args.journal || args.journal_key.is_some() {
+        Err("Error: `journal` argument given when decrypting".into())
+    } else if args.versions {
+        Err("Error: `versions` argument given when decrypting".into())
+    } else if args.index.is_some() || args.checksum {
+        Err("Error: `index` and `checksum` arguments given when decrypting".into())
+    } else if args.snapshot.len() > 1 {
+        Err("Error: only one `snapshot` argument allowed when decrypting".into())
+    } else if args.at.is_some() && !args.snapshot.is_empty() {
+        Err("Error: `at` argument given with `snapshot`".into())
+    } else {
+        validate_transform(args)
     }
 }
 
 fn validate_transform(args: &Args) -> Result<(), String> {
-    if args.key.is_none() {
+    if args.bits.is_some() {
+        Err("Error: `bits` argument given when not generating keys".into())
+    } else if args.out_dir.is_some() {
+        Err("Error: `out-dir` argument given when not generating keys".into())
+    } else if args.symmetric && !args.key.is_empty() {
+        Err("Error: `key` argument given with `symmetric`".into())
+    } else if !args.symmetric && args.key.is_empty() {
         Err("Error: `key` argument not given".into())
     } else if args.source.is_none() {
         Err("Error: `source` argument not given".into())
     } else if args.target.is_none() {
         Err("Error: `target` argument not given".into())
+    } else if has_prune_args(args) {
+        Err("Error: pruning arguments given when not pruning".into())
+    } else if args.compare.is_some() {
+        Err("Error: `compare` argument given when not verifying".into())
+    } else if is_stream(args) {
+        validate_stream(args)
+    } else {
+        Ok(())
+    }
+}
+
+fn validate_stream(args: &Args) -> Result<(), String> {
+    if args.encrypt_names || args.names_key.is_some() {
+        Err("Error: `encrypt-names` argument given with stdin or stdout".into())
+    } else if args.repository || args.repository_key.is_some() {
+        Err("Error: `repository` argument given with stdin or stdout".into())
+    } else if args.delete || args.max_delete.is_some() {
+        Err("Error: `delete` argument given with stdin or stdout".into())
+    } else if args.journal || args.journal_key.is_some() {
+        Err("Error: `journal` argument given with stdin or stdout".into())
+    } else if args.versions {
+        Err("Error: `versions` argument given with stdin or stdout".into())
+    } else if args.index.is_some() || args.checksum {
+        Err("Error: `index` and `checksum` arguments given with stdin or stdout".into())
+    } else if !args.snapshot.is_empty() || args.at.is_some() {
+        Err("Error: `snapshot` and `at` arguments given with stdin or stdout".into())
     } else {
         Ok(())
     }
 }
 
+/// Tells whether stdin or stdout is given instead of files.
+pub(super) fn is_stream(args: &Args) -> bool {
+    [&args.source, &args.target]
+        .into_iter()
+        .any(|path| path.as_deref() == Some(Path::new(STDIO)))
+}
+
 fn validate_get_keys(args: &Args) -> Result<(), String> {
-    if !matches!(args.key, None) {
+    if !args.key.is_empty() {
         Err("Error: `key` argument given when generating keys".into())
-    } else if !matches!(args.source, None) {
+    } else if args.source.is_some() {
         Err("Error: `source` argument given when generating keys".into())
-    } else if !matches!(args.target, None) {
+    } else if args.target.is_some() {
         Err("Error: `target` argument given when generating keys".into())
+    } else if args.cipher.is_some() {
+        Err("Error: `cipher` argument given when generating keys".into())
+    } else if args.padding.is_some() {
+        Err("Error: `padding` argument given when generating keys".into())
+    } else if args.compression.is_some() {
+        Err("Error: `compression` argument given when generating keys".into())
+    } else if args.symmetric {
+        Err("Error: `symmetric` argument given when generating keys".into())
+    } else if args.encrypt_names || args.names_key.is_some() {
+        Err("Error: `encrypt-names` argument given when generating keys".into())
+    } else if args.repository || args.repository_key.is_some() {
+        Err("Error: `repository` argument given when generating keys".into())
+    } else if !args.snapshot.is_empty() {
+        Err("Error: `snapshot` argument given when generating keys".into())
+    } else if args.at.is_some() {
+        Err("Error: `at` argument given when generating keys".into())
+    } else if let Some(bits) = args.bits.filter(|bits| !is_valid_key_size(*bits)) {
+        Err(format!(
+            "Error: invalid key size {}, must be a multiple of 8 between {} and {}",
+            bits, MIN_KEY_BITS, MAX_KEY_BITS
+        ))
+    } else if args.passphrase_fd.is_some() && !args.encrypt_key {
+        Err("Error: `passphrase-fd` argument given without `encrypt-key`".into())
+    } else if has_prune_args(args) {
+        Err("Error: pruning arguments given when generating keys".into())
+    } else if args.delete || args.max_delete.is_some() {
+        Err("Error: `delete` argument given when generating keys".into())
+    } else if args.journal || args.journal_key.is_some() {
+        Err("Error: `journal` argument given when generating keys".into())
+    } else if args.versions {
+        Err("Error: `versions` argument given when generating keys".into())
+    } else if args.index.is_some() || args.checksum {
+        Err("Error: `index` and `checksum` arguments given when generating keys".into())
+    } else if args.compare.is_some() {
+        Err("Error: `compare` argument given when generating keys".into())
+    } else if args.out_dir.as_ref().is_some_and(|dir| !dir.is_dir()) {
+        Err("Error: `out-dir` directory doesn't exist".into())
     } else {
         Ok(())
     }
 }
 
+fn validate_key_info(args: &Args) -> Result<(), String> {
+    if args.key.is_empty() {
+        Err("Error: `key` argument not given".into())
+    } else if args.source.is_some() {
+        Err("Error: `source` argument given when showing keys".into())
+    } else if args.target.is_some() {
+        Err("Error: `target` argument given when showing keys".into())
+    } else if args.cipher.is_some() {
+        Err("Error: `cipher` argument given when showing keys".into())
+    } else if args.padding.is_some() {
+        Err("Error: `padding` argument given when showing keys".into())
+    } else if args.compression.is_some() {
+        Err("Error: `compression` argument given when showing keys".into())
+    } else if args.bits.is_some() {
+        Err("Error: `bits` argument given when showing keys".into())
+    } else if args.out_dir.is_some() {
+        Err("Error: `out-dir` argument given when showing keys".into())
+    } else if args.encrypt_key {
+        Err("Error: `encrypt-key` argument given when showing keys".into())
+    } else if args.symmetric {
+        Err("Error: `symmetric` argument given when showing keys".into())
+    } else if args.encrypt_names || args.names_key.is_some() {
+        Err("Error: `encrypt-names` argument given when showing keys".into())
+    } else if args.repository || args.repository_key.is_some() {
+        Err("Error: `repository` argument given when showing keys".into())
+    } else if !args.snapshot.is_empty() {
+        Err("Error: `snapshot` argument given when showing keys".into())
+    } else if args.at.is_some() {
+        Err("Error: `at` argument given when showing keys".into())
+    } else if has_prune_args(args) {
+        Err("Error: pruning arguments given when showing keys".into())
+    } else if args.delete || args.max_delete.is_some() {
+        Err("Error: `delete` argument given when showing keys".into())
+    } else if args.journal || args.journal_key.is_some() {
+        Err("Error: `journal` argument given when showing keys".into())
+    } else if args.versions {
+        Err("Error: `versions` argument given when showing keys".into())
+    } else if args.index.is_some() || args.checksum {
+        Err("Error: `index` and `checksum` arguments given when showing keys".into())
+    } else if args.compare.is_some() {
+        Err("Error: `compare` argument given when showing keys".into())
+    } else {
+        Ok(())
+    }
+}
+
+fn validate_snapshots(args: &Args) -> Result<(), String> {
+    if !args.snapshot.is_empty() {
+        Err("Error: `snapshot` argument given when listing snapshots".into())
+    } else {
+        validate_read_snapshots(args)
+    }
+}
+
+fn validate_diff(args: &Args) -> Result<(), String> {
+    if args.snapshot.len() > 2 {
+        Err("Error: at most two `snapshot` arguments allowed when comparing snapshots".into())
+    } else {
+        validate_read_snapshots(args)
+    }
+}
+
+fn validate_read_snapshots(args: &Args) -> Result<(), String> {
+    if args.key.len() > 1 {
+        Err("Error: only one `key` argument allowed when reading snapshots".into())
+    } else if args.symmetric && !args.key.is_empty() {
+        Err("Error: `key` argument given with `symmetric`".into())
+    } else if !args.symmetric && args.key.is_empty() {
+        Err("Error: `key` argument not given".into())
+    } else if args.source.is_none() {
+        Err("Error: `source` argument not given".into())
+    } else if args.target.is_some() {
+        Err("Error: `target` argument given when reading snapshots".into())
+    } else if args.cipher.is_some() || args.padding.is_some() || args.compression.is_some() {
+        Err("Error: encryption arguments given when reading snapshots".into())
+    } else if args.bits.is_some() || args.out_dir.is_some() || args.encrypt_key {
+        Err("Error: key generation arguments given when reading snapshots".into())
+    } else if args.encrypt_names || args.names_key.is_some() {
+        Err("Error: `encrypt-names` argument given when reading snapshots".into())
+    } else if args.repository || args.repository_key.is_some() {
+        Err("Error: `repository` argument given when reading snapshots".into())
+    } else if args.at.is_some() {
+        Err("Error: `at` argument given when reading snapshots".into())
+    } else if has_prune_args(args) {
+        Err("Error: pruning arguments given when reading snapshots".into())
+    } else if args.delete || args.max_delete.is_some() {
+        Err("Error: `delete` argument given when reading snapshots".into())
+    } else if args.journal || args.journal_key.is_some() {
+        Err("Error: `journal` argument given when reading snapshots".into())
+    } else if args.versions {
+        Err("Error: `versions` argument given when reading snapshots".into())
+    } else if args.index.is_some() || args.checksum {
+        Err("Error: `index` and `checksum` arguments given when reading snapshots".into())
+    } else if args.compare.is_some() {
+        Err("Error: `compare` argument given when reading snapshots".into())
+    } else {
+        Ok(())
+    }
+}
+
+fn validate_prune(args: &Args) -> Result<(), String> {
+    if args.key.len() > 1 {
+        Err("Error: only one `key` argument allowed when pruning".into())
+    } else if args.symmetric && !args.key.is_empty() {
+        Err("Error: `key` argument given with `symmetric`".into())
+    } else if !args.symmetric && args.key.is_empty() {
+        Err("Error: `key` argument not given".into())
+    } else if args.source.is_none() {
+        Err("Error: `source` argument not given".into())
+    } else if args.target.is_some() {
+        Err("Error: `target` argument given when pruning".into())
+    } else if args.cipher.is_some() || args.padding.is_some() || args.compression.is_some() {
+        Err("Error: encryption arguments given when pruning".into())
+    } else if args.bits.is_some() || args.out_dir.is_some() || args.encrypt_key {
+        Err("Error: key generation arguments given when pruning".into())
+    } else if args.encrypt_names || args.names_key.is_some() {
+        Err("Error: `encrypt-names` argument given when pruning".into())
+    } else if args.repository || args.repository_key.is_some() {
+        Err("Error: `repository` argument given when pruning".into())
+    } else if !args.snapshot.is_empty() || args.at.is_some() {
+        Err("Error: `snapshot` and `at` arguments given when pruning".into())
+    } else if args.delete || args.max_delete.is_some() {
+        Err("Error: `delete` argument given when pruning".into())
+    } else if args.journal || args.journal_key.is_some() {
+        Err("Error: `journal` argument given when pruning".into())
+    } else if args.versions {
+        Err("Error: `versions` argument given when pruning".into())
+    } else if args.index.is_some() || args.checksum {
+        Err("Error: `index` and `checksum` arguments given when pruning".into())
+    } else if args.compare.is_some() {
+        Err("Error: `compare` argument given when pruning".into())
+    } else if retention(args).is_empty() {
+        Err("Error: `keep-last`, `keep-daily`, `keep-weekly` or `keep-monthly` argument not given, or all are 0".into())
+    } else {
+        Ok(())
+    }
+}
+
+fn validate_verify(args: &Args) -> Result<(), String> {
+    if args.key.len() > 1 {
+        Err("Error: only one `key` argument allowed when verifying".into())
+    } else if args.symmetric && !args.key.is_empty() {
+        Err("Error: `key` argument given with `symmetric`".into())
+    } else if !args.symmetric && args.key.is_empty() {
+        Err("Error: `key` argument not given".into())
+    } else if args.source.is_none() {
+        Err("Error: `source` argument not given".into())
+    } else if is_stream(args) {
+        Err("Error: stdin and stdout can't be verified".into())
+    } else if args.target.is_some() && args.compare.is_none() {
+        Err("Error: `target` argument given without `compare`".into())
+    } else if args.cipher.is_some() || args.padding.is_some() || args.compression.is_some() {
+        Err("Error: encryption arguments given when verifying".into())
+    } else if args.bits.is_some() || args.out_dir.is_some() || args.encrypt_key {
+        Err("Error: key generation arguments given when verifying".into())
+    } else if args.encrypt_names || args.names_key.is_some() {
+        Err("Error: names are decrypted without `encrypt-names` and `names-key` arguments".into())
+    } else if args.repository || args.repository_key.is_some() {
+        Err(
+            "Error: repositories are found without `repository` and `repository-key` arguments"
+                .into(),
+        )
+    } else if !args.snapshot.is_empty() || args.at.is_some() {
+        Err("Error: `snapshot` and `at` arguments given when verifying".into())
+    } else if has_prune_args(args) {
+        Err("Error: pruning arguments given when verifying".into())
+    } else if args.delete || args.max_delete.is_some() {
+        Err("Error: `delete` argument given when verifying".into())
+    } else if args.journal || args.journal_key.is_some() {
+        Err("Error: `journal` argument given when verifying".into())
+    } else if args.versions {
+        Err("Error: `versions` argument given when verifying".into())
+    } else if args.index.is_some() || args.checksum {
+        Err("Error: `index` and `checksum` arguments given when verifying".into())
+    } else {
+        Ok(())
+    }
+}
+
+fn has_prune_args(args: &Args) -> bool {
+    args.keep_last.is_some()
+        || args.keep_daily.is_some()
+        || args.keep_weekly.is_some()
+        || args.keep_monthly.is_some()
+        || args.dry_run
+}
+
+/// Versions kept by the `keep-*` arguments.
+pub(super) fn retention(args: &Args) -> Retention {
+    Retention {
+        last: args.keep_last.unwrap_or(0),
+        daily: args.keep_daily.unwrap_or(0),
+        weekly: args.keep_weekly.unwrap_or(0),
+        monthly: args.keep_monthly.unwrap_or(0),
+    }
+}
+
+/// Reads a UTC date, meaning the end of that day, or a date with time.
+fn parse_time(time: &str) -> Result<SystemTime, String> {
+    let time = time.trim();
+    let parsed = if time.len() == 10 {
+        humantime::parse_rfc3339_weak(&format!("{} 23:59:59.999999999", time))
+    } else {
+        humantime::parse_rfc3339_weak(time)
+    };
+    parsed.map_err(|e| format!("invalid time `{}`: {}", time, e))
+}
+
+fn is_valid_key_size(bits: usize) -> bool {
+    (MIN_KEY_BITS..=MAX_KEY_BITS).contains(&bits) && bits.is_multiple_of(8)
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub(super) enum Command {
     GenKeys,
     Decrypt,
     Encrypt,
+    KeyInfo,
+    Snapshots,
+    Diff,
+    Prune,
+    Verify,
 }
 
 impl FromStr for Command {
@@ -69,7 +570,12 @@ impl FromStr for Command {
             "enc" => Ok(Encrypt),
             "dec" => Ok(Decrypt),
             "keys" => Ok(GenKeys),
-            other => Err(format!("Invalid command `{}`. Must be either: `keys` to generate keys, `enc` for encryption, `dec` for decryption", other))
+            "key-info" => Ok(KeyInfo),
+            "snapshots" => Ok(Snapshots),
+            "diff" => Ok(Diff),
+            "prune" => Ok(Prune),
+            "verify" => Ok(Verify),
+            other => Err(format!("Invalid command `{}`. Must be either: `keys` to generate keys, `key-info` to show keys, `enc` for encryption, `dec` for decryption, `snapshots` to list snapshots, `diff` to compare snapshots, `prune` to remove old versions, `verify` to check a backup decrypts", other))
         }
     }
 }
@@ -3,4 +3,14 @@ pub(crate) enum ExitCodes {
     KeyGenerationError,
     UnableToWriteKeys,
     EncryptorError,
+    PassphraseError,
+    UnableToReadKey,
+    KeysMismatch,
+    SnapshotError,
+    PruneError,
+    DeleteError,
+    StreamError,
+    VerifyError,
+    FinishError,
+    SnapshotNotFound,
 }
@@ -17,22 +17,40 @@
 #[global_allocator]
 static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
-use crate::args::{validate_args, Args, Command};
+use crate::args::{is_stream, retention, validate_args, Args, Command, STDIO};
 use crate::exit_codes::ExitCodes;
+use crate::passphrase::read_passphrase;
+use caverr_lib::index::default_index_file;
+use caverr_lib::mirror::DEFAULT_MAX_DELETIONS;
+use caverr_lib::retention::prune;
+use caverr_lib::snapshot::{Change, Manifest};
 use caverr_lib::stats::StatHandler;
-use caverr_lib::worker::rsa::handler::{RsaHandler, Transformed};
-use caverr_lib::worker::rsa::keys::{generate_keys, write_private_key, write_public_key};
+use caverr_lib::verify::Comparison;
+use caverr_lib::worker::pass::handler::PassHandler;
+use caverr_lib::worker::rsa::handler::RsaHandler;
+use caverr_lib::worker::rsa::info::{key_info, KeyKind};
+use caverr_lib::worker::rsa::keys::{
+    fingerprint_to_hex, generate_keys, is_encrypted_private_key, write_encrypted_private_key,
+    write_keys_to_dir, write_private_key, write_public_key,
+};
+use caverr_lib::worker::rsa::DEFAULT_KEY_BITS;
+use caverr_lib::worker::{Handler, SnapshotReader, StreamHandler, Transformed, Verifier};
 use clap::Parser;
 use rayon::iter::IntoParallelIterator;
+use rayon::iter::IntoParallelRefIterator;
 use rayon::iter::ParallelIterator;
-use std::fs::read_dir;
-use std::io::stdout;
+use std::fmt::Debug;
+use std::fs::{read_dir, File};
+use std::io::{stdin, stdout, Read};
 use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread;
+use std::time::SystemTime;
 
 mod args;
 mod exit_codes;
+mod passphrase;
 
 fn main() {
     let args = Args::parse();
@@ -41,18 +59,115 @@ fn main() {
         exit(ExitCodes::InvalidArgs as i32);
     }
     if args.command == Command::GenKeys {
-        get_new_keys();
+        get_new_keys(&args);
+        exit(0);
+    }
+    if args.command == Command::KeyInfo {
+        show_key_info(&args);
+        exit(0);
+    }
+    if matches!(args.command, Command::Snapshots | Command::Diff) {
+        show_snapshots(&args);
+        exit(0);
+    }
+    if args.command == Command::Prune {
+        prune_versions(&args);
+        exit(0);
+    }
+    if args.command == Command::Verify {
+        verify_backup(&args);
+        exit(0);
+    }
+    if is_stream(&args) {
+        transform_stream(&args);
         exit(0);
     }
     let start = std::time::Instant::now();
     let stat_handler = start_stat_handler();
-    let producer = if args.command == Command::Decrypt {
-        get_decryptor(&args.key.unwrap(), &args.target.unwrap())
+    let source = args.source.clone().unwrap();
+    let target = args.target.clone().unwrap();
+    let cipher = args.cipher.unwrap_or_default();
+    let padding = args.padding.unwrap_or_default();
+    let compression = args.compression.unwrap_or_default();
+    let max_deletions = args.max_delete.unwrap_or(DEFAULT_MAX_DELETIONS);
+    let decrypt = args.command == Command::Decrypt;
+    if args.symmetric {
+        let producer = get_pass_handler(decrypt, &target, args.passphrase_fd)
+            .with_cipher(cipher)
+            .with_padding(padding)
+            .with_compression(compression);
+        let producer = if args.encrypt_names {
+            with_encrypted_names(producer.with_encrypted_names())
+        } else {
+            producer
+        };
+        let producer = if args.repository {
+            with_repository(producer.with_repository())
+        } else {
+            producer
+        };
+        if decrypt {
+            decrypt_dir(source, producer, &args, stat_handler.clone());
+        } else {
+            let producer = if args.versions {
+                producer.with_versions()
+            } else {
+                producer
+            };
+            let producer = with_snapshot(producer.with_snapshot(&[&source]));
+            let producer = match index_file(&args, &target) {
+                Some(index) => with_index(producer.with_index(&index, args.checksum)),
+                None => producer,
+            };
+            let producer = if args.delete {
+                with_deletions(producer.with_deletions(&[&source], max_deletions))
+            } else {
+                producer
+            };
+            let producer = if args.journal {
+                with_journal(producer.with_journal())
+            } else {
+                producer
+            };
+            walk_dir(source, producer, stat_handler.clone());
+        }
+    } else if decrypt {
+        let producer = get_decryptor(&args.key[0], &target, args.passphrase_fd);
+        decrypt_dir(source, producer, &args, stat_handler.clone());
     } else {
-        get_encryptor(&args.key.unwrap(), &args.target.unwrap())
-    };
-
-    walk_dir(args.source.unwrap(), producer, stat_handler.clone());
+        let producer = get_encryptor(&args.key, &target)
+            .with_cipher(cipher)
+            .with_padding(padding)
+            .with_compression(compression);
+        let producer = match &args.names_key {
+            Some(names_key) => with_encrypted_names(producer.with_encrypted_names(names_key)),
+            None => producer,
+        };
+        let producer = match &args.repository_key {
+            Some(repository_key) => with_repository(producer.with_repository(repository_key)),
+            None => producer,
+        };
+        let producer = if args.versions {
+            producer.with_versions()
+        } else {
+            producer
+        };
+        let producer = with_snapshot(producer.with_snapshot(&[&source]));
+        let producer = match index_file(&args, &target) {
+            Some(index) => with_index(producer.with_index(&index, args.checksum)),
+            None => producer,
+        };
+        let producer = if args.delete {
+            with_deletions(producer.with_deletions(&[&source], max_deletions))
+        } else {
+            producer
+        };
+        let producer = match &args.journal_key {
+            Some(journal_key) => with_journal(producer.with_journal(journal_key)),
+            None => producer,
+        };
+        walk_dir(source, producer, stat_handler.clone());
+    }
     let stats = stat_handler.current();
     println!(
         "Processed {} files ({} bytes) in {} seconds.",
@@ -66,7 +181,7 @@ fn show_stats_at_signal(handler: StatHandler) {
     use signal_hook::consts::SIGHUP;
     use signal_hook::iterator::Signals;
 
-    let signals = Signals::new(&[SIGHUP]);
+    let signals = Signals::new([SIGHUP]);
     thread::spawn(move || {
         for _ in signals.expect("Unable to register signals").forever() {
             let stats = handler.current();
@@ -75,8 +190,14 @@ fn show_stats_at_signal(handler: StatHandler) {
     });
 }
 
-fn get_decryptor(key: &Path, target: &Path) -> RsaHandler {
-    match RsaHandler::decryptor(key, target) {
+fn get_decryptor(key: &Path, target: &Path, passphrase_fd: Option<u32>) -> RsaHandler {
+    let decryptor = if is_encrypted_private_key(key).unwrap_or(false) {
+        let passphrase = get_passphrase(passphrase_fd, false);
+        RsaHandler::decryptor_with_passphrase(key, &passphrase, target)
+    } else {
+        RsaHandler::decryptor(key, target)
+    };
+    match decryptor {
         Ok(decryptor) => decryptor,
         Err(e) => {
             eprintln!("Unable to create decryptor: {:?}", e);
@@ -85,8 +206,8 @@ fn get_decryptor(key: &Path, target: &Path) -> RsaHandler {
     }
 }
 
-fn get_encryptor(key: &Path, target: &Path) -> RsaHandler {
-    match RsaHandler::encryptor(key, target) {
+fn get_encryptor(keys: &[PathBuf], target: &Path) -> RsaHandler {
+    match RsaHandler::encryptor_with_recipients(keys, target) {
         Ok(decryptor) => decryptor,
         Err(e) => {
             eprintln!("Unable to create encryptor: {:?}", e);
@@ -95,51 +216,485 @@ fn get_encryptor(key: &Path, target: &Path) -> RsaHandler {
     }
 }
 
+fn get_pass_handler(decrypt: bool, target: &Path, passphrase_fd: Option<u32>) -> PassHandler {
+    let passphrase = get_passphrase(passphrase_fd, !decrypt);
+    let handler = if decrypt {
+        PassHandler::decryptor(&passphrase, target)
+    } else {
+        PassHandler::encryptor(&passphrase, target)
+    };
+    match handler {
+        Ok(handler) => handler,
+        Err(e) => {
+            eprintln!("Unable to create passphrase handler: {:?}", e);
+            exit(ExitCodes::EncryptorError as i32)
+        }
+    }
+}
+
+fn with_encrypted_names<H: Handler, E: Debug>(handler: Result<H, E>) -> H {
+    match handler {
+        Ok(handler) => handler,
+        Err(e) => {
+            eprintln!("Unable to encrypt names: {:?}", e);
+            exit(ExitCodes::EncryptorError as i32)
+        }
+    }
+}
+
+fn with_repository<H: Handler, E: Debug>(handler: Result<H, E>) -> H {
+    match handler {
+        Ok(handler) => handler,
+        Err(e) => {
+            eprintln!("Unable to open repository: {:?}", e);
+            exit(ExitCodes::EncryptorError as i32)
+        }
+    }
+}
+
+fn with_journal<H: Handler, E: Debug>(handler: Result<H, E>) -> H {
+    match handler {
+        Ok(handler) => handler,
+        Err(e) => {
+            eprintln!("Unable to open journal: {:?}", e);
+            exit(ExitCodes::EncryptorError as i32)
+        }
+    }
+}
+
+fn with_snapshot<H: Handler, E: Debug>(handler: Result<H, E>) -> H {
+    match handler {
+        Ok(handler) => handler,
+        Err(e) => {
+            eprintln!("Unable to start snapshot: {:?}", e);
+            exit(ExitCodes::EncryptorError as i32)
+        }
+    }
+}
+
+/// Index file given, or the default one of the target directory.
+fn index_file(args: &Args, target: &Path) -> Option<PathBuf> {
+    args.index.clone().or_else(|| default_index_file(target))
+}
+
+fn with_index<H: Handler, E: Debug>(handler: Result<H, E>) -> H {
+    match handler {
+        Ok(handler) => handler,
+        Err(e) => {
+            eprintln!("Unable to open index: {:?}", e);
+            exit(ExitCodes::EncryptorError as i32)
+        }
+    }
+}
+
+fn with_deletions<H: Handler, E: Debug>(handler: Result<H, E>) -> H {
+    match handler {
+        Ok(handler) => handler,
+        Err(e) => {
+            eprintln!("Unable to delete stale files: {:?}", e);
+            exit(ExitCodes::EncryptorError as i32)
+        }
+    }
+}
+
 fn start_stat_handler() -> StatHandler {
     let stat_handler = StatHandler::default();
     show_stats_at_signal(stat_handler.clone());
     stat_handler
 }
 
-fn get_new_keys() {
-    match generate_keys() {
-        Ok((private_key, public_key)) => {
+fn get_passphrase(fd: Option<u32>, confirm: bool) -> String {
+    match read_passphrase(fd, confirm) {
+        Ok(passphrase) => passphrase,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(ExitCodes::PassphraseError as i32)
+        }
+    }
+}
+
+fn get_new_keys(args: &Args) {
+    let passphrase = args
+        .encrypt_key
+        .then(|| get_passphrase(args.passphrase_fd, true));
+    let (private_key, public_key) = match generate_keys(args.bits.unwrap_or(DEFAULT_KEY_BITS)) {
+        Ok(keys) => keys,
+        Err(e) => {
+            eprintln!("Unable to generate keys: {:?}", e);
+            exit(ExitCodes::KeyGenerationError as i32);
+        }
+    };
+    match &args.out_dir {
+        Some(out_dir) => match write_keys_to_dir(out_dir, private_key, passphrase.as_deref()) {
+            Ok(fingerprint) => println!("SHA256:{}", fingerprint_to_hex(&fingerprint)),
+            Err(e) => {
+                eprintln!("Unable to write keys: {}", e);
+                exit(ExitCodes::UnableToWriteKeys as i32);
+            }
+        },
+        None => {
             if let Err(e) = write_public_key(&mut stdout(), public_key) {
                 eprintln!("Unable to write public key: {:?}", e);
                 exit(ExitCodes::UnableToWriteKeys as i32);
             }
-            if let Err(e) = write_private_key(&mut stdout(), private_key) {
+            let written = match passphrase {
+                Some(passphrase) => {
+                    write_encrypted_private_key(&mut stdout(), private_key, &passphrase)
+                }
+                None => write_private_key(&mut stdout(), private_key),
+            };
+            if let Err(e) = written {
                 eprintln!("Unable to write private key: {:?}", e);
                 exit(ExitCodes::UnableToWriteKeys as i32);
             }
         }
+    }
+}
 
-        Err(e) => {
-            eprintln!("Unable to generate keys: {:?}", e);
-            exit(ExitCodes::KeyGenerationError as i32);
+fn show_key_info(args: &Args) {
+    let mut infos = Vec::new();
+    for key_file in &args.key {
+        let passphrase = is_encrypted_private_key(key_file)
+            .unwrap_or(false)
+            .then(|| get_passphrase(args.passphrase_fd, false));
+        let info = RsaHandler::read_key(key_file, passphrase.as_deref())
+            .and_then(|key| Ok(key_info(&key)?));
+        match info {
+            Ok(info) => {
+                for info in info {
+                    println!("{:?}: {}", key_file, info);
+                    infos.push(info);
+                }
+            }
+            Err(e) => {
+                eprintln!("Unable to read key: {:?}", e);
+                exit(ExitCodes::UnableToReadKey as i32);
+            }
         }
     }
+    let (private, public): (Vec<_>, Vec<_>) =
+        infos.iter().partition(|info| info.kind == KeyKind::Private);
+    if private.is_empty() || public.is_empty() {
+        return;
+    }
+    let paired = private
+        .iter()
+        .all(|private| public.iter().any(|public| public.is_pair_of(private)));
+    if paired {
+        println!("Private and public keys form a pair");
+    } else {
+        eprintln!("Private and public keys don't form a pair");
+        exit(ExitCodes::KeysMismatch as i32);
+    }
 }
 
-fn walk_dir(source: PathBuf, rsa: RsaHandler, stats: StatHandler) {
+fn walk_dir<H: Handler>(source: PathBuf, handler: H, stats: StatHandler) {
     let mut files = Vec::with_capacity(1024);
     scan(source, &mut files, &stats);
     files
         .into_par_iter()
-        .for_each(|file| transform_file(&rsa, file, &stats));
+        .for_each(|file| transform_file(&handler, file, &stats));
+    let deleted = handler.delete_stale();
+    match &deleted {
+        Ok(deleted) => deleted
+            .iter()
+            .for_each(|path| println!("Deleted {:?}", path)),
+        Err(e) => eprintln!("Unable to delete stale files: {:?}", e),
+    }
+    if let Err(e) = handler.finish() {
+        eprintln!("Unable to finish: {:?}", e);
+        exit(ExitCodes::FinishError as i32);
+    }
+    if deleted.is_err() {
+        exit(ExitCodes::DeleteError as i32);
+    }
+}
+
+/// Decrypts the whole backup, or only the files of the snapshot, as they were back then.
+fn decrypt_dir<H: SnapshotReader>(source: PathBuf, handler: H, args: &Args, stats: StatHandler) {
+    if args.snapshot.is_empty() && args.at.is_none() {
+        return walk_dir(source, handler, stats);
+    }
+    let manifests = read_snapshots(&handler, &source);
+    let found = match (args.snapshot.first(), args.at) {
+        (Some(id), _) => find_snapshot(&manifests, id),
+        (None, Some(at)) => find_snapshot_at(&manifests, at),
+        (None, None) => unreachable!(),
+    };
+    let manifest = &manifests[found];
+    println!("Restoring snapshot {}", manifest.id);
+    for _ in &manifest.entries {
+        stats.increment_count();
+    }
+    manifest.entries.par_iter().for_each(|entry| {
+        let restored = handler.restore(&source, entry);
+        stats.decrement_count();
+        match restored {
+            Ok(Transformed::Processed(bytes, _)) => stats.update(bytes, entry.path.clone()),
+            Ok(Transformed::Skipped | Transformed::Reused(_)) => {}
+            Err(e) => eprintln!("Unable to restore file {:?}: {:?}", entry.path, e),
+        }
+    });
+}
+
+fn show_snapshots(args: &Args) {
+    let source = args.source.as_ref().unwrap();
+    let manifests = if args.symmetric {
+        read_snapshots(&get_pass_handler(true, source, args.passphrase_fd), source)
+    } else {
+        read_snapshots(
+            &get_decryptor(&args.key[0], source, args.passphrase_fd),
+            source,
+        )
+    };
+    if args.command == Command::Snapshots {
+        for manifest in &manifests {
+            println!(
+                "{} {} {} files ({} bytes) {:?}",
+                manifest.id,
+                manifest.host,
+                manifest.entries.len(),
+                manifest.size(),
+                manifest.roots
+            );
+        }
+        return;
+    }
+    let newer = match args.snapshot.get(1).or_else(|| args.snapshot.first()) {
+        Some(id) => find_snapshot(&manifests, id),
+        None => manifests.len().saturating_sub(1),
+    };
+    let older = match (args.snapshot.len(), newer) {
+        (2, _) => find_snapshot(&manifests, &args.snapshot[0]),
+        (_, 0) => {
+            eprintln!("No earlier snapshot to compare with");
+            exit(ExitCodes::SnapshotNotFound as i32);
+        }
+        (_, newer) => newer - 1,
+    };
+    for change in manifests[older].diff(&manifests[newer]) {
+        match change {
+            Change::Added(path) => println!("+ {:?}", path),
+            Change::Removed(path) => println!("- {:?}", path),
+            Change::Modified(path) => println!("M {:?}", path),
+        }
+    }
 }
 
-fn transform_file(rsa: &RsaHandler, file: PathBuf, stats: &StatHandler) {
-    let transform_result = rsa.transform(&file);
+/// Removes versions and snapshots the retention doesn't keep. Versions the retained snapshots
+/// refer to are kept, reading them takes the key.
+fn prune_versions(args: &Args) {
+    let source = args.source.as_ref().unwrap();
+    let manifests = if args.symmetric {
+        read_snapshots(&get_pass_handler(true, source, args.passphrase_fd), source)
+    } else {
+        read_snapshots(
+            &get_decryptor(&args.key[0], source, args.passphrase_fd),
+            source,
+        )
+    };
+    let pruned = match prune(source, &retention(args), &manifests, args.dry_run) {
+        Ok(pruned) => pruned,
+        Err(e) => {
+            eprintln!("Unable to prune: {:?}", e);
+            exit(ExitCodes::PruneError as i32)
+        }
+    };
+    let action = if args.dry_run {
+        "Would remove"
+    } else {
+        "Removed"
+    };
+    for file in &pruned.files {
+        println!("{} {:?}", action, file);
+    }
+    println!(
+        "{} {} files ({} bytes).",
+        action,
+        pruned.files.len(),
+        pruned.bytes
+    );
+}
+
+/// Decrypts every file of the backup in memory and reports the ones that fail, nothing is
+/// written.
+fn verify_backup(args: &Args) {
+    let start = std::time::Instant::now();
+    let stat_handler = start_stat_handler();
+    let source = args.source.clone().unwrap();
+    let comparison = args.compare.map(|comparison| {
+        let sources_dir = args.target.clone().unwrap_or_else(|| PathBuf::from("/"));
+        (comparison, sources_dir)
+    });
+    let failed = if args.symmetric {
+        let handler = get_pass_handler(true, &source, args.passphrase_fd);
+        verify_dir(&source, handler, comparison, &stat_handler)
+    } else {
+        let handler = get_decryptor(&args.key[0], &source, args.passphrase_fd);
+        verify_dir(&source, handler, comparison, &stat_handler)
+    };
+    let stats = stat_handler.current();
+    println!(
+        "Verified {} files ({} bytes) in {} seconds, {} failed.",
+        stats.files,
+        stats.bytes,
+        start.elapsed().as_secs(),
+        failed
+    );
+    if failed > 0 {
+        exit(ExitCodes::VerifyError as i32);
+    }
+}
+
+/// Returns the number of files that failed.
+fn verify_dir<H: Verifier>(
+    backup_dir: &Path,
+    handler: H,
+    comparison: Option<(Comparison, PathBuf)>,
+    stats: &StatHandler,
+) -> usize {
+    let mut files = Vec::with_capacity(1024);
+    scan(backup_dir.to_path_buf(), &mut files, stats);
+    let comparison = comparison
+        .as_ref()
+        .map(|(comparison, sources_dir)| (*comparison, sources_dir.as_path()));
+    let failed = AtomicUsize::new(0);
+    files.into_par_iter().for_each(|file| {
+        let verified = handler.verify(backup_dir, &file, comparison);
+        stats.decrement_count();
+        match verified {
+            Ok(Transformed::Processed(bytes, source)) => stats.update(bytes, source),
+            Ok(Transformed::Skipped | Transformed::Reused(_)) => {}
+            Err(e) => {
+                eprintln!("Unable to verify file {:?}: {:?}", file, e);
+                failed.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    });
+    failed.into_inner()
+}
+
+fn read_snapshots<H: SnapshotReader>(handler: &H, backup_dir: &Path) -> Vec<Manifest> {
+    match handler.snapshots(backup_dir) {
+        Ok(manifests) => manifests,
+        Err(e) => {
+            eprintln!("Unable to read snapshots: {:?}", e);
+            exit(ExitCodes::SnapshotError as i32)
+        }
+    }
+}
+
+/// Position of the last snapshot taken before the time.
+fn find_snapshot_at(manifests: &[Manifest], at: SystemTime) -> usize {
+    match manifests
+        .iter()
+        .rposition(|manifest| manifest.time.to_system_time() <= at)
+    {
+        Some(found) => found,
+        None => {
+            eprintln!(
+                "No snapshot taken before {}",
+                humantime::format_rfc3339_seconds(at)
+            );
+            exit(ExitCodes::SnapshotNotFound as i32)
+        }
+    }
+}
+
+/// Position of the snapshot with the id or its unique prefix.
+fn find_snapshot(manifests: &[Manifest], id: &str) -> usize {
+    if let Some(found) = manifests.iter().position(|manifest| manifest.id == id) {
+        return found;
+    }
+    let found: Vec<_> = (0..manifests.len())
+        .filter(|i| manifests[*i].id.starts_with(id))
+        .collect();
+    match found.as_slice() {
+        [found] => *found,
+        [] => {
+            eprintln!("No snapshot {}", id);
+            exit(ExitCodes::SnapshotNotFound as i32)
+        }
+        _ => {
+            eprintln!("Snapshot {} is ambiguous", id);
+            exit(ExitCodes::SnapshotNotFound as i32)
+        }
+    }
+}
+
+/// Encrypts or decrypts stdin or a file into stdout or a file. Nothing else is written to
+/// stdout, so it can be piped.
+fn transform_stream(args: &Args) {
+    let current_dir = Path::new(".");
+    let decrypt = args.command == Command::Decrypt;
+    let cipher = args.cipher.unwrap_or_default();
+    let padding = args.padding.unwrap_or_default();
+    let compression = args.compression.unwrap_or_default();
+    if args.symmetric {
+        let handler = get_pass_handler(decrypt, current_dir, args.passphrase_fd)
+            .with_cipher(cipher)
+            .with_padding(padding)
+            .with_compression(compression);
+        stream_with(&handler, args);
+    } else if decrypt {
+        stream_with(
+            &get_decryptor(&args.key[0], current_dir, args.passphrase_fd),
+            args,
+        );
+    } else {
+        let handler = get_encryptor(&args.key, current_dir)
+            .with_cipher(cipher)
+            .with_padding(padding)
+            .with_compression(compression);
+        stream_with(&handler, args);
+    }
+}
+
+fn stream_with<H: StreamHandler>(handler: &H, args: &Args) {
+    let source = args.source.as_ref().unwrap();
+    let target = args.target.as_ref().unwrap();
+    let source: Box<dyn Read + Send> = if source.as_os_str() == STDIO {
+        Box::new(stdin())
+    } else {
+        match File::open(source) {
+            Ok(file) => Box::new(file),
+            Err(e) => {
+                eprintln!("Unable to open file {:?}: {}", source, e);
+                exit(ExitCodes::StreamError as i32)
+            }
+        }
+    };
+    let result = if target.as_os_str() == STDIO {
+        handler.transform_stream(source, stdout())
+    } else {
+        match File::create(target) {
+            Ok(file) => handler.transform_stream(source, file),
+            Err(e) => {
+                eprintln!("Unable to create file {:?}: {}", target, e);
+                exit(ExitCodes::StreamError as i32)
+            }
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("Unable to transform stream: {:?}", e);
+        exit(ExitCodes::StreamError as i32);
+    }
+}
+
+fn transform_file<H: Handler>(handler: &H, file: PathBuf, stats: &StatHandler) {
+    let transform_result = handler.transform(&file);
     stats.decrement_count();
     match transform_result {
-        Ok(transformed) => {
-            if let Transformed::Processed(bytes, _) = transformed {
-                let count = stats.current().counter;
-                println!("Remaining: {} Last {:?}", count, file);
-                stats.update(bytes, file);
-            }
+        Ok(Transformed::Processed(bytes, _)) => {
+            let count = stats.current().counter;
+            println!("Remaining: {} Last {:?}", count, file);
+            stats.update(bytes, file);
+        }
+        Ok(Transformed::Reused(_)) => {
+            println!("Reused encrypted file of {:?}", file);
+            stats.update(0, file);
         }
+        Ok(Transformed::Skipped) => {}
         Err(e) => {
             eprintln!("Unable to process file {:?}: {:?}", file, e)
         }
@@ -0,0 +1,40 @@
+use std::env;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+const PASSPHRASE_ENV: &str = "CAVERR_PASSPHRASE";
+
+/// Reads passphrase from the given file descriptor, `CAVERR_PASSPHRASE` variable
+/// or asks the user, in that order.
+pub(crate) fn read_passphrase(fd: Option<u32>, confirm: bool) -> Result<String, String> {
+    let passphrase = if let Some(fd) = fd {
+        read_from_fd(fd)?
+    } else if let Ok(passphrase) = env::var(PASSPHRASE_ENV) {
+        passphrase
+    } else {
+        let passphrase = prompt("Passphrase: ")?;
+        if confirm && prompt("Repeat passphrase: ")? != passphrase {
+            return Err("Passphrases don't match".into());
+        }
+        passphrase
+    };
+    if passphrase.is_empty() {
+        Err("Passphrase is empty".into())
+    } else {
+        Ok(passphrase)
+    }
+}
+
+fn prompt(message: &str) -> Result<String, String> {
+    rpassword::prompt_password(message).map_err(|e| format!("Unable to read passphrase: {}", e))
+}
+
+fn read_from_fd(fd: u32) -> Result<String, String> {
+    let file = File::open(format!("/dev/fd/{}", fd))
+        .map_err(|e| format!("Unable to open file descriptor {}: {}", fd, e))?;
+    let mut line = String::new();
+    BufReader::new(file)
+        .read_line(&mut line)
+        .map_err(|e| format!("Unable to read from file descriptor {}: {}", fd, e))?;
+    Ok(line.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
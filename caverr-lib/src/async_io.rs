@@ -0,0 +1,324 @@
+use crate::file::adapter::{DecryptingReader, EncryptingWriter};
+use crate::worker::rsa::holder::RsaKey;
+use anyhow::bail;
+use std::future::Future;
+use std::io::{Read, Write};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::{io, thread};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::{JoinError, JoinHandle};
+use tokio_util::io::SyncIoBridge;
+use tokio_util::sync::PollSender;
+
+/// Plain text read at once by the blocking side of a decrypting stream.
+const READ_SIZE: usize = 65536;
+
+/// Messages queued between the async and the blocking side of a stream.
+const QUEUE_LEN: usize = 16;
+
+/// Runs blocking work off the async runtime, with at most as many jobs at once as it was
+/// created for. A stream takes a job for as long as it's open.
+#[derive(Clone)]
+pub struct BlockingPool {
+    permits: Arc<Semaphore>,
+}
+
+impl BlockingPool {
+    pub fn new(jobs: usize) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(jobs.max(1))),
+        }
+    }
+
+    /// Waits for a free job, then runs the work on a blocking thread.
+    pub async fn run<T, F>(&self, work: F) -> anyhow::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> anyhow::Result<T> + Send + 'static,
+    {
+        let permit = self.permits.clone().acquire_owned().await?;
+        tokio::task::spawn_blocking(move || {
+            let result = work();
+            drop(permit);
+            result
+        })
+        .await?
+    }
+
+    fn spawn<F>(&self, work: F) -> JoinHandle<anyhow::Result<()>>
+    where
+        F: FnOnce() -> anyhow::Result<()> + Send + 'static,
+    {
+        let pool = self.clone();
+        tokio::spawn(async move { pool.run(work).await })
+    }
+}
+
+/// As many jobs as there are CPUs.
+impl Default for BlockingPool {
+    fn default() -> Self {
+        Self::new(thread::available_parallelism().map_or(1, |jobs| jobs.get()))
+    }
+}
+
+enum Message {
+    Data(Vec<u8>),
+    Finish,
+}
+
+/// Encrypts everything written to it into the inner writer, like [`EncryptingWriter`] does
+/// on a thread of the pool. The inner writer is shut down by `shutdown`, content of a writer
+/// dropped before that is truncated and won't decrypt.
+pub struct AsyncEncryptingWriter {
+    sender: PollSender<Message>,
+    task: Option<JoinHandle<anyhow::Result<()>>>,
+}
+
+impl AsyncEncryptingWriter {
+    /// Encrypts for the public keys with the default cipher, uncompressed and unpadded.
+    pub fn new<W>(pool: &BlockingPool, inner: W, key: RsaKey) -> Self
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        Self::spawn(pool, inner, move |inner| EncryptingWriter::new(inner, &key))
+    }
+
+    /// Runs the writer created by `create` for the bridged inner writer on the pool.
+    pub(crate) fn spawn<W, F>(pool: &BlockingPool, inner: W, create: F) -> Self
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+        F: FnOnce(SyncIoBridge<W>) -> anyhow::Result<EncryptingWriter<SyncIoBridge<W>>>
+            + Send
+            + 'static,
+    {
+        let (sender, mut receiver) = mpsc::channel(QUEUE_LEN);
+        let task = pool.spawn(move || {
+            let mut writer = create(SyncIoBridge::new(inner))?;
+            while let Some(message) = receiver.blocking_recv() {
+                match message {
+                    Message::Data(data) => writer.write_all(&data)?,
+                    Message::Finish => {
+                        writer.finish()?.shutdown()?;
+                        return Ok(());
+                    }
+                }
+            }
+            bail!("Writer was dropped before shutdown")
+        });
+        Self {
+            sender: PollSender::new(sender),
+            task: Some(task),
+        }
+    }
+
+    /// Error of the blocking side, it stops receiving only once it's done.
+    fn poll_task(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let task = match self.task.as_mut() {
+            Some(task) => task,
+            None => return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        };
+        let result = ready!(Pin::new(task).poll(cx));
+        self.task = None;
+        Poll::Ready(task_result(result))
+    }
+
+    fn poll_send(&mut self, cx: &mut Context<'_>, message: Message) -> Poll<io::Result<()>> {
+        match ready!(self.sender.poll_reserve(cx)) {
+            Ok(()) if self.sender.send_item(message).is_ok() => Poll::Ready(Ok(())),
+            _ => match ready!(self.poll_task(cx)) {
+                Ok(()) => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+                Err(e) => Poll::Ready(Err(e)),
+            },
+        }
+    }
+}
+
+impl AsyncWrite for AsyncEncryptingWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(self.poll_send(cx, Message::Data(buf.to_vec())))?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    /// Content is sealed once a chunk fills up, it reaches the inner writer on its own.
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    /// Seals the last chunk and shuts down the inner writer.
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.task.is_none() {
+            return Poll::Ready(Ok(()));
+        }
+        if !self.sender.is_closed() {
+            ready!(self.poll_send(cx, Message::Finish))?;
+            self.sender.close();
+        }
+        self.poll_task(cx)
+    }
+}
+
+/// Decrypts the encrypted stream or file read from the inner reader, like
+/// [`DecryptingReader`] does on a thread of the pool.
+pub struct AsyncDecryptingReader {
+    receiver: mpsc::Receiver<Vec<u8>>,
+    task: Option<JoinHandle<anyhow::Result<()>>>,
+    plain: Vec<u8>,
+    pos: usize,
+}
+
+impl AsyncDecryptingReader {
+    /// Decrypts with the private key.
+    pub fn new<R>(pool: &BlockingPool, inner: R, key: RsaKey) -> Self
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        Self::spawn(pool, inner, move |inner| DecryptingReader::new(inner, &key))
+    }
+
+    /// Runs the reader created by `create` for the bridged inner reader on the pool.
+    pub(crate) fn spawn<R, F>(pool: &BlockingPool, inner: R, create: F) -> Self
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        F: FnOnce(SyncIoBridge<R>) -> anyhow::Result<DecryptingReader<SyncIoBridge<R>>>
+            + Send
+            + 'static,
+    {
+        let (sender, receiver) = mpsc::channel(QUEUE_LEN);
+        let task = pool.spawn(move || {
+            let mut reader = create(SyncIoBridge::new(inner))?;
+            loop {
+                let mut plain = vec![0u8; READ_SIZE];
+                let read = reader.read(&mut plain)?;
+                plain.truncate(read);
+                // Nobody reads any more once the receiver is dropped.
+                if read == 0 || sender.blocking_send(plain).is_err() {
+                    return Ok(());
+                }
+            }
+        });
+        Self {
+            receiver,
+            task: Some(task),
+            plain: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl AsyncRead for AsyncDecryptingReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.pos == self.plain.len() {
+            match ready!(self.receiver.poll_recv(cx)) {
+                Some(plain) => {
+                    self.plain = plain;
+                    self.pos = 0;
+                }
+                // Content ends once the blocking side is done without errors.
+                None => {
+                    if let Some(task) = self.task.as_mut() {
+                        let result = ready!(Pin::new(task).poll(cx));
+                        self.task = None;
+                        task_result(result)?;
+                    }
+                    return Poll::Ready(Ok(()));
+                }
+            }
+        }
+        let len = buf.remaining().min(self.plain.len() - self.pos);
+        let pos = self.pos;
+        buf.put_slice(&self.plain[pos..pos + len]);
+        self.pos += len;
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn task_result(result: Result<anyhow::Result<()>, JoinError>) -> io::Result<()> {
+    match result {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => Err(io::Error::other(e)),
+        Err(e) => Err(io::Error::other(e)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::worker::rsa::handler::RsaHandler;
+    use crate::worker::rsa::keys::{generate_keys, write_keys_to_dir, PUBLIC_KEY_FILE};
+    use crate::worker::rsa::DEFAULT_KEY_BITS;
+    use crate::worker::Transformed;
+    use rand::{thread_rng, RngCore};
+    use std::fs;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn should_transform_without_blocking_runtime() {
+        let (private_key, public_key) =
+            generate_keys(DEFAULT_KEY_BITS).expect("Unable to create keys");
+        let pool = BlockingPool::new(2);
+        let mut original = vec![0u8; 200 * 1024];
+        thread_rng().fill_bytes(&mut original);
+
+        let (writer, mut encrypted) = tokio::io::duplex(4096);
+        let mut writer =
+            AsyncEncryptingWriter::new(&pool, writer, RsaKey::PublicKeys(vec![public_key.clone()]));
+        let content = original.clone();
+        let written = tokio::spawn(async move {
+            for part in content.chunks(1000) {
+                writer.write_all(part).await?;
+            }
+            writer.shutdown().await
+        });
+        let mut sealed = Vec::new();
+        encrypted
+            .read_to_end(&mut sealed)
+            .await
+            .expect("Unable to read");
+        written
+            .await
+            .expect("Writer panicked")
+            .expect("Unable to encrypt");
+
+        let key = RsaKey::PrivateKey(private_key.clone());
+        let mut decrypted = Vec::new();
+        AsyncDecryptingReader::new(&pool, io::Cursor::new(sealed.clone()), key.clone())
+            .read_to_end(&mut decrypted)
+            .await
+            .expect("Unable to decrypt");
+        assert_eq!(original, decrypted);
+        sealed.truncate(sealed.len() - 1);
+        assert!(
+            AsyncDecryptingReader::new(&pool, io::Cursor::new(sealed), key)
+                .read_to_end(&mut Vec::new())
+                .await
+                .is_err()
+        );
+
+        let tmp = tempfile::TempDir::new().expect("Unable to create temp dir");
+        let keys_dir = tmp.path().join("keys");
+        let target_dir = tmp.path().join("target");
+        fs::create_dir_all(&keys_dir).expect("Unable to create dir");
+        fs::create_dir_all(&target_dir).expect("Unable to create dir");
+        write_keys_to_dir(&keys_dir, private_key, None).expect("Unable to write keys");
+        let source = tmp.path().join("file");
+        fs::write(&source, &original).expect("Unable to write");
+        let handler = RsaHandler::encryptor(&keys_dir.join(PUBLIC_KEY_FILE), &target_dir)
+            .expect("Unable to create handler");
+        let transformed = handler
+            .transform_async(&pool, source)
+            .await
+            .expect("Unable to encrypt");
+        assert!(matches!(transformed, Transformed::Processed(bytes, _) if bytes == 200 * 1024));
+    }
+}
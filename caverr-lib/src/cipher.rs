@@ -0,0 +1,193 @@
+use aes_gcm::aead::{self, Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use chacha20poly1305::ChaCha20Poly1305;
+use rand::{thread_rng, RngCore};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use thiserror::Error;
+
+pub const DATA_KEY_SIZE: usize = 32;
+pub const TAG_SIZE: usize = 16;
+const NONCE_SIZE: usize = 12;
+
+/// First byte of nonces, separates content chunks from metadata.
+const CONTENT: u8 = 0;
+const METADATA: u8 = 1;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CipherSuite {
+    #[default]
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+impl CipherSuite {
+    pub fn id(&self) -> u8 {
+        match self {
+            CipherSuite::Aes256Gcm => 1,
+            CipherSuite::ChaCha20Poly1305 => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Result<Self, CipherError> {
+        match id {
+            1 => Ok(CipherSuite::Aes256Gcm),
+            2 => Ok(CipherSuite::ChaCha20Poly1305),
+            other => Err(CipherError::UnknownSuite(other)),
+        }
+    }
+}
+
+impl FromStr for CipherSuite {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "aes" | "aes-256-gcm" => Ok(CipherSuite::Aes256Gcm),
+            "chacha" | "chacha20-poly1305" => Ok(CipherSuite::ChaCha20Poly1305),
+            other => Err(format!(
+                "Invalid cipher `{}`. Must be either: `aes-256-gcm` or `chacha20-poly1305`",
+                other
+            )),
+        }
+    }
+}
+
+impl Display for CipherSuite {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CipherSuite::Aes256Gcm => write!(f, "aes-256-gcm"),
+            CipherSuite::ChaCha20Poly1305 => write!(f, "chacha20-poly1305"),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum CipherError {
+    #[error("unknown cipher suite id {0}")]
+    UnknownSuite(u8),
+
+    #[error("invalid data key length {0}")]
+    InvalidKeyLength(usize),
+
+    #[error("unable to encrypt chunk {0}")]
+    Encryption(usize),
+
+    #[error("unable to decrypt chunk {0}: file is corrupted, truncated or was modified")]
+    Decryption(usize),
+
+    #[error("unable to seal or open metadata: file is corrupted or was modified")]
+    Metadata,
+}
+
+/// Random symmetric key protecting the content of a single file.
+#[derive(Clone)]
+pub struct DataKey([u8; DATA_KEY_SIZE]);
+
+impl DataKey {
+    pub fn generate() -> Self {
+        let mut key = [0u8; DATA_KEY_SIZE];
+        thread_rng().fill_bytes(&mut key);
+        Self(key)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CipherError> {
+        let key = bytes
+            .try_into()
+            .map_err(|_| CipherError::InvalidKeyLength(bytes.len()))?;
+        Ok(Self(key))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; DATA_KEY_SIZE] {
+        &self.0
+    }
+}
+
+#[allow(clippy::large_enum_variant)]
+enum Inner {
+    Aes(Aes256Gcm),
+    ChaCha(ChaCha20Poly1305),
+}
+
+/// AEAD used for file content. Every chunk is sealed separately, its nonce is built from its
+/// index and a flag marking the last chunk, so chunks can't be reordered, dropped or appended.
+/// The file header is authenticated with every chunk.
+pub(crate) struct ChunkCipher {
+    inner: Inner,
+    header: Vec<u8>,
+}
+
+impl ChunkCipher {
+    pub(crate) fn new(suite: CipherSuite, key: &DataKey, header: Vec<u8>) -> Self {
+        let inner = match suite {
+            CipherSuite::Aes256Gcm => Inner::Aes(Aes256Gcm::new(key.as_bytes().into())),
+            CipherSuite::ChaCha20Poly1305 => {
+                Inner::ChaCha(ChaCha20Poly1305::new(key.as_bytes().into()))
+            }
+        };
+        Self { inner, header }
+    }
+
+    pub(crate) fn encrypt(
+        &self,
+        index: usize,
+        last: bool,
+        data: &[u8],
+    ) -> Result<Vec<u8>, CipherError> {
+        self.seal(nonce(CONTENT, index, last), data)
+            .map_err(|_| CipherError::Encryption(index))
+    }
+
+    pub(crate) fn decrypt(
+        &self,
+        index: usize,
+        last: bool,
+        data: &[u8],
+    ) -> Result<Vec<u8>, CipherError> {
+        self.open(nonce(CONTENT, index, last), data)
+            .map_err(|_| CipherError::Decryption(index))
+    }
+
+    /// Seals file metadata, its nonces never collide with the ones of content chunks.
+    pub(crate) fn encrypt_metadata(&self, data: &[u8]) -> Result<Vec<u8>, CipherError> {
+        self.seal(nonce(METADATA, 0, true), data)
+            .map_err(|_| CipherError::Metadata)
+    }
+
+    pub(crate) fn decrypt_metadata(&self, data: &[u8]) -> Result<Vec<u8>, CipherError> {
+        self.open(nonce(METADATA, 0, true), data)
+            .map_err(|_| CipherError::Metadata)
+    }
+
+    fn seal(&self, nonce: [u8; NONCE_SIZE], data: &[u8]) -> aead::Result<Vec<u8>> {
+        let nonce = Nonce::from_slice(&nonce);
+        let payload = Payload {
+            msg: data,
+            aad: &self.header,
+        };
+        match &self.inner {
+            Inner::Aes(cipher) => cipher.encrypt(nonce, payload),
+            Inner::ChaCha(cipher) => cipher.encrypt(nonce, payload),
+        }
+    }
+
+    fn open(&self, nonce: [u8; NONCE_SIZE], data: &[u8]) -> aead::Result<Vec<u8>> {
+        let nonce = Nonce::from_slice(&nonce);
+        let payload = Payload {
+            msg: data,
+            aad: &self.header,
+        };
+        match &self.inner {
+            Inner::Aes(cipher) => cipher.decrypt(nonce, payload),
+            Inner::ChaCha(cipher) => cipher.decrypt(nonce, payload),
+        }
+    }
+}
+
+fn nonce(domain: u8, index: usize, last: bool) -> [u8; NONCE_SIZE] {
+    let mut nonce = [0u8; NONCE_SIZE];
+    nonce[0] = domain;
+    nonce[NONCE_SIZE - 9] = last as u8;
+    nonce[NONCE_SIZE - 8..].copy_from_slice(&(index as u64).to_be_bytes());
+    nonce
+}
@@ -0,0 +1,83 @@
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Level of zstd compression, a good balance of speed and ratio.
+pub const ZSTD_LEVEL: i32 = 3;
+
+/// Extensions of files that are compressed already, they would only get slower to encrypt.
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "apk", "avi", "avif", "br", "bz2", "docx", "flac", "gif", "gz", "heic", "jar", "jpeg",
+    "jpg", "lz4", "lzma", "m4a", "mkv", "mov", "mp3", "mp4", "odt", "ogg", "opus", "png", "pptx",
+    "rar", "tbz2", "tgz", "txz", "webm", "webp", "xlsx", "xz", "zip", "zst",
+];
+
+/// Compresses content of files before encryption.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+}
+
+#[derive(Debug, Error)]
+pub enum CompressionError {
+    #[error("unknown compression id {0}")]
+    UnknownCompression(u8),
+}
+
+impl Compression {
+    pub fn id(&self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Zstd => 1,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Result<Self, CompressionError> {
+        match id {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Zstd),
+            other => Err(CompressionError::UnknownCompression(other)),
+        }
+    }
+
+    /// Compression for the file, none if its extension tells it's compressed already.
+    pub fn for_file(&self, path: &Path) -> Self {
+        let compressed = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_ascii_lowercase())
+            .is_some_and(|extension| COMPRESSED_EXTENSIONS.contains(&extension.as_str()));
+        if compressed {
+            Compression::None
+        } else {
+            *self
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "zstd" => Ok(Compression::Zstd),
+            other => Err(format!(
+                "Invalid compression `{}`. Must be either: `none` or `zstd`",
+                other
+            )),
+        }
+    }
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
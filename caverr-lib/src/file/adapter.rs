@@ -0,0 +1,444 @@
+use crate::cipher::{ChunkCipher, DataKey, TAG_SIZE};
+use crate::compression::{Compression, ZSTD_LEVEL};
+use crate::file::header::{Content, Header};
+use crate::file::metadata::Metadata;
+use crate::file::multi_thread::read_chunk;
+use crate::file::stream::ChunkDecompressor;
+use crate::file::{open_preamble, write_preamble, Encoding, CHUNK_SIZE};
+use crate::padding::Padding;
+use crate::worker::rsa::holder::{RsaHolder, RsaKey};
+use crate::worker::KeyHolder;
+use anyhow::bail;
+use std::io;
+use std::io::{Read, Write};
+use zstd::zstd_safe::DCtx;
+
+/// Encrypts everything written through it into the inner writer, in the format of encrypted
+/// streams. Nothing touches the filesystem and chunks are sealed as they fill up.
+///
+/// [`EncryptingWriter::finish`] must be called once everything is written, it seals the last
+/// chunk. Content of a writer dropped before that is truncated and won't decrypt.
+pub struct EncryptingWriter<W: Write> {
+    sink: Sink<W>,
+}
+
+enum Sink<W: Write> {
+    Plain(ChunkSealer<W>),
+    Zstd(zstd::stream::write::Encoder<'static, ChunkSealer<W>>),
+}
+
+impl<W: Write> EncryptingWriter<W> {
+    /// Encrypts for the public keys with the default cipher, uncompressed and unpadded.
+    pub fn new(inner: W, key: &RsaKey) -> anyhow::Result<Self> {
+        Self::with_encoding(inner, &RsaHolder::new(key), Encoding::default())
+    }
+
+    /// Writes the header and the metadata right away. Padding needs compression, as the
+    /// length of the content isn't known upfront.
+    pub(crate) fn with_encoding(
+        mut inner: W,
+        holder: &dyn KeyHolder,
+        encoding: Encoding,
+    ) -> anyhow::Result<Self> {
+        let cipher = write_preamble(
+            None,
+            Metadata::for_stream(),
+            &DataKey::generate(),
+            holder,
+            encoding,
+            Content::Data,
+            &mut inner,
+        )?;
+        let sealer = ChunkSealer {
+            inner,
+            cipher,
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+            next: 0,
+            len: 0,
+            padding: encoding.padding,
+        };
+        let sink = match encoding.compression {
+            Compression::Zstd => Sink::Zstd(zstd::stream::write::Encoder::new(sealer, ZSTD_LEVEL)?),
+            Compression::None => Sink::Plain(sealer),
+        };
+        Ok(Self { sink })
+    }
+
+    /// Seals the last chunk and flushes the inner writer, returns it.
+    pub fn finish(self) -> anyhow::Result<W> {
+        let mut sealer = match self.sink {
+            Sink::Plain(sealer) => sealer,
+            Sink::Zstd(encoder) => {
+                let mut sealer = encoder.finish()?;
+                sealer.pad()?;
+                sealer
+            }
+        };
+        sealer.seal(true)?;
+        sealer.inner.flush()?;
+        Ok(sealer.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.sink {
+            Sink::Plain(sealer) => sealer.write(buf),
+            Sink::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    /// Flushes the inner writer, the chunk being filled is sealed only once it's full.
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.sink {
+            Sink::Plain(sealer) => sealer.flush(),
+            Sink::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Seals full chunks into the inner writer. A full chunk is kept until more content comes,
+/// the last one is sealed differently.
+struct ChunkSealer<W> {
+    inner: W,
+    cipher: ChunkCipher,
+    buffer: Vec<u8>,
+    next: usize,
+    len: u64,
+    padding: Padding,
+}
+
+impl<W: Write> ChunkSealer<W> {
+    fn seal(&mut self, last: bool) -> io::Result<()> {
+        let sealed = self
+            .cipher
+            .encrypt(self.next, last, &self.buffer)
+            .map_err(io::Error::other)?;
+        self.inner.write_all(&sealed)?;
+        self.buffer.clear();
+        self.next += 1;
+        Ok(())
+    }
+
+    /// Appends zeros to the compressed content, up to its padded length.
+    fn pad(&mut self) -> io::Result<()> {
+        let zeros = self.padding.padded_len(self.len) - self.len;
+        io::copy(&mut io::repeat(0).take(zeros), self)?;
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for ChunkSealer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.buffer.len() == CHUNK_SIZE && !buf.is_empty() {
+            self.seal(false)?;
+        }
+        let len = buf.len().min(CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        self.len += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Decrypts an encrypted stream or file read from the inner reader, chunk by chunk as the
+/// content is read. Content stored in a repository can't be read this way.
+pub struct DecryptingReader<R: Read> {
+    opener: ChunkOpener<R>,
+    decoding: Decoding,
+    plain: Vec<u8>,
+    pos: usize,
+    metadata: Option<Metadata>,
+}
+
+enum Decoding {
+    Plain,
+    /// Padded content, with the number of bytes left to read.
+    Limited(u64),
+    Zstd(ChunkDecompressor),
+}
+
+impl<R: Read> DecryptingReader<R> {
+    /// Decrypts with the private key.
+    pub fn new(inner: R, key: &RsaKey) -> anyhow::Result<Self> {
+        Self::with_holder(inner, &RsaHolder::new(key))
+    }
+
+    /// Reads the header and the metadata right away.
+    pub(crate) fn with_holder(mut inner: R, holder: &dyn KeyHolder) -> anyhow::Result<Self> {
+        let header = Header::read(&mut inner)?;
+        if header.content == Content::ChunkIds {
+            bail!("Content stored in a repository needs the encrypted file");
+        }
+        let (cipher, content_len, metadata) = open_preamble(&header, &mut inner, holder)?;
+        let decoding = match (header.compression, content_len) {
+            (Compression::Zstd, _) => Decoding::Zstd(ChunkDecompressor::new()?),
+            (Compression::None, Some(content_len)) => Decoding::Limited(content_len),
+            (Compression::None, None) => Decoding::Plain,
+        };
+        Ok(Self {
+            opener: ChunkOpener {
+                inner,
+                cipher,
+                chunk_len: header.chunk_size as usize + TAG_SIZE,
+                next: 0,
+                ahead: None,
+                finished: false,
+            },
+            decoding,
+            plain: Vec::new(),
+            pos: 0,
+            metadata,
+        })
+    }
+
+    /// Metadata sealed with the content, files written before it was stored have none.
+    pub fn metadata(&self) -> Option<&Metadata> {
+        self.metadata.as_ref()
+    }
+
+    /// Opens the next chunk and decodes it, tells whether there was one. Compressed content
+    /// is decompressed a buffer at a time instead.
+    fn fill(&mut self) -> io::Result<bool> {
+        if let Decoding::Zstd(decompressor) = &mut self.decoding {
+            loop {
+                if decompressor.needs_input() {
+                    match self.opener.next()? {
+                        Some((chunk, _)) => decompressor.feed(chunk),
+                        None if decompressor.is_finished() => return Ok(false),
+                        None => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::UnexpectedEof,
+                                "compressed content is truncated",
+                            ))
+                        }
+                    }
+                }
+                self.plain.resize(DCtx::out_size(), 0);
+                let len = decompressor.decompress(&mut self.plain)?;
+                self.plain.truncate(len);
+                if len > 0 {
+                    self.pos = 0;
+                    return Ok(true);
+                }
+            }
+        }
+        let (chunk, last) = match self.opener.next()? {
+            Some(chunk) => chunk,
+            None => return Ok(false),
+        };
+        self.pos = 0;
+        match &mut self.decoding {
+            Decoding::Plain => self.plain = chunk,
+            Decoding::Limited(remaining) => {
+                let len = chunk
+                    .len()
+                    .min(usize::try_from(*remaining).unwrap_or(usize::MAX));
+                *remaining -= len as u64;
+                self.plain.clear();
+                self.plain.extend_from_slice(&chunk[..len]);
+                if last && *remaining > 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "padded content is truncated",
+                    ));
+                }
+            }
+            Decoding::Zstd(_) => unreachable!("compressed content is decompressed above"),
+        }
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for DecryptingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.plain.len() {
+            if buf.is_empty() || !self.fill()? {
+                return Ok(0);
+            }
+        }
+        let len = buf.len().min(self.plain.len() - self.pos);
+        buf[..len].copy_from_slice(&self.plain[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+/// Opens sealed chunks of the inner reader. The next chunk is read ahead, the last one is
+/// the one followed by nothing.
+struct ChunkOpener<R> {
+    inner: R,
+    cipher: ChunkCipher,
+    chunk_len: usize,
+    next: usize,
+    ahead: Option<Vec<u8>>,
+    finished: bool,
+}
+
+impl<R: Read> ChunkOpener<R> {
+    /// Plain text of the next chunk and whether it's the last one.
+    fn next(&mut self) -> io::Result<Option<(Vec<u8>, bool)>> {
+        if self.finished {
+            return Ok(None);
+        }
+        let sealed = match self.ahead.take() {
+            Some(sealed) => sealed,
+            None => self.read_sealed()?,
+        };
+        let ahead = self.read_sealed()?;
+        let last = ahead.is_empty();
+        let chunk = self
+            .cipher
+            .decrypt(self.next, last, &sealed)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.next += 1;
+        if last {
+            self.finished = true;
+        } else {
+            self.ahead = Some(ahead);
+        }
+        Ok(Some((chunk, last)))
+    }
+
+    fn read_sealed(&mut self) -> io::Result<Vec<u8>> {
+        let mut sealed = vec![0u8; self.chunk_len];
+        let len = read_chunk(&mut self.inner, &mut sealed)?;
+        sealed.truncate(len);
+        Ok(sealed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::file::{decrypt_stream, encrypt_stream};
+    use crate::worker::rsa::keys::generate_keys;
+    use crate::worker::rsa::DEFAULT_KEY_BITS;
+    use rand::{thread_rng, RngCore};
+
+    #[test]
+    fn should_encrypt_and_decrypt_on_the_fly() {
+        let (private_key, public_key) =
+            generate_keys(DEFAULT_KEY_BITS).expect("Unable to create keys");
+        let private_key = RsaKey::PrivateKey(private_key);
+        let public_key = RsaKey::PublicKeys(vec![public_key]);
+        let mut original = vec![0u8; 3 * CHUNK_SIZE + 1234];
+        thread_rng().fill_bytes(&mut original);
+        let encodings = [
+            Encoding::default(),
+            Encoding {
+                compression: Compression::Zstd,
+                padding: Padding::PowerOfTwo,
+                ..Encoding::default()
+            },
+        ];
+        for original in [&original[..], &original[..2 * CHUNK_SIZE], &[]] {
+            for encoding in encodings {
+                let mut writer = EncryptingWriter::with_encoding(
+                    Vec::new(),
+                    &RsaHolder::new(&public_key),
+                    encoding,
+                )
+                .expect("Unable to create writer");
+                for part in original.chunks(1000) {
+                    writer.write_all(part).expect("Unable to write");
+                }
+                let encrypted = writer.finish().expect("Unable to finish");
+
+                let mut reader = DecryptingReader::new(encrypted.as_slice(), &private_key)
+                    .expect("Unable to create reader");
+                assert_eq!(Some(0o100600), reader.metadata().map(|m| m.mode));
+                let mut decrypted = Vec::new();
+                reader
+                    .read_to_end(&mut decrypted)
+                    .expect("Unable to decrypt");
+                assert_eq!(original, decrypted.as_slice());
+
+                let mut decrypted = Vec::new();
+                decrypt_stream(
+                    encrypted.as_slice(),
+                    &RsaHolder::new(&private_key),
+                    &mut decrypted,
+                )
+                .expect("Unable to decrypt stream");
+                assert_eq!(original, decrypted.as_slice());
+
+                let mut truncated = encrypted[..encrypted.len() - 1].as_ref();
+                let mut reader = DecryptingReader::new(&mut truncated, &private_key)
+                    .expect("Unable to create reader");
+                assert!(reader.read_to_end(&mut Vec::new()).is_err());
+            }
+        }
+
+        let mut encrypted = Vec::new();
+        let padded = Encoding {
+            padding: Padding::Padme,
+            ..Encoding::default()
+        };
+        encrypt_stream(
+            &original[..],
+            &RsaHolder::new(&public_key),
+            padded,
+            &mut encrypted,
+        )
+        .expect_err("Unknown length is padded");
+        encrypt_stream(
+            &original[..],
+            &RsaHolder::new(&public_key),
+            Encoding::default(),
+            &mut encrypted,
+        )
+        .expect("Unable to encrypt stream");
+        let mut decrypted = Vec::new();
+        DecryptingReader::new(encrypted.as_slice(), &private_key)
+            .and_then(|mut reader| Ok(reader.read_to_end(&mut decrypted)?))
+            .expect("Unable to decrypt");
+        assert_eq!(original, decrypted);
+        assert!(
+            EncryptingWriter::with_encoding(Vec::new(), &RsaHolder::new(&public_key), padded)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn should_decompress_a_buffer_at_a_time() {
+        let (private_key, public_key) =
+            generate_keys(DEFAULT_KEY_BITS).expect("Unable to create keys");
+        let compressed = Encoding {
+            compression: Compression::Zstd,
+            ..Encoding::default()
+        };
+        let mut writer = EncryptingWriter::with_encoding(
+            Vec::new(),
+            &RsaHolder::new(&RsaKey::PublicKeys(vec![public_key])),
+            compressed,
+        )
+        .expect("Unable to create writer");
+        let zeros = vec![0u8; 1024 * 1024];
+        for _ in 0..64 {
+            writer.write_all(&zeros).expect("Unable to write");
+        }
+        let encrypted = writer.finish().expect("Unable to finish");
+        // Every chunk holds much more than that once decompressed.
+        assert!(encrypted.len() < 2 * CHUNK_SIZE);
+
+        let mut reader =
+            DecryptingReader::new(encrypted.as_slice(), &RsaKey::PrivateKey(private_key))
+                .expect("Unable to create reader");
+        let mut buffer = vec![0u8; 65536];
+        let mut len = 0;
+        loop {
+            let read = reader.read(&mut buffer).expect("Unable to decrypt");
+            assert!(reader.plain.capacity() <= DCtx::out_size());
+            if read == 0 {
+                break;
+            }
+            assert!(buffer[..read].iter().all(|byte| *byte == 0));
+            len += read;
+        }
+        assert_eq!(64 * zeros.len(), len);
+    }
+}
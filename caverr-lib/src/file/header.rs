@@ -0,0 +1,453 @@
+use crate::cipher::{CipherError, CipherSuite};
+use crate::compression::{Compression, CompressionError};
+use crate::padding::{Padding, PaddingError};
+use crate::worker::rsa::keys::{Fingerprint, FINGERPRINT_SIZE};
+use std::io;
+use std::io::{Read, Write};
+use thiserror::Error;
+
+pub const MAGIC: &[u8; 6] = b"CAVERR";
+/// Version 2 adds encrypted metadata after the header, version 3 the padding,
+/// version 4 the compression and version 5 the kind of content.
+pub const FORMAT_VERSION: u8 = 5;
+
+/// Largest chunk accepted when reading a header, guards against allocating garbage sizes.
+const MAX_CHUNK_SIZE: u32 = 16 * 1024 * 1024;
+
+/// Largest Argon2 memory cost accepted when reading a header (1 GiB, 16 times the default),
+/// guards against files that would allocate a lot before the passphrase is checked.
+pub(crate) const MAX_MEMORY_KIB: u32 = 1024 * 1024;
+
+/// Largest Argon2 time cost accepted when reading a header, guards against files that would
+/// take forever to open.
+pub(crate) const MAX_ITERATIONS: u32 = 100;
+
+/// Largest Argon2 parallelism accepted when reading a header.
+pub(crate) const MAX_PARALLELISM: u32 = 64;
+
+pub const SALT_SIZE: usize = 16;
+
+const RECIPIENTS: u8 = 1;
+const PASSPHRASE: u8 = 2;
+
+const DATA: u8 = 0;
+const CHUNK_IDS: u8 = 1;
+
+#[derive(Debug, Error)]
+pub enum HeaderError {
+    #[error("IO error {0}")]
+    IOError(io::Error),
+
+    #[error("not a caverr file")]
+    NotCaverr,
+
+    #[error("unsupported format version {0}, latest known is {FORMAT_VERSION}")]
+    UnsupportedVersion(u8),
+
+    #[error("{0}")]
+    InvalidCipher(CipherError),
+
+    #[error("{0}")]
+    InvalidPadding(PaddingError),
+
+    #[error("{0}")]
+    InvalidCompression(CompressionError),
+
+    #[error("unknown content type {0}")]
+    UnknownContent(u8),
+
+    #[error("invalid chunk size {0}")]
+    InvalidChunkSize(u32),
+
+    #[error("no recipients")]
+    NoRecipients,
+
+    #[error("unknown key type {0}")]
+    UnknownKeyType(u8),
+
+    #[error("invalid key derivation parameters")]
+    InvalidKdfParams,
+}
+
+/// Data key encrypted with the public key of one recipient.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Recipient {
+    pub fingerprint: Fingerprint,
+    pub wrapped_key: Vec<u8>,
+}
+
+/// Data key encrypted with a key derived from a passphrase with Argon2id.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PassphraseSlot {
+    pub salt: [u8; SALT_SIZE],
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+    pub wrapped_key: Vec<u8>,
+}
+
+/// What the sealed chunks of a file hold.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Content {
+    /// Content of the source file.
+    #[default]
+    Data,
+    /// Ids of chunks of the source file kept in a repository.
+    ChunkIds,
+}
+
+/// How the data key is protected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KeySlot {
+    Recipients(Vec<Recipient>),
+    Passphrase(PassphraseSlot),
+}
+
+/// Beginning of every encrypted file.
+///
+/// Layout (integers are big endian):
+/// `magic[6] | version u8 | cipher u8 | chunk size u32 | padding u8 | compression u8 |
+/// content u8 | key type u8 | key slot` where padding is missing before version 3,
+/// compression before version 4, content before version 5 and key slot is either
+/// - `recipients u8 | recipient...`, every recipient is `fingerprint[32] | key len u16 | key`, or
+/// - `salt[16] | memory u32 | iterations u32 | parallelism u32 | key len u16 | key`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub version: u8,
+    pub cipher: CipherSuite,
+    pub chunk_size: u32,
+    pub padding: Padding,
+    pub compression: Compression,
+    pub content: Content,
+    pub key: KeySlot,
+}
+
+impl Header {
+    pub fn new(
+        cipher: CipherSuite,
+        chunk_size: u32,
+        padding: Padding,
+        compression: Compression,
+        key: KeySlot,
+    ) -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            cipher,
+            chunk_size,
+            padding,
+            compression,
+            content: Content::Data,
+            key,
+        }
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(MAGIC)?;
+        w.write_all(&[self.version, self.cipher.id()])?;
+        w.write_all(&self.chunk_size.to_be_bytes())?;
+        if self.version >= 3 {
+            w.write_all(&[self.padding.id()])?;
+        }
+        if self.version >= 4 {
+            w.write_all(&[self.compression.id()])?;
+        }
+        if self.version >= 5 {
+            let content = match self.content {
+                Content::Data => DATA,
+                Content::ChunkIds => CHUNK_IDS,
+            };
+            w.write_all(&[content])?;
+        }
+        match &self.key {
+            KeySlot::Recipients(recipients) => {
+                w.write_all(&[RECIPIENTS, recipients.len() as u8])?;
+                for recipient in recipients {
+                    w.write_all(&recipient.fingerprint)?;
+                    write_bytes(w, &recipient.wrapped_key)?;
+                }
+            }
+            KeySlot::Passphrase(slot) => {
+                w.write_all(&[PASSPHRASE])?;
+                w.write_all(&slot.salt)?;
+                w.write_all(&slot.memory_kib.to_be_bytes())?;
+                w.write_all(&slot.iterations.to_be_bytes())?;
+                w.write_all(&slot.parallelism.to_be_bytes())?;
+                write_bytes(w, &slot.wrapped_key)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read<R: Read>(r: &mut R) -> Result<Self, HeaderError> {
+        let mut magic = [0u8; MAGIC.len()];
+        r.read_exact(&mut magic).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => HeaderError::NotCaverr,
+            _ => HeaderError::IOError(e),
+        })?;
+        if &magic != MAGIC {
+            return Err(HeaderError::NotCaverr);
+        }
+        let version = read_u8(r)?;
+        if version == 0 || version > FORMAT_VERSION {
+            return Err(HeaderError::UnsupportedVersion(version));
+        }
+        let cipher = CipherSuite::from_id(read_u8(r)?).map_err(HeaderError::InvalidCipher)?;
+        let chunk_size = read_u32(r)?;
+        if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+            return Err(HeaderError::InvalidChunkSize(chunk_size));
+        }
+        let padding = if version >= 3 {
+            Padding::from_id(read_u8(r)?).map_err(HeaderError::InvalidPadding)?
+        } else {
+            Padding::None
+        };
+        let compression = if version >= 4 {
+            Compression::from_id(read_u8(r)?).map_err(HeaderError::InvalidCompression)?
+        } else {
+            Compression::None
+        };
+        let content = if version >= 5 {
+            match read_u8(r)? {
+                DATA => Content::Data,
+                CHUNK_IDS => Content::ChunkIds,
+                other => return Err(HeaderError::UnknownContent(other)),
+            }
+        } else {
+            Content::Data
+        };
+        let key = match read_u8(r)? {
+            RECIPIENTS => KeySlot::Recipients(read_recipients(r)?),
+            PASSPHRASE => KeySlot::Passphrase(read_passphrase_slot(r)?),
+            other => return Err(HeaderError::UnknownKeyType(other)),
+        };
+        Ok(Self {
+            version,
+            cipher,
+            chunk_size,
+            padding,
+            compression,
+            content,
+            key,
+        })
+    }
+}
+
+fn read_recipients<R: Read>(r: &mut R) -> Result<Vec<Recipient>, HeaderError> {
+    let count = read_u8(r)?;
+    if count == 0 {
+        return Err(HeaderError::NoRecipients);
+    }
+    let mut recipients = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let mut fingerprint = [0u8; FINGERPRINT_SIZE];
+        r.read_exact(&mut fingerprint)
+            .map_err(HeaderError::IOError)?;
+        let wrapped_key = read_bytes(r)?;
+        recipients.push(Recipient {
+            fingerprint,
+            wrapped_key,
+        });
+    }
+    Ok(recipients)
+}
+
+fn read_passphrase_slot<R: Read>(r: &mut R) -> Result<PassphraseSlot, HeaderError> {
+    let mut salt = [0u8; SALT_SIZE];
+    r.read_exact(&mut salt).map_err(HeaderError::IOError)?;
+    let memory_kib = read_u32(r)?;
+    let iterations = read_u32(r)?;
+    let parallelism = read_u32(r)?;
+    if memory_kib > MAX_MEMORY_KIB
+        || !(1..=MAX_ITERATIONS).contains(&iterations)
+        || !(1..=MAX_PARALLELISM).contains(&parallelism)
+    {
+        return Err(HeaderError::InvalidKdfParams);
+    }
+    Ok(PassphraseSlot {
+        salt,
+        memory_kib,
+        iterations,
+        parallelism,
+        wrapped_key: read_bytes(r)?,
+    })
+}
+
+fn read_u8<R: Read>(r: &mut R) -> Result<u8, HeaderError> {
+    let mut byte = [0u8; 1];
+    r.read_exact(&mut byte).map_err(HeaderError::IOError)?;
+    Ok(byte[0])
+}
+
+fn read_u32<R: Read>(r: &mut R) -> Result<u32, HeaderError> {
+    let mut bytes = [0u8; 4];
+    r.read_exact(&mut bytes).map_err(HeaderError::IOError)?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+fn read_bytes<R: Read>(r: &mut R) -> Result<Vec<u8>, HeaderError> {
+    let mut len = [0u8; 2];
+    r.read_exact(&mut len).map_err(HeaderError::IOError)?;
+    let mut bytes = vec![0u8; u16::from_be_bytes(len) as usize];
+    r.read_exact(&mut bytes).map_err(HeaderError::IOError)?;
+    Ok(bytes)
+}
+
+fn write_bytes<W: Write>(w: &mut W, bytes: &[u8]) -> io::Result<()> {
+    w.write_all(&(bytes.len() as u16).to_be_bytes())?;
+    w.write_all(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn should_read_written_header() {
+        let recipients = vec![
+            Recipient {
+                fingerprint: [7; FINGERPRINT_SIZE],
+                wrapped_key: vec![1, 2, 3],
+            },
+            Recipient {
+                fingerprint: [8; FINGERPRINT_SIZE],
+                wrapped_key: vec![4, 5],
+            },
+        ];
+        let passphrase = PassphraseSlot {
+            salt: [9; SALT_SIZE],
+            memory_kib: 1024,
+            iterations: 2,
+            parallelism: 1,
+            wrapped_key: vec![6; 60],
+        };
+        for key in [
+            KeySlot::Recipients(recipients),
+            KeySlot::Passphrase(passphrase),
+        ] {
+            let mut header = Header::new(
+                CipherSuite::ChaCha20Poly1305,
+                4096,
+                Padding::Padme,
+                Compression::Zstd,
+                key,
+            );
+            header.content = Content::ChunkIds;
+            let mut bytes = Vec::new();
+            header.write(&mut bytes).expect("Unable to write header");
+            let read = Header::read(&mut bytes.as_slice()).expect("Unable to read header");
+            assert_eq!(header, read);
+        }
+
+        // Written before padding was introduced.
+        let mut old = Header::new(
+            CipherSuite::Aes256Gcm,
+            4096,
+            Padding::None,
+            Compression::None,
+            KeySlot::Recipients(vec![]),
+        );
+        old.version = 2;
+        let mut bytes = Vec::new();
+        old.write(&mut bytes).expect("Unable to write header");
+        bytes[MAGIC.len() + 7] = 1;
+        bytes.extend_from_slice(&[0; FINGERPRINT_SIZE + 2]);
+        let read = Header::read(&mut bytes.as_slice()).expect("Unable to read header");
+        assert_eq!(read.version, 2);
+        assert_eq!(read.padding, Padding::None);
+        assert_eq!(read.compression, Compression::None);
+        assert_eq!(read.content, Content::Data);
+    }
+
+    #[test]
+    fn should_reject_invalid_headers() {
+        let recipients = vec![Recipient {
+            fingerprint: [0; FINGERPRINT_SIZE],
+            wrapped_key: vec![],
+        }];
+        let header = Header::new(
+            CipherSuite::Aes256Gcm,
+            4096,
+            Padding::None,
+            Compression::None,
+            KeySlot::Recipients(recipients),
+        );
+        let mut bytes = Vec::new();
+        header.write(&mut bytes).expect("Unable to write header");
+
+        let mut future = bytes.clone();
+        future[MAGIC.len()] = FORMAT_VERSION + 1;
+        let result = Header::read(&mut future.as_slice());
+        assert!(
+            matches!(result, Err(HeaderError::UnsupportedVersion(v)) if v == FORMAT_VERSION + 1)
+        );
+
+        let mut garbage = bytes.clone();
+        garbage[0] = b'X';
+        let result = Header::read(&mut garbage.as_slice());
+        assert!(matches!(result, Err(HeaderError::NotCaverr)));
+
+        let result = Header::read(&mut &b"CAV"[..]);
+        assert!(matches!(result, Err(HeaderError::NotCaverr)));
+
+        let mut no_recipients = bytes.clone();
+        no_recipients[MAGIC.len() + 10] = 0;
+        let result = Header::read(&mut no_recipients.as_slice());
+        assert!(matches!(result, Err(HeaderError::NoRecipients)));
+
+        let mut bad_key_type = bytes.clone();
+        bad_key_type[MAGIC.len() + 9] = 99;
+        let result = Header::read(&mut bad_key_type.as_slice());
+        assert!(matches!(result, Err(HeaderError::UnknownKeyType(99))));
+
+        let mut bad_padding = bytes.clone();
+        bad_padding[MAGIC.len() + 6] = 99;
+        let result = Header::read(&mut bad_padding.as_slice());
+        assert!(matches!(result, Err(HeaderError::InvalidPadding(_))));
+
+        let mut bad_compression = bytes.clone();
+        bad_compression[MAGIC.len() + 7] = 99;
+        let result = Header::read(&mut bad_compression.as_slice());
+        assert!(matches!(result, Err(HeaderError::InvalidCompression(_))));
+
+        let mut bad_content = bytes.clone();
+        bad_content[MAGIC.len() + 8] = 99;
+        let result = Header::read(&mut bad_content.as_slice());
+        assert!(matches!(result, Err(HeaderError::UnknownContent(99))));
+
+        let mut bad_cipher = bytes;
+        bad_cipher[MAGIC.len() + 1] = 99;
+        let result = Header::read(&mut bad_cipher.as_slice());
+        assert!(matches!(result, Err(HeaderError::InvalidCipher(_))));
+
+        let costly = |memory_kib, iterations, parallelism| {
+            let slot = PassphraseSlot {
+                salt: [0; SALT_SIZE],
+                memory_kib,
+                iterations,
+                parallelism,
+                wrapped_key: vec![],
+            };
+            let header = Header::new(
+                CipherSuite::Aes256Gcm,
+                4096,
+                Padding::None,
+                Compression::None,
+                KeySlot::Passphrase(slot),
+            );
+            let mut bytes = Vec::new();
+            header.write(&mut bytes).expect("Unable to write header");
+            Header::read(&mut bytes.as_slice())
+        };
+        assert!(costly(MAX_MEMORY_KIB, MAX_ITERATIONS, MAX_PARALLELISM).is_ok());
+        for (memory_kib, iterations, parallelism) in [
+            (1024, u32::MAX, 1),
+            (1024, MAX_ITERATIONS + 1, 1),
+            (1024, 1, u32::MAX),
+            (MAX_MEMORY_KIB + 1, 1, 1),
+            (u32::MAX, 1, 1),
+        ] {
+            let result = costly(memory_kib, iterations, parallelism);
+            assert!(matches!(result, Err(HeaderError::InvalidKdfParams)));
+        }
+    }
+}
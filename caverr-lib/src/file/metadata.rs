@@ -0,0 +1,311 @@
+use std::ffi::OsString;
+use std::fs::{File, FileTimes};
+use std::io::Read;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{fs, io};
+#[cfg(unix)]
+use std::{
+    fs::Permissions,
+    os::unix::fs::{chown, MetadataExt, PermissionsExt},
+};
+use thiserror::Error;
+
+use crate::path::{os_str_bytes, os_string_from_bytes};
+
+/// Largest metadata accepted when reading a file, guards against allocating garbage sizes.
+pub(crate) const MAX_METADATA_SIZE: u32 = 16 * 1024 * 1024;
+
+#[cfg(unix)]
+const USER_XATTR_PREFIX: &[u8] = b"user.";
+
+#[derive(Debug, Error)]
+pub enum MetadataError {
+    #[error("IO error {0}")]
+    IOError(io::Error),
+
+    #[error("invalid metadata")]
+    Invalid,
+}
+
+/// Seconds and nanoseconds since the Unix epoch.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp {
+    pub secs: i64,
+    pub nanos: u32,
+}
+
+impl Timestamp {
+    /// Modification time of the file.
+    #[cfg(unix)]
+    pub(crate) fn modified(metadata: &fs::Metadata) -> Self {
+        Self {
+            secs: metadata.mtime(),
+            nanos: metadata.mtime_nsec() as u32,
+        }
+    }
+
+    /// Modification time of the file.
+    #[cfg(not(unix))]
+    pub(crate) fn modified(metadata: &fs::Metadata) -> Self {
+        metadata.modified().map(Self::from).unwrap_or_default()
+    }
+
+    pub fn to_system_time(self) -> SystemTime {
+        let nanos = Duration::from_nanos(self.nanos as u64);
+        if self.secs >= 0 {
+            UNIX_EPOCH + Duration::from_secs(self.secs as u64) + nanos
+        } else {
+            UNIX_EPOCH - Duration::from_secs(self.secs.unsigned_abs()) + nanos
+        }
+    }
+}
+
+impl From<SystemTime> for Timestamp {
+    fn from(time: SystemTime) -> Self {
+        match time.duration_since(UNIX_EPOCH) {
+            Ok(since) => Self {
+                secs: since.as_secs() as i64,
+                nanos: since.subsec_nanos(),
+            },
+            Err(e) => {
+                let before = e.duration() - Duration::from_nanos(1);
+                Self {
+                    secs: -(before.as_secs() as i64) - 1,
+                    nanos: 999_999_999 - before.subsec_nanos(),
+                }
+            }
+        }
+    }
+}
+
+/// Unix metadata of the source file, encrypted together with its content.
+///
+/// Layout (integers are big endian):
+/// `mode u32 | mtime i64 u32 | atime i64 u32 | uid u32 | gid u32 | xattrs u16 | xattr...`
+/// where every xattr is `name len u16 | name | value len u32 | value`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Metadata {
+    pub mode: u32,
+    pub modified: Timestamp,
+    pub accessed: Timestamp,
+    pub uid: u32,
+    pub gid: u32,
+    pub xattrs: Vec<(OsString, Vec<u8>)>,
+}
+
+impl Metadata {
+    #[cfg(unix)]
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let metadata = fs::metadata(path)?;
+        let xattrs = match xattr::list(path) {
+            Ok(names) => names
+                .filter_map(|name| match xattr::get(path, &name) {
+                    Ok(Some(value)) => Some(Ok((name, value))),
+                    Ok(None) => None,
+                    Err(e) => Some(Err(e)),
+                })
+                .collect::<io::Result<_>>()?,
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            mode: metadata.mode(),
+            modified: Timestamp::modified(&metadata),
+            accessed: Timestamp {
+                secs: metadata.atime(),
+                nanos: metadata.atime_nsec() as u32,
+            },
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            xattrs,
+        })
+    }
+
+    /// Outside of Unix only the times and whether the file is read only are known.
+    #[cfg(not(unix))]
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let metadata = fs::metadata(path)?;
+        let mode = if metadata.permissions().readonly() {
+            0o100444
+        } else {
+            0o100644
+        };
+        Ok(Self {
+            mode,
+            modified: Timestamp::modified(&metadata),
+            accessed: metadata.accessed().map(Timestamp::from).unwrap_or_default(),
+            uid: 0,
+            gid: 0,
+            xattrs: Vec::new(),
+        })
+    }
+
+    /// Metadata of content read from a stream: a file only its owner can read and write,
+    /// modified now.
+    pub fn for_stream() -> Self {
+        let now = Timestamp::from(SystemTime::now());
+        let (uid, gid) = owner();
+        Self {
+            mode: 0o100600,
+            modified: now,
+            accessed: now,
+            uid,
+            gid,
+            xattrs: Vec::new(),
+        }
+    }
+
+    /// Restores the metadata. Ownership and xattrs outside of the `user.` namespace
+    /// are only restored when running as root.
+    #[cfg(unix)]
+    pub fn apply(&self, path: &Path) -> io::Result<()> {
+        let root = is_root();
+        if root {
+            chown(path, Some(self.uid), Some(self.gid))?;
+        }
+        for (name, value) in &self.xattrs {
+            if root || os_str_bytes(name).starts_with(USER_XATTR_PREFIX) {
+                xattr::set(path, name, value)?;
+            }
+        }
+        let times = FileTimes::new()
+            .set_modified(self.modified.to_system_time())
+            .set_accessed(self.accessed.to_system_time());
+        File::open(path)?.set_times(times)?;
+        // Last, so a read only mode doesn't get in the way of the other changes.
+        fs::set_permissions(path, Permissions::from_mode(self.mode))
+    }
+
+    /// Restores the times and, from the mode, whether the file is read only.
+    #[cfg(not(unix))]
+    pub fn apply(&self, path: &Path) -> io::Result<()> {
+        let times = FileTimes::new()
+            .set_modified(self.modified.to_system_time())
+            .set_accessed(self.accessed.to_system_time());
+        File::options().write(true).open(path)?.set_times(times)?;
+        let mut permissions = fs::metadata(path)?.permissions();
+        permissions.set_readonly(self.mode & 0o222 == 0);
+        fs::set_permissions(path, permissions)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&self.mode.to_be_bytes());
+        for timestamp in [self.modified, self.accessed] {
+            bytes.extend_from_slice(&timestamp.secs.to_be_bytes());
+            bytes.extend_from_slice(&timestamp.nanos.to_be_bytes());
+        }
+        bytes.extend_from_slice(&self.uid.to_be_bytes());
+        bytes.extend_from_slice(&self.gid.to_be_bytes());
+        bytes.extend_from_slice(&(self.xattrs.len() as u16).to_be_bytes());
+        for (name, value) in &self.xattrs {
+            bytes.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            bytes.extend_from_slice(os_str_bytes(name));
+            bytes.extend_from_slice(&(value.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(value);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self, MetadataError> {
+        let r = &mut bytes;
+        let mode = u32::from_be_bytes(read_array(r)?);
+        let mut timestamps = [Timestamp::default(); 2];
+        for timestamp in &mut timestamps {
+            timestamp.secs = i64::from_be_bytes(read_array(r)?);
+            timestamp.nanos = u32::from_be_bytes(read_array(r)?);
+        }
+        let uid = u32::from_be_bytes(read_array(r)?);
+        let gid = u32::from_be_bytes(read_array(r)?);
+        let count = u16::from_be_bytes(read_array(r)?);
+        let mut xattrs = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let name_len = u16::from_be_bytes(read_array(r)?) as usize;
+            let name = os_string_from_bytes(read_vec(r, name_len)?);
+            let value_len = u32::from_be_bytes(read_array(r)?) as usize;
+            xattrs.push((name, read_vec(r, value_len)?));
+        }
+        if !r.is_empty() {
+            return Err(MetadataError::Invalid);
+        }
+        Ok(Self {
+            mode,
+            modified: timestamps[0],
+            accessed: timestamps[1],
+            uid,
+            gid,
+            xattrs,
+        })
+    }
+}
+
+fn read_array<const N: usize>(r: &mut &[u8]) -> Result<[u8; N], MetadataError> {
+    let mut bytes = [0u8; N];
+    r.read_exact(&mut bytes)
+        .map_err(|_| MetadataError::Invalid)?;
+    Ok(bytes)
+}
+
+fn read_vec(r: &mut &[u8], len: usize) -> Result<Vec<u8>, MetadataError> {
+    if r.len() < len {
+        return Err(MetadataError::Invalid);
+    }
+    let (bytes, rest) = r.split_at(len);
+    *r = rest;
+    Ok(bytes.to_vec())
+}
+
+#[cfg(unix)]
+fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(unix)]
+fn owner() -> (u32, u32) {
+    unsafe { (libc::geteuid(), libc::getegid()) }
+}
+
+#[cfg(not(unix))]
+fn owner() -> (u32, u32) {
+    (0, 0)
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn should_restore_metadata() {
+        let tmp = tempfile::TempDir::new().expect("Unable to create temp dir");
+        let source = tmp.path().join("source");
+        fs::write(&source, b"content").expect("Unable to write");
+        fs::set_permissions(&source, Permissions::from_mode(0o751)).expect("No permissions");
+        let xattrs = xattr::set(&source, "user.caverr", b"value").is_ok();
+        let times = FileTimes::new()
+            .set_modified(UNIX_EPOCH + Duration::new(1_000_000_000, 123))
+            .set_accessed(UNIX_EPOCH + Duration::from_secs(1_100_000_000));
+        File::open(&source)
+            .and_then(|file| file.set_times(times))
+            .expect("Unable to set times");
+
+        let metadata = Metadata::read(&source).expect("Unable to read metadata");
+        assert_eq!(metadata.mode & 0o7777, 0o751);
+        assert_eq!(metadata.modified.secs, 1_000_000_000);
+        assert_eq!(metadata.modified.nanos, 123);
+        let read = Metadata::from_bytes(&metadata.to_bytes()).expect("Invalid metadata");
+        assert_eq!(metadata, read);
+        assert!(Metadata::from_bytes(&metadata.to_bytes()[1..]).is_err());
+
+        let target = tmp.path().join("target");
+        fs::write(&target, b"content").expect("Unable to write");
+        read.apply(&target).expect("Unable to apply metadata");
+        let restored = Metadata::read(&target).expect("Unable to read metadata");
+        assert_eq!(restored.mode, metadata.mode);
+        assert_eq!(restored.modified, metadata.modified);
+        assert_eq!(restored.accessed, metadata.accessed);
+        if xattrs {
+            assert_eq!(restored.xattrs, metadata.xattrs);
+        }
+    }
+}
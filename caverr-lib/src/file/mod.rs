@@ -1,36 +1,669 @@
+pub mod adapter;
+pub mod header;
+pub mod metadata;
 mod multi_thread;
+mod stream;
 
+use crate::cipher::{ChunkCipher, CipherSuite, DataKey, TAG_SIZE};
+use crate::compression::{Compression, ZSTD_LEVEL};
+use crate::file::header::{Content, Header, HeaderError};
+use crate::file::metadata::{Metadata, Timestamp, MAX_METADATA_SIZE};
+use crate::file::stream::{Decompressor, HashingReader, LimitedWriter, PaddedReader};
+use crate::index::FileState;
+use crate::journal::{Journal, Resumed};
+use crate::padding::Padding;
+use crate::repository::{Repositories, Repository};
+use crate::version::keep_version;
 use crate::worker::rsa::holder::RsaHolder;
-use anyhow::Context;
+use crate::worker::KeyHolder;
+use anyhow::{bail, Context};
 use rand::{thread_rng, RngCore};
 use std::fs;
-use std::fs::File;
-use std::io::BufWriter;
-use std::io::{BufReader, Write};
-use std::path::Path;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, SeekFrom};
+use std::io::{BufRead, BufReader, Read, Seek, Write};
+use std::path::{Path, PathBuf};
 
+/// Size of plain text sealed in a single AEAD chunk.
+const CHUNK_SIZE: usize = 65536;
+
+/// Hash of plain content of a file.
+pub type ContentHash = [u8; 32];
+
+/// How encrypted files are written. Decryption reads the content encoding from the header.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Encoding {
+    pub(crate) cipher: CipherSuite,
+    pub(crate) padding: Padding,
+    pub(crate) compression: Compression,
+    /// Keeps replaced encrypted files as versions instead of overwriting them.
+    pub(crate) versions: bool,
+}
+
+/// Encrypts or decrypts the file with the RSA key, returns the size of the source. The size
+/// of RSA blocks is told by the key and the header now, `message_len` is left unused.
 pub fn file_transform(
     source_path: &Path,
     rsa: RsaHolder,
     target_path: &Path,
-    message_len: usize,
+    _message_len: usize,
 ) -> anyhow::Result<u64> {
-    let source = File::open(&source_path)
+    let repositories = Repositories::default();
+    let encoding = Encoding::default();
+    let (bytes, _) = transform_file(
+        source_path,
+        &rsa,
+        target_path,
+        encoding,
+        &repositories,
+        None,
+    )?;
+    Ok(bytes)
+}
+
+/// Encrypts or decrypts the file, returns the size of the source and, when encrypting,
+/// the hash of its content. Large files are encrypted resumable when given the journal,
+/// unless compressed or stored in a repository.
+pub(crate) fn transform_file(
+    source_path: &Path,
+    holder: &dyn KeyHolder,
+    target_path: &Path,
+    encoding: Encoding,
+    repositories: &Repositories,
+    journal: Option<&Journal>,
+) -> anyhow::Result<(u64, Option<ContentHash>)> {
+    let source = File::open(source_path)
         .with_context(|| format!("Unable to read the source file: {:?}", source_path))?;
+    let state = FileState::of(&source.metadata()?);
     let bytes = source.metadata()?.len();
-    let source = BufReader::with_capacity(65536, source);
-    let tmp_path = target_path.with_file_name(format!("{}.tmp", thread_rng().next_u64()));
-    let mut tmp_target = BufWriter::with_capacity(
-        65536,
-        File::create(&tmp_path)
-            .with_context(|| format!("Unable to write to target file: {:?}", tmp_path))?,
-    );
-    multi_thread::file_transform(source, rsa, message_len, &mut tmp_target)?;
+    let encoding = Encoding {
+        compression: encoding.compression.for_file(source_path),
+        ..encoding
+    };
+    let journal = journal.filter(|journal| {
+        holder.is_encryptor()
+            && repositories.encryptor().is_none()
+            && encoding.compression == Compression::None
+            && bytes >= journal.resumable_len
+    });
+    let resumed = match journal {
+        Some(journal) => journal.resume(source_path, state)?,
+        None => None,
+    };
+    let (tmp_path, tmp_file, reopened) = match resumed {
+        Some(resumed) => match reopen(&resumed, bytes, encoding) {
+            Ok((file, reopened)) => (resumed.tmp, file, Some(reopened)),
+            Err(_) => {
+                let _ = fs::remove_file(&resumed.tmp);
+                let (tmp_path, file) = create_tmp(target_path)?;
+                (tmp_path, file, None)
+            }
+        },
+        None => {
+            let (tmp_path, file) = create_tmp(target_path)?;
+            (tmp_path, file, None)
+        }
+    };
+    let mut tmp_target = BufWriter::with_capacity(65536, tmp_file);
+    let result = if holder.is_encryptor() {
+        let mut hasher = blake3::Hasher::new();
+        let source = BufReader::with_capacity(65536, HashingReader::new(source, &mut hasher));
+        Metadata::read(source_path)
+            .with_context(|| format!("Unable to read metadata of file: {:?}", source_path))
+            .and_then(|metadata| match (repositories.encryptor(), journal) {
+                (Some(repository), _) => store(
+                    source,
+                    metadata,
+                    holder,
+                    encoding,
+                    repository,
+                    &mut tmp_target,
+                ),
+                (None, Some(journal)) => encrypt_resumable(
+                    source,
+                    bytes,
+                    metadata,
+                    holder,
+                    encoding,
+                    reopened,
+                    journal.checkpoint_chunks,
+                    |target: &mut BufWriter<File>, chunks, key| {
+                        // The checkpoint must not outlive the chunks it vouches for.
+                        target.flush()?;
+                        target.get_ref().sync_data()?;
+                        journal.checkpoint(source_path, state, &tmp_path, chunks, key)
+                    },
+                    &mut tmp_target,
+                ),
+                (None, None) => encrypt(
+                    source,
+                    Some(bytes),
+                    metadata,
+                    holder,
+                    encoding,
+                    Content::Data,
+                    &mut tmp_target,
+                ),
+            })
+            .map(|_| (None, Some(*hasher.finalize().as_bytes())))
+    } else {
+        decrypt_file(
+            source,
+            source_path,
+            bytes,
+            holder,
+            repositories,
+            &mut tmp_target,
+        )
+        .map(|metadata| (metadata, None))
+    };
+    let (metadata, hash) = match result {
+        Ok(result) => result,
+        Err(e) => {
+            drop(tmp_target);
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+    };
     tmp_target
         .flush()
         .with_context(|| format!("Unable to flush file: {:?}", tmp_path))?;
     drop(tmp_target);
+    if encoding.versions {
+        keep_version(target_path)
+            .with_context(|| format!("Unable to keep version of file: {:?}", target_path))?;
+    }
     fs::rename(tmp_path, target_path)
         .with_context(|| format!("Unable to rename file to:  {:?}", target_path))?;
+    if let Some(metadata) = metadata {
+        metadata
+            .apply(target_path)
+            .with_context(|| format!("Unable to restore metadata of file: {:?}", target_path))?;
+    }
+    Ok((bytes, hash))
+}
+
+/// Creates a temporary file next to the target, renamed to it once written.
+fn create_tmp(target_path: &Path) -> anyhow::Result<(PathBuf, File)> {
+    let tmp_path = target_path.with_file_name(format!("{}.tmp", thread_rng().next_u64()));
+    let file = File::create(&tmp_path)
+        .with_context(|| format!("Unable to write to target file: {:?}", tmp_path))?;
+    Ok((tmp_path, file))
+}
+
+/// Temporary file of an interrupted run reopened to encrypt the rest of the source into.
+struct Reopened {
+    cipher: ChunkCipher,
+    key: DataKey,
+    chunks: u64,
+}
+
+/// Reopens the temporary file an interrupted run was encrypting the source of `len` bytes
+/// into, truncated after the chunks it recorded. It must be written with the encoding.
+fn reopen(resumed: &Resumed, len: u64, encoding: Encoding) -> anyhow::Result<(File, Reopened)> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&resumed.tmp)?;
+    let header = Header::read(&mut file)?;
+    if header.cipher != encoding.cipher
+        || header.padding != encoding.padding
+        || header.compression != encoding.compression
+        || header.chunk_size != CHUNK_SIZE as u32
+        || header.content != Content::Data
+    {
+        bail!("File {:?} is encrypted differently", resumed.tmp);
+    }
+    if resumed.chunks * CHUNK_SIZE as u64 >= encoding.padding.padded_len(len) {
+        bail!("File {:?} has more chunks than its source", resumed.tmp);
+    }
+    let mut header_bytes = Vec::new();
+    header.write(&mut header_bytes)?;
+    let cipher = ChunkCipher::new(header.cipher, &resumed.key, header_bytes);
+    // Opening the metadata checks the key too.
+    read_metadata(&mut file, &cipher)?;
+    let end = file.stream_position()? + resumed.chunks * (CHUNK_SIZE + TAG_SIZE) as u64;
+    if file.metadata()?.len() < end {
+        bail!("File {:?} is shorter than its recorded chunks", resumed.tmp);
+    }
+    file.set_len(end)?;
+    file.seek(SeekFrom::End(0))?;
+    let reopened = Reopened {
+        cipher,
+        key: resumed.key.clone(),
+        chunks: resumed.chunks,
+    };
+    Ok((file, reopened))
+}
+
+/// Modification time of the source recorded in the encrypted file, which its decrypted file
+/// gets. `None` for files written before metadata was stored.
+pub(crate) fn recorded_modified(
+    source_path: &Path,
+    holder: &dyn KeyHolder,
+) -> anyhow::Result<Option<Timestamp>> {
+    let mut source = BufReader::new(
+        File::open(source_path)
+            .with_context(|| format!("Unable to read the source file: {:?}", source_path))?,
+    );
+    let header = match Header::read(&mut source) {
+        Ok(header) => header,
+        Err(HeaderError::NotCaverr) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let (_, _, metadata) = open_preamble(&header, &mut source, holder)?;
+    Ok(metadata.map(|metadata| metadata.modified))
+}
+
+/// Decrypts the file into the target instead of a file of its own, returns the size of the
+/// source. Its metadata is dropped.
+pub(crate) fn file_verify<W: Write + Send>(
+    source_path: &Path,
+    holder: &dyn KeyHolder,
+    repositories: &Repositories,
+    mut target: W,
+) -> anyhow::Result<u64> {
+    let source = File::open(source_path)
+        .with_context(|| format!("Unable to read the source file: {:?}", source_path))?;
+    let bytes = source.metadata()?.len();
+    decrypt_file(
+        source,
+        source_path,
+        bytes,
+        holder,
+        repositories,
+        &mut target,
+    )?;
+    target.flush()?;
     Ok(bytes)
 }
+
+/// Decrypts the encrypted file of `bytes` bytes, or the file written before headers were
+/// introduced.
+fn decrypt_file<W: Write + Send>(
+    source: File,
+    source_path: &Path,
+    bytes: u64,
+    holder: &dyn KeyHolder,
+    repositories: &Repositories,
+    target: &mut W,
+) -> anyhow::Result<Option<Metadata>> {
+    let mut source = BufReader::with_capacity(65536, source);
+    match (Header::read(&mut source), holder.legacy_block_len()) {
+        (Ok(header), _) => decrypt(
+            header,
+            Some(source_path),
+            source,
+            holder,
+            repositories,
+            target,
+        ),
+        (Err(HeaderError::NotCaverr), Some(block_len))
+            if bytes.is_multiple_of(block_len as u64) =>
+        {
+            decrypt_legacy(source, block_len, holder, target).map(|_| None)
+        }
+        (Err(e), _) => Err(e.into()),
+    }
+}
+
+/// Encrypts everything read from the source into the target, as an encrypted file of
+/// content without a file of its own.
+pub(crate) fn encrypt_stream<R, W>(
+    source: R,
+    holder: &dyn KeyHolder,
+    encoding: Encoding,
+    target: W,
+) -> anyhow::Result<()>
+where
+    R: Read + Send,
+    W: Write + Send,
+{
+    let mut target = BufWriter::with_capacity(65536, target);
+    encrypt(
+        BufReader::with_capacity(65536, source),
+        None,
+        Metadata::for_stream(),
+        holder,
+        encoding,
+        Content::Data,
+        &mut target,
+    )?;
+    Ok(target.flush()?)
+}
+
+/// Decrypts an encrypted file read from the source into the target, its metadata is dropped.
+pub(crate) fn decrypt_stream<R, W>(
+    source: R,
+    holder: &dyn KeyHolder,
+    target: W,
+) -> anyhow::Result<()>
+where
+    R: Read + Send,
+    W: Write + Send,
+{
+    let mut source = BufReader::with_capacity(65536, source);
+    let mut target = BufWriter::with_capacity(65536, target);
+    let header = Header::read(&mut source)?;
+    decrypt(
+        header,
+        None,
+        source,
+        holder,
+        &Repositories::default(),
+        &mut target,
+    )?;
+    Ok(target.flush()?)
+}
+
+/// Hash of the file content.
+pub(crate) fn hash_file(path: &Path) -> io::Result<ContentHash> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(File::open(path)?)?;
+    Ok(*hasher.finalize().as_bytes())
+}
+
+/// Writes the header followed by `metadata len u32 | sealed metadata` and the sealed chunks.
+/// When padded, zeros follow the content and, unless compressed, sealed metadata starts with
+/// `content len u64`. The end of compressed content is told by its zstd frame instead.
+/// Content of unknown length can't be padded that way, only compressed content of it.
+fn encrypt<R: BufRead + Send, W: Write + Send>(
+    source: R,
+    len: Option<u64>,
+    metadata: Metadata,
+    holder: &dyn KeyHolder,
+    encoding: Encoding,
+    content: Content,
+    target: &mut W,
+) -> anyhow::Result<()> {
+    let cipher = write_preamble(
+        len,
+        metadata,
+        &DataKey::generate(),
+        holder,
+        encoding,
+        content,
+        target,
+    )?;
+    let Encoding {
+        padding,
+        compression,
+        ..
+    } = encoding;
+    let work = |id, last, data: Vec<u8>| Ok(cipher.encrypt(id, last, &data)?);
+    match (compression, padding) {
+        (Compression::Zstd, _) => {
+            let source = zstd::stream::read::Encoder::with_buffer(source, ZSTD_LEVEL)?;
+            let source = PaddedReader::new(source, padding);
+            multi_thread::file_transform(source, CHUNK_SIZE, target, work)
+        }
+        (Compression::None, Padding::None) => {
+            multi_thread::file_transform(source, CHUNK_SIZE, target, work)
+        }
+        (Compression::None, padding) => {
+            let len = len.unwrap_or(0);
+            let zeros = padding.padded_len(len) - len;
+            let source = source.take(len).chain(io::repeat(0).take(zeros));
+            multi_thread::file_transform(source, CHUNK_SIZE, target, work)
+        }
+    }
+}
+
+/// Encrypts uncompressed content of `len` bytes like `encrypt`, but resumable. Chunks follow
+/// those of the reopened file, if any. Every `every` chunks `checkpoint` is called with the
+/// target, the number of chunks written to it and the data key.
+#[allow(clippy::too_many_arguments)]
+fn encrypt_resumable<R, W, C>(
+    source: R,
+    len: u64,
+    metadata: Metadata,
+    holder: &dyn KeyHolder,
+    encoding: Encoding,
+    reopened: Option<Reopened>,
+    every: usize,
+    checkpoint: C,
+    target: &mut W,
+) -> anyhow::Result<()>
+where
+    R: BufRead + Send,
+    W: Write + Send,
+    C: Fn(&mut W, usize, &DataKey) -> anyhow::Result<()> + Sync,
+{
+    let zeros = encoding.padding.padded_len(len) - len;
+    let mut source = source.take(len).chain(io::repeat(0).take(zeros));
+    let (cipher, key, first_id) = match reopened {
+        Some(reopened) => {
+            // Content of written chunks is read anyway, it's part of the content hash.
+            let written = reopened.chunks * CHUNK_SIZE as u64;
+            io::copy(&mut (&mut source).take(written), &mut io::sink())?;
+            (reopened.cipher, reopened.key, reopened.chunks as usize)
+        }
+        None => {
+            let key = DataKey::generate();
+            let cipher = write_preamble(
+                Some(len),
+                metadata,
+                &key,
+                holder,
+                encoding,
+                Content::Data,
+                target,
+            )?;
+            (cipher, key, 0)
+        }
+    };
+    let work = |id, last, data: Vec<u8>| Ok(cipher.encrypt(id, last, &data)?);
+    multi_thread::resumable_transform(
+        source,
+        CHUNK_SIZE,
+        first_id,
+        target,
+        work,
+        every,
+        |target, chunks| checkpoint(target, chunks, &key),
+    )
+}
+
+/// Writes everything before the content, returns the cipher sealing its chunks with the key.
+fn write_preamble<W: Write>(
+    len: Option<u64>,
+    metadata: Metadata,
+    key: &DataKey,
+    holder: &dyn KeyHolder,
+    encoding: Encoding,
+    content: Content,
+    target: &mut W,
+) -> anyhow::Result<ChunkCipher> {
+    let Encoding {
+        cipher: suite,
+        padding,
+        compression,
+        ..
+    } = encoding;
+    let len = match len {
+        Some(len) => len,
+        None if has_content_len(padding, compression) => {
+            bail!("Content of unknown length is only padded when compressed")
+        }
+        None => 0,
+    };
+    let slot = holder.wrap_key(key)?;
+    let mut header = Header::new(suite, CHUNK_SIZE as u32, padding, compression, slot);
+    header.content = content;
+    let header = {
+        let mut bytes = Vec::new();
+        header.write(&mut bytes)?;
+        bytes
+    };
+    target.write_all(&header)?;
+    let cipher = ChunkCipher::new(suite, key, header);
+    let mut sealed = Vec::new();
+    if has_content_len(padding, compression) {
+        sealed.extend_from_slice(&len.to_be_bytes());
+    }
+    sealed.extend(metadata.to_bytes());
+    let sealed = cipher.encrypt_metadata(&sealed)?;
+    target.write_all(&(sealed.len() as u32).to_be_bytes())?;
+    target.write_all(&sealed)?;
+    Ok(cipher)
+}
+
+/// Stores chunks of the source in the repository, the target file only lists their ids.
+fn store<R: BufRead + Send, W: Write + Send>(
+    source: R,
+    metadata: Metadata,
+    holder: &dyn KeyHolder,
+    encoding: Encoding,
+    repository: &Repository,
+    target: &mut W,
+) -> anyhow::Result<()> {
+    let ids = repository.store(source, encoding.cipher, encoding.compression)?;
+    let encoding = Encoding {
+        compression: Compression::None,
+        ..encoding
+    };
+    let len = ids.len() as u64;
+    encrypt(
+        ids.as_slice(),
+        Some(len),
+        metadata,
+        holder,
+        encoding,
+        Content::ChunkIds,
+        target,
+    )
+}
+
+fn has_content_len(padding: Padding, compression: Compression) -> bool {
+    padding != Padding::None && compression == Compression::None
+}
+
+/// Written before headers were introduced: every block is encrypted with RSA directly.
+fn decrypt_legacy<W: Write + Send>(
+    mut source: BufReader<File>,
+    block_len: usize,
+    holder: &dyn KeyHolder,
+    target: &mut W,
+) -> anyhow::Result<()> {
+    source.rewind()?;
+    multi_thread::file_transform(source, block_len, target, |_, _, data| {
+        if data.is_empty() {
+            Ok(data)
+        } else {
+            holder.decrypt_block(data)
+        }
+    })
+}
+
+/// Decrypts what follows the header. Content stored in a repository is found next to the
+/// source file, so it can't be decrypted without one.
+fn decrypt<R: BufRead + Send, W: Write + Send>(
+    header: Header,
+    source_path: Option<&Path>,
+    mut source: R,
+    holder: &dyn KeyHolder,
+    repositories: &Repositories,
+    target: &mut W,
+) -> anyhow::Result<Option<Metadata>> {
+    let (cipher, content_len, metadata) = open_preamble(&header, &mut source, holder)?;
+    let chunk_len = header.chunk_size as usize + TAG_SIZE;
+    let work = |id, last, data: Vec<u8>| Ok(cipher.decrypt(id, last, &data)?);
+    match header.content {
+        Content::Data => match content_len {
+            Some(content_len) => {
+                let mut target = LimitedWriter::new(target, content_len);
+                decrypt_content(source, chunk_len, header.compression, work, &mut target)?;
+                if target.remaining() > 0 {
+                    bail!("Padded content is shorter than {} bytes", content_len);
+                }
+            }
+            None => decrypt_content(source, chunk_len, header.compression, work, target)?,
+        },
+        Content::ChunkIds => {
+            let mut ids = Vec::new();
+            decrypt_content(source, chunk_len, header.compression, work, &mut ids)?;
+            if let Some(content_len) = content_len {
+                if (ids.len() as u64) < content_len {
+                    bail!("Padded content is shorter than {} bytes", content_len);
+                }
+                ids.truncate(content_len as usize);
+            }
+            let source_path = match source_path {
+                Some(source_path) => source_path,
+                None => bail!("Content stored in a repository needs the encrypted file"),
+            };
+            repositories
+                .decryptor(source_path, holder)?
+                .restore(&ids, target)?;
+        }
+    }
+    Ok(metadata)
+}
+
+/// Reads everything between the header and the content, returns the cipher opening its
+/// chunks, the length of padded content and the metadata.
+fn open_preamble<R: Read>(
+    header: &Header,
+    source: &mut R,
+    holder: &dyn KeyHolder,
+) -> anyhow::Result<(ChunkCipher, Option<u64>, Option<Metadata>)> {
+    let key = holder.unwrap_key(&header.key)?;
+    let mut header_bytes = Vec::new();
+    header.write(&mut header_bytes)?;
+    let cipher = ChunkCipher::new(header.cipher, &key, header_bytes);
+    // Version 1 files were written before metadata was stored.
+    let metadata = if header.version >= 2 {
+        Some(read_metadata(source, &cipher)?)
+    } else {
+        None
+    };
+    let (content_len, metadata) = match metadata {
+        Some(metadata) if has_content_len(header.padding, header.compression) => {
+            if metadata.len() < 8 {
+                bail!("Invalid metadata size {}", metadata.len());
+            }
+            let (len, metadata) = metadata.split_at(8);
+            let len = u64::from_be_bytes(len.try_into()?);
+            (Some(len), Some(Metadata::from_bytes(metadata)?))
+        }
+        Some(metadata) => (None, Some(Metadata::from_bytes(&metadata)?)),
+        None => (None, None),
+    };
+    Ok((cipher, content_len, metadata))
+}
+
+fn decrypt_content<R, W, F>(
+    source: R,
+    chunk_len: usize,
+    compression: Compression,
+    work: F,
+    target: &mut W,
+) -> anyhow::Result<()>
+where
+    R: Read + Send,
+    W: Write + Send,
+    F: Fn(usize, bool, Vec<u8>) -> anyhow::Result<Vec<u8>> + Sync,
+{
+    match compression {
+        Compression::Zstd => {
+            let mut decompressor = Decompressor::new(&mut *target)?;
+            multi_thread::file_transform(source, chunk_len, &mut decompressor, work)?;
+            decompressor.finish()?;
+            Ok(())
+        }
+        Compression::None => multi_thread::file_transform(source, chunk_len, target, work),
+    }
+}
+
+/// Opens sealed metadata, it's parsed by the caller as it may start with the content length.
+fn read_metadata<R: Read>(source: &mut R, cipher: &ChunkCipher) -> anyhow::Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    source.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len);
+    if len < TAG_SIZE as u32 || len > MAX_METADATA_SIZE + TAG_SIZE as u32 {
+        bail!("Invalid metadata size {}", len);
+    }
+    let mut sealed = vec![0u8; len as usize];
+    source.read_exact(&mut sealed)?;
+    Ok(cipher.decrypt_metadata(&sealed)?)
+}
@@ -1,21 +1,46 @@
-use crate::worker::rsa::holder::RsaHolder;
 use rayon::iter::ParallelBridge;
 use rayon::iter::ParallelIterator;
-use std::fs::File;
 use std::io;
-use std::io::Write;
-use std::io::{BufReader, BufWriter, Read};
+use std::io::{Read, Write};
 use std::sync::{Arc, Mutex, RwLock};
 
-pub(super) fn file_transform(
-    source: BufReader<File>,
-    rsa: RsaHolder,
+pub(super) fn file_transform<R, W, F>(
+    source: R,
     message_len: usize,
-    target: &mut BufWriter<File>,
-) -> anyhow::Result<()> {
-    let source = ParallelFile::new(source, message_len);
+    target: &mut W,
+    work: F,
+) -> anyhow::Result<()>
+where
+    R: Read + Send,
+    W: Write + Send,
+    F: Fn(usize, bool, Vec<u8>) -> anyhow::Result<Vec<u8>> + Sync,
+{
+    resumable_transform(source, message_len, 0, target, work, usize::MAX, |_, _| {
+        Ok(())
+    })
+}
+
+/// Transforms the source into chunks numbered from `first_id`, as the rest of a transform
+/// that was interrupted after `first_id` chunks. Once every `every` chunks are written, except
+/// after the last one, `checkpoint` is called with the target and the number of written chunks.
+pub(super) fn resumable_transform<R, W, F, C>(
+    source: R,
+    message_len: usize,
+    first_id: usize,
+    target: &mut W,
+    work: F,
+    every: usize,
+    checkpoint: C,
+) -> anyhow::Result<()>
+where
+    R: Read + Send,
+    W: Write + Send,
+    F: Fn(usize, bool, Vec<u8>) -> anyhow::Result<Vec<u8>> + Sync,
+    C: Fn(&mut W, usize) -> anyhow::Result<()> + Sync,
+{
+    let source = ParallelFile::new(source, message_len, first_id);
     let buffered_target = Arc::new(Mutex::new(target));
-    let pending_chunks = PendingChunks::new();
+    let pending_chunks = PendingChunks::new(first_id);
     let error: Arc<RwLock<Option<anyhow::Error>>> = Arc::new(RwLock::new(None));
     source.into_iter().par_bridge().for_each(|chunk| {
         let error_lock = error.read().unwrap();
@@ -32,37 +57,44 @@ pub(super) fn file_transform(
                 return;
             }
         };
-        let transformed = match rsa.work(chunk.data) {
+        let transformed = match work(chunk.id, chunk.last, chunk.data) {
             Ok(bytes) => bytes,
             Err(e) => {
                 let mut error_lock = error.write().unwrap();
-                *error_lock = Some(e.into());
+                *error_lock = Some(e);
                 return;
             }
         };
         let mut chunks = pending_chunks.inner.lock().unwrap();
         if chunks.next == chunk.id {
             let mut t = buffered_target.lock().unwrap();
-            if let Err(e) = t.write_all(&transformed) {
-                let mut error_lock = error.write().unwrap();
-                *error_lock = Some(e.into());
-                return;
-            }
-            chunks.next += 1;
-            let mut next = chunks.next;
-            while let Some(found) = chunks.find(next) {
-                if let Err(e) = t.write_all(&found.data) {
+            let mut current = Some(Chunk {
+                data: transformed,
+                id: chunk.id,
+                last: chunk.last,
+            });
+            while let Some(found) = current {
+                let written = t.write_all(&found.data).map_err(anyhow::Error::from);
+                let written = match written {
+                    Ok(()) if !found.last && (found.id + 1) % every == 0 => {
+                        checkpoint(&mut **t, found.id + 1)
+                    }
+                    written => written,
+                };
+                if let Err(e) = written {
                     let mut error_lock = error.write().unwrap();
-                    *error_lock = Some(e.into());
+                    *error_lock = Some(e);
                     return;
                 }
                 chunks.next += 1;
-                next = chunks.next;
+                let next = chunks.next;
+                current = chunks.find(next);
             }
         } else {
             chunks.push(Chunk {
                 data: transformed,
                 id: chunk.id,
+                last: chunk.last,
             });
         }
     });
@@ -74,25 +106,30 @@ pub(super) fn file_transform(
     }
 }
 
-struct ParallelFile {
-    inner: Arc<Mutex<InnerParallelFile>>,
+struct ParallelFile<R> {
+    inner: Arc<Mutex<InnerParallelFile<R>>>,
 }
 
-struct InnerParallelFile {
-    file: BufReader<File>,
+struct InnerParallelFile<R> {
+    file: R,
     chunk_size: usize,
     next_id: usize,
     was_error: bool,
+    /// Chunk read ahead, needed to tell whether the current one is the last.
+    ahead: Option<Vec<u8>>,
+    finished: bool,
 }
 
-impl ParallelFile {
-    fn new(file: BufReader<File>, chunk_size: usize) -> Self {
+impl<R: Read> ParallelFile<R> {
+    fn new(file: R, chunk_size: usize, first_id: usize) -> Self {
         Self {
             inner: Arc::new(Mutex::new(InnerParallelFile {
                 file,
                 chunk_size,
-                next_id: 0,
+                next_id: first_id,
                 was_error: false,
+                ahead: None,
+                finished: false,
             })),
         }
     }
@@ -101,34 +138,48 @@ impl ParallelFile {
 struct Chunk {
     data: Vec<u8>,
     id: usize,
+    last: bool,
 }
 
-impl Iterator for ParallelFile {
+impl<R: Read> InnerParallelFile<R> {
+    fn read(&mut self) -> io::Result<Vec<u8>> {
+        let mut buffer = vec![0u8; self.chunk_size];
+        let len = read_chunk(&mut self.file, &mut buffer[..])?;
+        buffer.truncate(len);
+        Ok(buffer)
+    }
+
+    fn read_both(&mut self) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        let current = match self.ahead.take() {
+            Some(current) => current,
+            None => self.read()?,
+        };
+        Ok((current, self.read()?))
+    }
+}
+
+/// Yields chunks in order. The last one is marked, an empty source yields a single empty chunk.
+impl<R: Read> Iterator for ParallelFile<R> {
     type Item = io::Result<Chunk>;
 
     fn next(&mut self) -> Option<Self::Item> {
         let mut inner = self.inner.lock().unwrap();
-        if inner.was_error {
+        if inner.was_error || inner.finished {
             return None;
         }
-        let size = inner.chunk_size;
-        let mut buffer = vec![0u8; size];
-        let result = inner.file.read(&mut buffer[..]);
         let id = inner.next_id;
         inner.next_id += 1;
-        drop(inner);
-
-        match result {
-            Ok(len) => {
-                if len == 0 {
-                    None
+        match inner.read_both() {
+            Ok((data, ahead)) => {
+                let last = ahead.is_empty();
+                if last {
+                    inner.finished = true;
                 } else {
-                    buffer.truncate(len);
-                    Some(Ok(Chunk { data: buffer, id }))
+                    inner.ahead = Some(ahead);
                 }
+                Some(Ok(Chunk { data, id, last }))
             }
             Err(e) => {
-                let mut inner = self.inner.lock().unwrap();
                 inner.was_error = true;
                 Some(Err(e))
             }
@@ -136,6 +187,21 @@ impl Iterator for ParallelFile {
     }
 }
 
+/// Reads until the buffer is full or the end of file is reached, so chunk boundaries don't
+/// depend on how the underlying reader splits the data.
+pub(super) fn read_chunk<R: Read>(reader: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buffer.len() {
+        match reader.read(&mut buffer[len..]) {
+            Ok(0) => break,
+            Ok(read) => len += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
 struct PendingChunks {
     inner: Arc<Mutex<InnerPendingChunks>>,
 }
@@ -161,11 +227,11 @@ impl InnerPendingChunks {
 }
 
 impl PendingChunks {
-    fn new() -> Self {
+    fn new(first_id: usize) -> Self {
         Self {
             inner: Arc::new(Mutex::new(InnerPendingChunks {
                 chunks: Vec::with_capacity(32),
-                next: 0,
+                next: first_id,
             })),
         }
     }
@@ -0,0 +1,273 @@
+use crate::padding::Padding;
+use std::io;
+use std::io::{Read, Write};
+use zstd::stream::raw::{Decoder, InBuffer, Operation, OutBuffer};
+
+/// Appends zeros once the inner reader ends, so padding doesn't need the length upfront.
+pub(super) struct PaddedReader<R> {
+    inner: R,
+    padding: Padding,
+    len: u64,
+    zeros: Option<u64>,
+}
+
+impl<R: Read> PaddedReader<R> {
+    pub(super) fn new(inner: R, padding: Padding) -> Self {
+        Self {
+            inner,
+            padding,
+            len: 0,
+            zeros: None,
+        }
+    }
+}
+
+impl<R: Read> Read for PaddedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(zeros) = self.zeros.as_mut() {
+            let len = buf.len().min(usize::try_from(*zeros).unwrap_or(usize::MAX));
+            buf[..len].fill(0);
+            *zeros -= len as u64;
+            return Ok(len);
+        }
+        let read = self.inner.read(buf)?;
+        if read == 0 && !buf.is_empty() {
+            self.zeros = Some(self.padding.padded_len(self.len) - self.len);
+            return self.read(buf);
+        }
+        self.len += read as u64;
+        Ok(read)
+    }
+}
+
+/// Hashes everything read through it.
+pub(super) struct HashingReader<'a, R> {
+    inner: R,
+    hasher: &'a mut blake3::Hasher,
+}
+
+impl<'a, R: Read> HashingReader<'a, R> {
+    pub(super) fn new(inner: R, hasher: &'a mut blake3::Hasher) -> Self {
+        Self { inner, hasher }
+    }
+}
+
+impl<R: Read> Read for HashingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// Writes at most the given number of bytes, the rest is dropped.
+pub(super) struct LimitedWriter<W> {
+    inner: W,
+    remaining: u64,
+}
+
+impl<W: Write> LimitedWriter<W> {
+    pub(super) fn new(inner: W, limit: u64) -> Self {
+        Self {
+            inner,
+            remaining: limit,
+        }
+    }
+
+    /// Bytes that can still be written.
+    pub(super) fn remaining(&self) -> u64 {
+        self.remaining
+    }
+}
+
+impl<W: Write> Write for LimitedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf
+            .len()
+            .min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        self.inner.write_all(&buf[..len])?;
+        self.remaining -= len as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Decompresses a single zstd frame into the target. Only zeros may follow the frame,
+/// they are padding and get dropped.
+pub(super) struct Decompressor<W> {
+    target: W,
+    decoder: Decoder<'static>,
+    buffer: Vec<u8>,
+    finished: bool,
+}
+
+impl<W: Write> Decompressor<W> {
+    pub(super) fn new(target: W) -> io::Result<Self> {
+        Ok(Self {
+            target,
+            decoder: Decoder::new()?,
+            buffer: vec![0u8; zstd::zstd_safe::DCtx::out_size()],
+            finished: false,
+        })
+    }
+
+    /// Fails when the frame wasn't complete, returns the target otherwise.
+    pub(super) fn finish(mut self) -> io::Result<W> {
+        if !self.finished {
+            self.run(&[])?;
+        }
+        if !self.finished {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "compressed content is truncated",
+            ));
+        }
+        self.target.flush()?;
+        Ok(self.target)
+    }
+
+    fn run(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut input = InBuffer::around(data);
+        loop {
+            if self.finished {
+                if data[input.pos()..].iter().any(|byte| *byte != 0) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "unexpected data after compressed content",
+                    ));
+                }
+                return Ok(());
+            }
+            let mut output = OutBuffer::around(&mut self.buffer[..]);
+            let hint = self.decoder.run(&mut input, &mut output)?;
+            let written = output.pos();
+            self.target.write_all(&self.buffer[..written])?;
+            self.finished = hint == 0;
+            if input.pos() == data.len() && written < self.buffer.len() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for Decompressor<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.run(data)?;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.target.flush()
+    }
+}
+
+/// Decompresses a single zstd frame given piece by piece, into buffers of the caller and at
+/// most their size at a time, so highly compressed content doesn't inflate all at once.
+/// Only zeros may follow the frame, like with [`Decompressor`].
+pub(super) struct ChunkDecompressor {
+    decoder: Decoder<'static>,
+    input: Vec<u8>,
+    pos: usize,
+    drained: bool,
+    finished: bool,
+}
+
+impl ChunkDecompressor {
+    pub(super) fn new() -> io::Result<Self> {
+        Ok(Self {
+            decoder: Decoder::new()?,
+            input: Vec::new(),
+            pos: 0,
+            drained: true,
+            finished: false,
+        })
+    }
+
+    /// Tells whether everything given is decompressed, so the next piece is needed.
+    pub(super) fn needs_input(&self) -> bool {
+        self.drained && self.pos == self.input.len()
+    }
+
+    pub(super) fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Gives the next piece of compressed content, the previous one must be decompressed.
+    pub(super) fn feed(&mut self, input: Vec<u8>) {
+        self.input = input;
+        self.pos = 0;
+    }
+
+    /// Decompresses into the buffer, returns the number of bytes written. Nothing is written
+    /// once the frame is finished.
+    pub(super) fn decompress(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let mut written = 0;
+        if !self.finished {
+            let capacity = buffer.len();
+            let mut input = InBuffer::around(&self.input[self.pos..]);
+            let mut output = OutBuffer::around(buffer);
+            let hint = self.decoder.run(&mut input, &mut output)?;
+            self.pos += input.pos();
+            written = output.pos();
+            // A full buffer may leave decompressed content behind in the decoder.
+            self.drained = written < capacity;
+            self.finished = hint == 0;
+        }
+        if self.finished {
+            if self.input[self.pos..].iter().any(|byte| *byte != 0) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unexpected data after compressed content",
+                ));
+            }
+            self.pos = self.input.len();
+            self.drained = true;
+        }
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compression::ZSTD_LEVEL;
+
+    #[test]
+    fn should_decompress_padded_frame() {
+        let original = b"caverr ".repeat(10_000);
+        let mut compressed = Vec::new();
+        let source = zstd::stream::read::Encoder::new(original.as_slice(), ZSTD_LEVEL)
+            .expect("Unable to create encoder");
+        PaddedReader::new(source, Padding::PowerOfTwo)
+            .read_to_end(&mut compressed)
+            .expect("Unable to compress");
+        assert!(compressed.len().is_power_of_two());
+        assert!(compressed.len() < original.len());
+
+        let mut decompressed = Vec::new();
+        let mut decompressor = Decompressor::new(&mut decompressed).expect("No decompressor");
+        for chunk in compressed.chunks(100) {
+            decompressor.write_all(chunk).expect("Unable to decompress");
+        }
+        decompressor.finish().expect("Unable to finish");
+        assert_eq!(original, decompressed);
+
+        let frame_len = zstd::stream::encode_all(original.as_slice(), ZSTD_LEVEL)
+            .expect("Unable to compress")
+            .len();
+        assert!(frame_len < compressed.len());
+        let mut garbage = compressed.clone();
+        garbage[frame_len] = 1;
+        let mut decompressor = Decompressor::new(Vec::new()).expect("No decompressor");
+        assert!(decompressor.write_all(&garbage).is_err());
+
+        let mut decompressor = Decompressor::new(Vec::new()).expect("No decompressor");
+        decompressor
+            .write_all(&compressed[..frame_len - 1])
+            .expect("Unable to decompress");
+        assert!(decompressor.finish().is_err());
+    }
+}
@@ -0,0 +1,488 @@
+use crate::file::metadata::Timestamp;
+use crate::file::{hash_file, ContentHash};
+use crate::snapshot::{read_array, read_path, read_timestamp, write_path, write_timestamp};
+use anyhow::{bail, Context};
+use rand::{thread_rng, RngCore};
+use std::collections::HashMap;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+const INDEX_MAGIC: &[u8; 8] = b"CAVERRIX";
+const INDEX_VERSION: u8 = 1;
+
+/// Source file as last seen, a change of any of these tells its content may have changed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct FileState {
+    size: u64,
+    modified: Timestamp,
+    inode: u64,
+    changed: Timestamp,
+}
+
+impl FileState {
+    #[cfg(unix)]
+    pub(crate) fn of(metadata: &fs::Metadata) -> Self {
+        Self {
+            size: metadata.size(),
+            modified: Timestamp::modified(metadata),
+            inode: metadata.ino(),
+            changed: Timestamp {
+                secs: metadata.ctime(),
+                nanos: metadata.ctime_nsec() as u32,
+            },
+        }
+    }
+
+    /// Without inodes and change times, the file is only told apart by size and mtime.
+    #[cfg(not(unix))]
+    pub(crate) fn of(metadata: &fs::Metadata) -> Self {
+        let modified = Timestamp::modified(metadata);
+        Self {
+            size: metadata.len(),
+            modified,
+            inode: 0,
+            changed: modified,
+        }
+    }
+
+    /// Layout: `size u64 | modified | inode u64 | changed`.
+    pub(crate) fn write(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(&self.size.to_be_bytes());
+        write_timestamp(bytes, self.modified);
+        bytes.extend_from_slice(&self.inode.to_be_bytes());
+        write_timestamp(bytes, self.changed);
+    }
+
+    pub(crate) fn read(r: &mut &[u8]) -> anyhow::Result<Self> {
+        Ok(Self {
+            size: u64::from_be_bytes(read_array(r)?),
+            modified: read_timestamp(r)?,
+            inode: u64::from_be_bytes(read_array(r)?),
+            changed: read_timestamp(r)?,
+        })
+    }
+}
+
+/// Source file encrypted before, with the encrypted file written for it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct IndexEntry {
+    state: FileState,
+    hash: ContentHash,
+    target: PathBuf,
+    written: Timestamp,
+}
+
+/// Indexed entries, with sources by their size and modification time. Files extracted from
+/// an archive or generated together often share both, so there may be many of them.
+#[derive(Default)]
+struct Entries {
+    by_path: HashMap<PathBuf, IndexEntry>,
+    by_state: HashMap<(u64, Timestamp), Vec<PathBuf>>,
+}
+
+impl Entries {
+    fn insert(&mut self, path: PathBuf, entry: IndexEntry) {
+        let state = (entry.state.size, entry.state.modified);
+        if let Some(previous) = self.by_path.insert(path.clone(), entry) {
+            self.remove_state(&path, (previous.state.size, previous.state.modified));
+        }
+        self.by_state.entry(state).or_default().push(path);
+    }
+
+    fn remove_state(&mut self, path: &Path, state: (u64, Timestamp)) {
+        if let Some(paths) = self.by_state.get_mut(&state) {
+            paths.retain(|known| known != path);
+            if paths.is_empty() {
+                self.by_state.remove(&state);
+            }
+        }
+    }
+
+    /// Indexed sources other than `path` with the given size and modification time.
+    fn with_state(&self, path: &Path, state: FileState) -> Vec<(PathBuf, IndexEntry)> {
+        self.by_state
+            .get(&(state.size, state.modified))
+            .into_iter()
+            .flatten()
+            .filter(|original| *original != path)
+            .filter_map(|original| Some((original.clone(), self.by_path.get(original)?.clone())))
+            .collect()
+    }
+}
+
+/// Indexed source with the same content as another one, its encrypted file can be reused.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Original {
+    pub(crate) source: PathBuf,
+    pub(crate) target: PathBuf,
+    pub(crate) hash: ContentHash,
+}
+
+/// What the index knows about a source file and its encrypted target.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Lookup {
+    /// The target holds the current content of the source, with this hash.
+    Unchanged(FileState, ContentHash),
+    /// The source or the target changed since they were indexed.
+    Changed(FileState),
+    /// The source isn't indexed yet.
+    Unknown(FileState),
+}
+
+impl Lookup {
+    pub(crate) fn state(&self) -> FileState {
+        match self {
+            Self::Unchanged(state, _) | Self::Changed(state) | Self::Unknown(state) => *state,
+        }
+    }
+
+    pub(crate) fn hash(&self) -> Option<ContentHash> {
+        match self {
+            Self::Unchanged(_, hash) => Some(*hash),
+            Self::Changed(_) | Self::Unknown(_) => None,
+        }
+    }
+}
+
+/// Local record of source files encrypted into a target directory, so unchanged files are
+/// told by their state and content hash instead of modification times.
+pub(crate) struct Index {
+    path: PathBuf,
+    target_dir: PathBuf,
+    checksum: bool,
+    entries: Mutex<Entries>,
+}
+
+impl Index {
+    /// Reads the index file, a missing one is created when written. In `checksum` mode
+    /// content of every source file is hashed, even when its state is unchanged.
+    pub(crate) fn open(path: &Path, target_dir: &Path, checksum: bool) -> anyhow::Result<Self> {
+        let entries = match fs::read(path) {
+            Ok(bytes) => {
+                from_bytes(&bytes).with_context(|| format!("Invalid index file {:?}", path))?
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Entries::default(),
+            Err(e) => return Err(e).with_context(|| format!("Unable to read index {:?}", path)),
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            target_dir: target_dir.to_path_buf(),
+            checksum,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    /// Compares the source with its state and content when it was encrypted to the target.
+    pub(crate) fn lookup(&self, source: &Path, target: &Path) -> anyhow::Result<Lookup> {
+        let state = FileState::of(&fs::metadata(source)?);
+        let known = self
+            .entries
+            .lock()
+            .unwrap()
+            .by_path
+            .get(&source.canonicalize()?)
+            .cloned();
+        let known = match known {
+            Some(known) => known,
+            None if self.checksum => return Ok(Lookup::Changed(state)),
+            None => return Ok(Lookup::Unknown(state)),
+        };
+        let written = match fs::metadata(target) {
+            Ok(metadata) => Timestamp::modified(&metadata),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Lookup::Changed(state)),
+            Err(e) => return Err(e.into()),
+        };
+        if known.target != target.strip_prefix(&self.target_dir)? || known.written != written {
+            return Ok(Lookup::Changed(state));
+        }
+        if known.state == state && !self.checksum {
+            return Ok(Lookup::Unchanged(state, known.hash));
+        }
+        let hash =
+            hash_file(source).with_context(|| format!("Unable to hash file {:?}", source))?;
+        if hash == known.hash {
+            Ok(Lookup::Unchanged(state, hash))
+        } else {
+            Ok(Lookup::Changed(state))
+        }
+    }
+
+    /// Finds another indexed source the source was likely moved or copied from. It has the
+    /// same size, modification time and content, so its encrypted file holds the same content
+    /// and metadata.
+    pub(crate) fn find_original(
+        &self,
+        source: &Path,
+        state: FileState,
+    ) -> anyhow::Result<Option<Original>> {
+        let path = source.canonicalize()?;
+        let candidates = self.entries.lock().unwrap().with_state(&path, state);
+        let mut source_hash = None;
+        for (original, entry) in candidates {
+            let target = self.target_dir.join(&entry.target);
+            match fs::metadata(&target) {
+                Ok(metadata) if Timestamp::modified(&metadata) == entry.written => {}
+                _ => continue,
+            }
+            let hash = match source_hash {
+                Some(hash) => hash,
+                None => *source_hash.insert(
+                    hash_file(source)
+                        .with_context(|| format!("Unable to hash file {:?}", source))?,
+                ),
+            };
+            if hash == entry.hash {
+                return Ok(Some(Original {
+                    source: original,
+                    target,
+                    hash,
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Records the source, in the state it had before it was encrypted to the target.
+    pub(crate) fn add(
+        &self,
+        source: &Path,
+        state: FileState,
+        target: &Path,
+        hash: ContentHash,
+    ) -> anyhow::Result<()> {
+        let entry = IndexEntry {
+            state,
+            hash,
+            target: target.strip_prefix(&self.target_dir)?.to_path_buf(),
+            written: Timestamp::modified(&fs::metadata(target)?),
+        };
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(source.canonicalize()?, entry);
+        Ok(())
+    }
+
+    /// Writes the index file, without source files that are gone.
+    pub(crate) fn write(&self) -> anyhow::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        let gone: Vec<_> = entries
+            .by_path
+            .iter()
+            .filter(|(path, _)| fs::symlink_metadata(path).is_err())
+            .map(|(path, entry)| (path.clone(), (entry.state.size, entry.state.modified)))
+            .collect();
+        for (path, state) in gone {
+            entries.by_path.remove(&path);
+            entries.remove_state(&path, state);
+        }
+        let bytes = to_bytes(&entries.by_path);
+        drop(entries);
+        let dir = self.path.parent().unwrap_or_else(|| Path::new("."));
+        fs::create_dir_all(dir)?;
+        let tmp_path = dir.join(format!("{}.tmp", thread_rng().next_u64()));
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let written = options
+            .open(&tmp_path)
+            .and_then(|mut file| file.write_all(&bytes).and_then(|_| file.sync_all()))
+            .and_then(|_| fs::rename(&tmp_path, &self.path));
+        if written.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+        written.with_context(|| format!("Unable to write index {:?}", self.path))
+    }
+}
+
+/// Index file of the target directory in the user's cache directory, `None` when there is
+/// no home directory.
+pub fn default_index_file(target_dir: &Path) -> Option<PathBuf> {
+    let cache_dir = std::env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?;
+    let target_dir = target_dir.canonicalize().ok()?;
+    let hash = blake3::hash(target_dir.as_os_str().as_encoded_bytes());
+    Some(
+        cache_dir
+            .join("caverr")
+            .join(format!("{}.index", &hash.to_hex()[..32])),
+    )
+}
+
+/// Layout: `magic | version u8 | count u64 | entry...`.
+fn to_bytes(entries: &HashMap<PathBuf, IndexEntry>) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(INDEX_MAGIC);
+    bytes.push(INDEX_VERSION);
+    bytes.extend_from_slice(&(entries.len() as u64).to_be_bytes());
+    for (path, entry) in entries {
+        write_path(&mut bytes, path);
+        entry.state.write(&mut bytes);
+        bytes.extend_from_slice(&entry.hash);
+        write_path(&mut bytes, &entry.target);
+        write_timestamp(&mut bytes, entry.written);
+    }
+    bytes
+}
+
+fn from_bytes(mut bytes: &[u8]) -> anyhow::Result<Entries> {
+    let r = &mut bytes;
+    if &read_array::<8>(r)? != INDEX_MAGIC {
+        bail!("Not an index file");
+    }
+    let [version] = read_array(r)?;
+    if version != INDEX_VERSION {
+        bail!("Unsupported index version {}", version);
+    }
+    let mut entries = Entries::default();
+    for _ in 0..u64::from_be_bytes(read_array(r)?) {
+        let path = read_path(r)?;
+        let entry = IndexEntry {
+            state: FileState::read(r)?,
+            hash: read_array(r)?,
+            target: read_path(r)?,
+            written: read_timestamp(r)?,
+        };
+        entries.insert(path, entry);
+    }
+    if !r.is_empty() {
+        bail!("Unexpected data after index entries");
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::File;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn should_detect_changes() {
+        let tmp = tempfile::TempDir::new().expect("Unable to create temp dir");
+        let target_dir = tmp.path().join("target");
+        fs::create_dir_all(&target_dir).expect("Unable to create dir");
+        let source = tmp.path().join("source");
+        let target = target_dir.join("source");
+        fs::write(&source, b"content").expect("Unable to write");
+        fs::write(&target, b"encrypted").expect("Unable to write");
+        let index_file = tmp.path().join("cache").join("index");
+        let open = |checksum| Index::open(&index_file, &target_dir, checksum).expect("No index");
+
+        let index = open(false);
+        let lookup = index.lookup(&source, &target).expect("Unable to look up");
+        assert!(matches!(lookup, Lookup::Unknown(_)));
+        assert!(matches!(
+            open(true).lookup(&source, &target),
+            Ok(Lookup::Changed(_))
+        ));
+        let hash = hash_file(&source).expect("Unable to hash");
+        index
+            .add(&source, lookup.state(), &target, hash)
+            .expect("Unable to add");
+        index.write().expect("Unable to write index");
+
+        let index = open(false);
+        let lookup = index.lookup(&source, &target).expect("Unable to look up");
+        assert_eq!(Lookup::Unchanged(lookup.state(), hash), lookup);
+
+        // Touched, but the same content.
+        let touched = SystemTime::now() + Duration::from_secs(60);
+        File::options()
+            .write(true)
+            .open(&source)
+            .and_then(|file| file.set_modified(touched))
+            .expect("Unable to touch");
+        let touched = index.lookup(&source, &target).expect("Unable to look up");
+        assert_ne!(lookup.state(), touched.state());
+        assert_eq!(Some(hash), touched.hash());
+
+        // Modified, with the modification time restored.
+        fs::write(&source, b"CONTENT").expect("Unable to write");
+        File::options()
+            .write(true)
+            .open(&source)
+            .and_then(|file| file.set_modified(touched.state().modified.to_system_time()))
+            .expect("Unable to touch");
+        assert!(matches!(
+            index.lookup(&source, &target),
+            Ok(Lookup::Changed(_))
+        ));
+
+        // The target was replaced since.
+        fs::write(&source, b"content").expect("Unable to write");
+        let lookup = index.lookup(&source, &target).expect("Unable to look up");
+        assert_eq!(Some(hash), lookup.hash());
+        fs::write(target_dir.join("replacement"), b"other").expect("Unable to write");
+        File::options()
+            .write(true)
+            .open(target_dir.join("replacement"))
+            .and_then(|file| file.set_modified(SystemTime::UNIX_EPOCH))
+            .expect("Unable to touch");
+        fs::rename(target_dir.join("replacement"), &target).expect("Unable to rename");
+        assert!(matches!(
+            index.lookup(&source, &target),
+            Ok(Lookup::Changed(_))
+        ));
+
+        fs::remove_file(&source).expect("Unable to remove");
+        index.write().expect("Unable to write index");
+        assert!(open(false).entries.lock().unwrap().by_path.is_empty());
+    }
+
+    #[test]
+    fn should_find_original_among_files_with_same_state() {
+        let tmp = tempfile::TempDir::new().expect("Unable to create temp dir");
+        let target_dir = tmp.path().join("target");
+        fs::create_dir_all(&target_dir).expect("Unable to create dir");
+        let index = Index::open(&tmp.path().join("index"), &target_dir, false).expect("No index");
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        let write = |path: &Path, content: &[u8]| {
+            fs::write(path, content).expect("Unable to write");
+            File::options()
+                .write(true)
+                .open(path)
+                .and_then(|file| file.set_modified(modified))
+                .expect("Unable to touch");
+        };
+        for name in ["first", "second", "third"] {
+            let source = tmp.path().join(name);
+            let target = target_dir.join(name);
+            write(&source, &name.as_bytes()[..5]);
+            fs::write(&target, b"encrypted").expect("Unable to write");
+            let state = FileState::of(&fs::metadata(&source).expect("No metadata"));
+            let hash = hash_file(&source).expect("Unable to hash");
+            index
+                .add(&source, state, &target, hash)
+                .expect("Unable to add");
+        }
+
+        let copy = tmp.path().join("copy");
+        write(&copy, b"secon");
+        let state = FileState::of(&fs::metadata(&copy).expect("No metadata"));
+        let original = index
+            .find_original(&copy, state)
+            .expect("Unable to find")
+            .expect("No original");
+        assert_eq!(
+            original.source,
+            tmp.path().join("second").canonicalize().unwrap()
+        );
+        assert_eq!(original.target, target_dir.join("second"));
+
+        write(&copy, b"other");
+        assert_eq!(
+            None,
+            index.find_original(&copy, state).expect("Unable to find")
+        );
+    }
+}
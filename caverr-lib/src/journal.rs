@@ -0,0 +1,489 @@
+use crate::cipher::{DataKey, DATA_KEY_SIZE};
+use crate::file::ContentHash;
+use crate::index::{FileState, Lookup};
+use crate::key_file::open_or_create_wrapped_key;
+use crate::snapshot::{read_array, read_path, write_path};
+use crate::worker::KeyHolder;
+use aes_gcm_siv::aead::{Aead, KeyInit};
+use aes_gcm_siv::{Aes256GcmSiv, Nonce};
+use anyhow::{anyhow, bail, Context};
+use rand::{thread_rng, RngCore};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::{fs, io};
+
+/// Directory of the target tree holding the journal of an encryption run.
+pub const JOURNAL_DIR: &str = ".caverr-journal";
+
+const KEY_FILE: &str = "key";
+const LOG_FILE: &str = "log";
+
+const COMPLETED: u8 = 1;
+const PROGRESS: u8 = 2;
+
+const NONCE_SIZE: usize = 12;
+
+/// Files at least this large are resumed from their last checkpoint.
+const RESUMABLE_LEN: u64 = 64 * 1024 * 1024;
+
+/// Chunks encrypted between checkpoints of a resumable file.
+const CHECKPOINT_CHUNKS: usize = 256;
+
+/// Source file encrypted by an interrupted run.
+struct Completed {
+    state: FileState,
+    hash: ContentHash,
+    target: PathBuf,
+}
+
+/// Source file an interrupted run was encrypting into a temporary file. Its first `chunks`
+/// chunks are sealed with the data key.
+struct Progress {
+    state: FileState,
+    tmp: PathBuf,
+    chunks: u64,
+    key: DataKey,
+}
+
+/// Temporary file an interrupted run was encrypting the source into, to be reopened.
+pub(crate) struct Resumed {
+    pub(crate) tmp: PathBuf,
+    pub(crate) chunks: u64,
+    pub(crate) key: DataKey,
+}
+
+/// Record of an encryption run kept in the target tree until the run is finished, so the next
+/// run skips files an interrupted one encrypted and resumes large files where it stopped.
+/// Records are appended as files are encrypted, they survive a killed run. They are sealed
+/// with the journal key, as they tell source names and hashes of their content.
+pub(crate) struct Journal {
+    target_dir: PathBuf,
+    log: Mutex<File>,
+    cipher: Aes256GcmSiv,
+    completed: HashMap<PathBuf, Completed>,
+    progress: Mutex<HashMap<PathBuf, Progress>>,
+    pub(crate) resumable_len: u64,
+    pub(crate) checkpoint_chunks: usize,
+}
+
+impl Journal {
+    /// Reads what an interrupted run recorded in the target tree and starts recording this run.
+    /// The key sealing records is wrapped by the holder and kept there, the given one or a new
+    /// one when missing.
+    pub(crate) fn open(
+        target_dir: &Path,
+        holder: &dyn KeyHolder,
+        key: Option<DataKey>,
+    ) -> anyhow::Result<Self> {
+        let dir = target_dir.join(JOURNAL_DIR);
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Unable to create journal in {:?}", target_dir))?;
+        let key = open_or_create_wrapped_key(&dir.join(KEY_FILE), JOURNAL_DIR, holder, key)
+            .with_context(|| format!("Unable to use journal key of {:?}", target_dir))?;
+        let log_path = dir.join(LOG_FILE);
+        let cipher = Aes256GcmSiv::new(key.as_bytes().into());
+        let (completed, progress) = match fs::read(&log_path) {
+            Ok(bytes) => from_bytes(&bytes, &cipher)
+                .with_context(|| format!("Invalid journal file {:?}", log_path))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Default::default(),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Unable to read journal {:?}", log_path))
+            }
+        };
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)
+            .with_context(|| format!("Unable to write journal {:?}", log_path))?;
+        Ok(Self {
+            target_dir: target_dir.to_path_buf(),
+            log: Mutex::new(log),
+            cipher,
+            completed,
+            progress: Mutex::new(progress),
+            resumable_len: RESUMABLE_LEN,
+            checkpoint_chunks: CHECKPOINT_CHUNKS,
+        })
+    }
+
+    /// What an interrupted run knew of the source when it encrypted it to the target, if the
+    /// source is unchanged since.
+    pub(crate) fn completed(&self, source: &Path, target: &Path) -> anyhow::Result<Option<Lookup>> {
+        let completed = match self.completed.get(&source.canonicalize()?) {
+            Some(completed) => completed,
+            None => return Ok(None),
+        };
+        let state = FileState::of(&fs::metadata(source)?);
+        let unchanged = completed.state == state
+            && self.target_dir.join(&completed.target) == target
+            && target.is_file();
+        Ok(unchanged.then_some(Lookup::Unchanged(state, completed.hash)))
+    }
+
+    /// Records the source as encrypted to the target, in the state it had before.
+    pub(crate) fn complete(
+        &self,
+        source: &Path,
+        state: FileState,
+        target: &Path,
+        hash: ContentHash,
+    ) -> anyhow::Result<()> {
+        let mut body = Vec::new();
+        write_path(&mut body, &source.canonicalize()?);
+        state.write(&mut body);
+        body.extend_from_slice(&hash);
+        write_path(&mut body, target.strip_prefix(&self.target_dir)?);
+        self.append(COMPLETED, &body)?;
+        self.progress
+            .lock()
+            .unwrap()
+            .remove(&source.canonicalize()?);
+        Ok(())
+    }
+
+    /// Records the first `chunks` chunks of the source in the state as written to the
+    /// temporary file, sealed with the data key. The temporary file is removed when the run
+    /// finishes, unless the source is completed before.
+    pub(crate) fn checkpoint(
+        &self,
+        source: &Path,
+        state: FileState,
+        tmp: &Path,
+        chunks: usize,
+        key: &DataKey,
+    ) -> anyhow::Result<()> {
+        let source = source.canonicalize()?;
+        let tmp = tmp.strip_prefix(&self.target_dir)?.to_path_buf();
+        let mut body = Vec::new();
+        write_path(&mut body, &source);
+        state.write(&mut body);
+        write_path(&mut body, &tmp);
+        body.extend_from_slice(&(chunks as u64).to_be_bytes());
+        body.extend_from_slice(key.as_bytes());
+        self.append(PROGRESS, &body)?;
+        let progress = Progress {
+            state,
+            tmp,
+            chunks: chunks as u64,
+            key: key.clone(),
+        };
+        self.progress.lock().unwrap().insert(source, progress);
+        Ok(())
+    }
+
+    /// Takes the temporary file an interrupted run was encrypting the source into, when the
+    /// source is unchanged since. The temporary file of a changed one is removed.
+    pub(crate) fn resume(
+        &self,
+        source: &Path,
+        state: FileState,
+    ) -> anyhow::Result<Option<Resumed>> {
+        let progress = self
+            .progress
+            .lock()
+            .unwrap()
+            .remove(&source.canonicalize()?);
+        let progress = match progress {
+            Some(progress) => progress,
+            None => return Ok(None),
+        };
+        let tmp = self.target_dir.join(&progress.tmp);
+        if progress.state != state {
+            let _ = fs::remove_file(&tmp);
+            return Ok(None);
+        }
+        Ok(Some(Resumed {
+            tmp,
+            chunks: progress.chunks,
+            key: progress.key,
+        }))
+    }
+
+    /// Removes the journal once the run is finished, with temporary files of sources that
+    /// weren't resumed or completed.
+    pub(crate) fn finish(&self) -> anyhow::Result<()> {
+        for progress in self.progress.lock().unwrap().values() {
+            let _ = fs::remove_file(self.target_dir.join(&progress.tmp));
+        }
+        let dir = self.target_dir.join(JOURNAL_DIR);
+        fs::remove_dir_all(&dir).with_context(|| format!("Unable to remove journal {:?}", dir))
+    }
+
+    /// Layout: `len u32 | nonce | sealed kind u8 | sealed body`, with a random nonce. Written
+    /// at once so a killed run leaves at most its last record incomplete. Progress records are
+    /// synced, a resumed file is only as good as its last checkpoint.
+    fn append(&self, kind: u8, body: &[u8]) -> anyhow::Result<()> {
+        let mut nonce = [0u8; NONCE_SIZE];
+        thread_rng().fill_bytes(&mut nonce);
+        let sealed = self
+            .cipher
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                [&[kind][..], body].concat().as_ref(),
+            )
+            .map_err(|_| anyhow!("Unable to seal journal record"))?;
+        let mut record = Vec::with_capacity(4 + NONCE_SIZE + sealed.len());
+        record.extend_from_slice(&((NONCE_SIZE + sealed.len()) as u32).to_be_bytes());
+        record.extend_from_slice(&nonce);
+        record.extend_from_slice(&sealed);
+        let mut log = self.log.lock().unwrap();
+        log.write_all(&record)
+            .and_then(|_| match kind {
+                PROGRESS => log.sync_data(),
+                _ => Ok(()),
+            })
+            .with_context(|| format!("Unable to write journal of {:?}", self.target_dir))
+    }
+}
+
+/// Tells whether the file belongs to the journal of the target tree.
+pub(crate) fn is_journal_file(path: &Path) -> bool {
+    path.components()
+        .any(|component| component.as_os_str() == JOURNAL_DIR)
+}
+
+/// Last records of every source, sources completed later than in progress are completed.
+/// An incomplete last record is left out.
+#[allow(clippy::type_complexity)]
+fn from_bytes(
+    mut bytes: &[u8],
+    cipher: &Aes256GcmSiv,
+) -> anyhow::Result<(HashMap<PathBuf, Completed>, HashMap<PathBuf, Progress>)> {
+    let mut completed = HashMap::new();
+    let mut progress = HashMap::new();
+    while bytes.len() >= 4 {
+        let len = u32::from_be_bytes(bytes[..4].try_into()?) as usize;
+        if bytes.len() < 4 + len {
+            break;
+        }
+        if len < NONCE_SIZE {
+            bail!("Journal record too short");
+        }
+        let (nonce, sealed) = bytes[4..4 + len].split_at(NONCE_SIZE);
+        let record = cipher
+            .decrypt(Nonce::from_slice(nonce), sealed)
+            .map_err(|_| anyhow!("Unable to open journal record, wrong key"))?;
+        let (&kind, body) = record
+            .split_first()
+            .ok_or_else(|| anyhow!("Empty journal record"))?;
+        let r = &mut &body[..];
+        let source = read_path(r)?;
+        let state = FileState::read(r)?;
+        match kind {
+            COMPLETED => {
+                let hash = read_array(r)?;
+                let target = read_path(r)?;
+                progress.remove(&source);
+                completed.insert(
+                    source,
+                    Completed {
+                        state,
+                        hash,
+                        target,
+                    },
+                );
+            }
+            PROGRESS => {
+                let tmp = read_path(r)?;
+                let chunks = u64::from_be_bytes(read_array(r)?);
+                let key = DataKey::from_bytes(&read_array::<DATA_KEY_SIZE>(r)?)?;
+                completed.remove(&source);
+                progress.insert(
+                    source,
+                    Progress {
+                        state,
+                        tmp,
+                        chunks,
+                        key,
+                    },
+                );
+            }
+            kind => bail!("Unknown journal record {}", kind),
+        }
+        if !r.is_empty() {
+            bail!("Unexpected data in journal record");
+        }
+        bytes = &bytes[4 + len..];
+    }
+    Ok((completed, progress))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::file::{file_verify, transform_file, Encoding};
+    use crate::repository::Repositories;
+    use crate::worker::pass::holder::PassKey;
+    use rand::{thread_rng, RngCore};
+
+    #[test]
+    fn should_resume_interrupted_file() {
+        let tmp = tempfile::TempDir::new().expect("Unable to create temp dir");
+        let target_dir = tmp.path().join("target");
+        fs::create_dir_all(&target_dir).expect("Unable to create dir");
+        let target_dir = target_dir.canonicalize().expect("No target dir");
+        let source = tmp.path().join("source");
+        let mut original = vec![0u8; 5 * 65536 + 1000];
+        thread_rng().fill_bytes(&mut original);
+        fs::write(&source, &original).expect("Unable to write");
+        let key = PassKey::encryption("secret").expect("No key");
+        let open = || {
+            let mut journal = Journal::open(&target_dir, &key, None).expect("No journal");
+            journal.resumable_len = 0;
+            journal.checkpoint_chunks = 2;
+            journal
+        };
+        let encrypt = |journal: &Journal| {
+            let target = target_dir.join("source");
+            let repositories = Repositories::default();
+            transform_file(
+                &source,
+                &key,
+                &target,
+                Encoding::default(),
+                &repositories,
+                Some(journal),
+            )
+        };
+
+        // The encrypted file can't replace a directory, so the run stops before renaming.
+        let target = target_dir.join("source");
+        fs::create_dir_all(target.join("dir")).expect("Unable to create dir");
+        assert!(encrypt(&open()).is_err());
+        let tmp_file = fs::read_dir(&target_dir)
+            .expect("Unable to list")
+            .map(|entry| entry.expect("No entry").path())
+            .find(|path| path.extension().is_some_and(|extension| extension == "tmp"))
+            .expect("No temporary file");
+        let interrupted = fs::read(&tmp_file).expect("Unable to read");
+        // Killed while writing the last chunk, after the checkpoint of the first four.
+        File::options()
+            .write(true)
+            .open(&tmp_file)
+            .and_then(|file| file.set_len(interrupted.len() as u64 - 100))
+            .expect("Unable to truncate");
+
+        fs::remove_dir_all(&target).expect("Unable to remove");
+        let journal = open();
+        let (bytes, hash) = encrypt(&journal).expect("Unable to resume");
+        assert_eq!(original.len() as u64, bytes);
+        // Chunks are sealed with the data key of the interrupted run.
+        assert_eq!(interrupted, fs::read(&target).expect("Unable to read"));
+        assert!(!tmp_file.exists());
+        let mut decrypted = Vec::new();
+        file_verify(
+            &target,
+            &PassKey::decryption("secret"),
+            &Repositories::default(),
+            &mut decrypted,
+        )
+        .expect("Unable to decrypt");
+        assert_eq!(original, decrypted);
+
+        let state = FileState::of(&fs::metadata(&source).expect("No source"));
+        let hash = hash.expect("No hash");
+        journal
+            .complete(&source, state, &target, hash)
+            .expect("Unable to complete");
+        drop(journal);
+        let journal = open();
+        assert_eq!(
+            Some(Lookup::Unchanged(state, hash)),
+            journal
+                .completed(&source, &target)
+                .expect("Unable to look up")
+        );
+        journal.finish().expect("Unable to finish");
+        assert!(!target_dir.join(JOURNAL_DIR).exists());
+    }
+
+    #[test]
+    fn should_remove_temporary_files_of_failed_files() {
+        let tmp = tempfile::TempDir::new().expect("Unable to create temp dir");
+        let target_dir = tmp.path().join("target");
+        let target = target_dir.join("source");
+        fs::create_dir_all(target.join("dir")).expect("Unable to create dir");
+        let target_dir = target_dir.canonicalize().expect("No target dir");
+        let source = tmp.path().join("source");
+        let mut original = vec![0u8; 3 * 65536];
+        thread_rng().fill_bytes(&mut original);
+        fs::write(&source, &original).expect("Unable to write");
+        let key = PassKey::encryption("secret").expect("No key");
+        let mut journal = Journal::open(&target_dir, &key, None).expect("No journal");
+        journal.resumable_len = 0;
+        journal.checkpoint_chunks = 1;
+
+        // Checkpointed, then failed to replace the directory in the way.
+        assert!(transform_file(
+            &source,
+            &key,
+            &target,
+            Encoding::default(),
+            &Repositories::default(),
+            Some(&journal),
+        )
+        .is_err());
+        let is_tmp = |path: &Path| path.extension().is_some_and(|extension| extension == "tmp");
+        let tmp_files = || {
+            fs::read_dir(&target_dir)
+                .expect("Unable to list")
+                .filter(|entry| is_tmp(&entry.as_ref().expect("No entry").path()))
+                .count()
+        };
+        assert_eq!(1, tmp_files());
+        journal.finish().expect("Unable to finish");
+        assert_eq!(0, tmp_files());
+    }
+
+    #[test]
+    fn should_seal_records() {
+        let tmp = tempfile::TempDir::new().expect("Unable to create temp dir");
+        let target_dir = tmp.path().join("target");
+        let target = target_dir.join("secret-plans.txt");
+        fs::create_dir_all(target.join("dir")).expect("Unable to create dir");
+        let target_dir = target_dir.canonicalize().expect("No target dir");
+        let source = tmp.path().join("secret-plans.txt");
+        let mut original = vec![0u8; 3 * 65536];
+        thread_rng().fill_bytes(&mut original);
+        fs::write(&source, &original).expect("Unable to write");
+        let key = PassKey::encryption("secret").expect("No key");
+        let mut journal = Journal::open(&target_dir, &key, None).expect("No journal");
+        journal.resumable_len = 0;
+        journal.checkpoint_chunks = 1;
+
+        // Interrupted after checkpoints, then another file completed.
+        assert!(transform_file(
+            &source,
+            &key,
+            &target,
+            Encoding::default(),
+            &Repositories::default(),
+            Some(&journal),
+        )
+        .is_err());
+        let hash = *blake3::hash(&original).as_bytes();
+        let state = FileState::of(&fs::metadata(&source).expect("No source"));
+        let completed = target_dir.join("completed");
+        fs::write(&completed, b"encrypted").expect("Unable to write");
+        journal
+            .complete(&source, state, &completed, hash)
+            .expect("Unable to complete");
+        drop(journal);
+
+        let log = fs::read(target_dir.join(JOURNAL_DIR).join(LOG_FILE)).expect("Unable to read");
+        let contains = |bytes: &[u8]| log.windows(bytes.len()).any(|window| window == bytes);
+        assert!(!contains(b"secret-plans"));
+        assert!(!contains(b"completed"));
+        assert!(!contains(&hash));
+        let journal = Journal::open(&target_dir, &key, None).expect("No journal");
+        assert_eq!(
+            Some(Lookup::Unchanged(state, hash)),
+            journal
+                .completed(&source, &completed)
+                .expect("Unable to look up")
+        );
+    }
+}
@@ -0,0 +1,117 @@
+use crate::cipher::{CipherSuite, DataKey};
+use crate::compression::Compression;
+use crate::file::header::Header;
+use crate::padding::Padding;
+use crate::worker::KeyHolder;
+use anyhow::{bail, Context};
+use data_encoding::BASE32_NOPAD;
+use sha2::{Digest, Sha256};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::{fs, io};
+
+const KEY_CHECK_SIZE: usize = 16;
+
+/// Chunk size recorded in headers of key files, they have no chunks.
+const KEY_FILE_CHUNK_SIZE: u32 = 255;
+
+/// Reads a key kept by the encryptor, a new one is created when the file doesn't exist.
+pub fn read_or_create_key(path: &Path) -> anyhow::Result<DataKey> {
+    let context = || format!("Unable to read key from file {:?}", path);
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    match options.open(path) {
+        Ok(mut file) => {
+            let key = DataKey::generate();
+            writeln!(file, "{}", BASE32_NOPAD.encode(key.as_bytes())).with_context(context)?;
+            Ok(key)
+        }
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            let encoded = fs::read_to_string(path).with_context(context)?;
+            let bytes = BASE32_NOPAD
+                .decode(encoded.trim().as_bytes())
+                .with_context(context)?;
+            Ok(DataKey::from_bytes(&bytes).with_context(context)?)
+        }
+        Err(e) => Err(e).with_context(context),
+    }
+}
+
+/// Key of a target tree wrapped by the holder and kept in the file. The key is unwrapped
+/// from the file when not given, a new file stores the given or a new key.
+pub(crate) fn open_or_create_wrapped_key(
+    path: &Path,
+    purpose: &str,
+    holder: &dyn KeyHolder,
+    key: Option<DataKey>,
+) -> anyhow::Result<DataKey> {
+    if path.exists() {
+        let (header, check) = read_wrapped_key(path)?;
+        let key = match key {
+            Some(key) => key,
+            None => holder.unwrap_key(&header.key)?,
+        };
+        if key_check(purpose, &key) != check {
+            bail!("Key in {:?} doesn't match the given one", path);
+        }
+        Ok(key)
+    } else {
+        let key = key.unwrap_or_else(DataKey::generate);
+        write_wrapped_key(path, purpose, holder, &key)?;
+        Ok(key)
+    }
+}
+
+/// Unwraps the key kept in the file.
+pub(crate) fn unwrap_key_file(path: &Path, holder: &dyn KeyHolder) -> anyhow::Result<DataKey> {
+    let (header, _) = read_wrapped_key(path)?;
+    holder
+        .unwrap_key(&header.key)
+        .with_context(|| format!("Unable to unwrap key from file {:?}", path))
+}
+
+/// Layout: `header | key check[16]`, the header only carries the wrapped key.
+fn write_wrapped_key(
+    path: &Path,
+    purpose: &str,
+    holder: &dyn KeyHolder,
+    key: &DataKey,
+) -> anyhow::Result<()> {
+    let header = Header::new(
+        CipherSuite::default(),
+        KEY_FILE_CHUNK_SIZE,
+        Padding::None,
+        Compression::None,
+        holder.wrap_key(key)?,
+    );
+    let mut file =
+        File::create(path).with_context(|| format!("Unable to write key file {:?}", path))?;
+    header.write(&mut file)?;
+    file.write_all(&key_check(purpose, key))?;
+    Ok(())
+}
+
+fn read_wrapped_key(path: &Path) -> anyhow::Result<(Header, [u8; KEY_CHECK_SIZE])> {
+    let context = || format!("Unable to read key file {:?}", path);
+    let mut file = File::open(path).with_context(context)?;
+    let header = Header::read(&mut file).with_context(context)?;
+    let mut check = [0u8; KEY_CHECK_SIZE];
+    file.read_exact(&mut check).with_context(context)?;
+    Ok((header, check))
+}
+
+/// Tells whether the key matches the tree without revealing it.
+fn key_check(purpose: &str, key: &DataKey) -> [u8; KEY_CHECK_SIZE] {
+    let mut check = [0u8; KEY_CHECK_SIZE];
+    let digest = Sha256::new()
+        .chain_update(purpose)
+        .chain_update(key.as_bytes())
+        .finalize();
+    check.copy_from_slice(&digest[..KEY_CHECK_SIZE]);
+    check
+}
@@ -1,4 +1,19 @@
+#[cfg(feature = "async")]
+pub mod async_io;
+pub mod cipher;
+pub mod compression;
 pub mod file;
+pub mod index;
+pub mod journal;
+pub mod key_file;
+pub mod mirror;
+pub mod names;
+pub mod padding;
 pub mod path;
+pub mod repository;
+pub mod retention;
+pub mod snapshot;
 pub mod stats;
+pub mod verify;
+pub mod version;
 pub mod worker;
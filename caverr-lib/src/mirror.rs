@@ -0,0 +1,115 @@
+use crate::file::metadata::Timestamp;
+use crate::journal::is_journal_file;
+use crate::names::Names;
+use crate::repository::is_repository_file;
+use crate::snapshot::is_snapshot_file;
+use crate::version::{parse_version, version_path};
+use anyhow::{bail, Context};
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+/// Encrypted files deleted in a run at most, unless told otherwise.
+pub const DEFAULT_MAX_DELETIONS: usize = 100;
+
+/// Deletes encrypted files whose source files are gone, so the target mirrors the sources.
+pub(crate) struct Mirror {
+    roots: Vec<PathBuf>,
+    max_deletions: usize,
+}
+
+impl Mirror {
+    pub(crate) fn new(roots: Vec<PathBuf>, max_deletions: usize) -> Self {
+        Self {
+            roots,
+            max_deletions,
+        }
+    }
+
+    /// Removes encrypted files in the targets of the roots whose source files don't exist any
+    /// more, they are kept as versions when `versions` is set. Nothing is removed when there
+    /// are more of them than allowed.
+    pub(crate) fn delete_stale(
+        &self,
+        target_dir: &Path,
+        names: &Names,
+        versions: bool,
+    ) -> anyhow::Result<Vec<PathBuf>> {
+        let mut stale = Vec::new();
+        for root in &self.roots {
+            let target_root = names.encrypted_path(root, target_dir)?;
+            if target_root.is_dir() {
+                find_stale(&target_root, target_dir, names, &mut stale)?;
+            }
+        }
+        if stale.len() > self.max_deletions {
+            bail!(
+                "Source files of {} encrypted files are gone, more than {} allowed to delete",
+                stale.len(),
+                self.max_deletions
+            );
+        }
+        stale.sort();
+        for path in &stale {
+            let removed = if versions {
+                quarantine(path)
+            } else {
+                fs::remove_file(path)
+            };
+            removed.with_context(|| format!("Unable to delete file {:?}", path))?;
+        }
+        Ok(stale)
+    }
+}
+
+fn find_stale(
+    dir: &Path,
+    target_dir: &Path,
+    names: &Names,
+    stale: &mut Vec<PathBuf>,
+) -> anyhow::Result<()> {
+    for entry in fs::read_dir(dir).with_context(|| format!("Unable to read dir {:?}", dir))? {
+        let entry = entry?;
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            find_stale(&path, target_dir, names, stale)?;
+        } else if file_type.is_file() && !is_kept(&path, names) {
+            // Files that can't be traced back to a source are not ours to delete.
+            if let Ok(source) = names.source_path(&path, target_dir) {
+                match fs::symlink_metadata(&source) {
+                    Ok(_) => {}
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => stale.push(path),
+                    // Unreadable isn't gone, the file must not be deleted by mistake.
+                    Err(e) => {
+                        return Err(e).with_context(|| format!("Unable to check {:?}", source))
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Files in the target tree that have no source file of their own.
+fn is_kept(path: &Path, names: &Names) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let is_temporary = name
+        .strip_suffix(".tmp")
+        .is_some_and(|stem| stem.parse::<u64>().is_ok());
+    is_temporary
+        || names.is_names_file(path)
+        || is_repository_file(path)
+        || is_snapshot_file(path)
+        || is_journal_file(path)
+        || parse_version(path).is_some()
+}
+
+/// Renames the encrypted file to its version, so it can be restored until pruned.
+fn quarantine(path: &Path) -> io::Result<()> {
+    let version = version_path(path, Timestamp::modified(&fs::metadata(path)?));
+    if version.exists() {
+        fs::remove_file(path)
+    } else {
+        fs::rename(path, version)
+    }
+}
@@ -0,0 +1,283 @@
+use crate::cipher::DataKey;
+use crate::key_file::{open_or_create_wrapped_key, unwrap_key_file};
+use crate::path::{
+    build_relative_path, os_str_bytes, os_string_from_bytes, without_root, RelativePathError,
+};
+use crate::worker::KeyHolder;
+use aes_gcm_siv::aead::{Aead, KeyInit, Payload};
+use aes_gcm_siv::{Aes256GcmSiv, Nonce};
+use anyhow::{anyhow, Context};
+use data_encoding::BASE32_NOPAD;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Marks the root of a tree with encrypted names and holds the wrapped names key.
+pub const NAMES_FILE: &str = ".caverr-names";
+
+/// Longest file name most filesystems accept.
+const MAX_NAME_LEN: usize = 255;
+
+const LONG_NAME_SUFFIX: &str = ".long";
+const LONG_NAME_FILE_SUFFIX: &str = ".name";
+
+/// Deterministic encryption of single path components, equal names in the same directory
+/// give equal results so the encryptor finds files it wrote before. Names are bound to the
+/// encrypted path of their directory in the tree, equal names elsewhere differ.
+pub struct NameCipher {
+    cipher: Aes256GcmSiv,
+}
+
+impl NameCipher {
+    pub fn new(key: &DataKey) -> Self {
+        Self {
+            cipher: Aes256GcmSiv::new(key.as_bytes().into()),
+        }
+    }
+
+    /// Encrypts the name of an entry of the `parent` directory, given by its encrypted path
+    /// relative to the root of the tree, into upper case base32, which is safe on any
+    /// filesystem.
+    pub fn encrypt_name(&self, parent: &Path, name: &OsStr) -> anyhow::Result<String> {
+        let payload = Payload {
+            msg: os_str_bytes(name),
+            aad: os_str_bytes(parent.as_os_str()),
+        };
+        let encrypted = self
+            .cipher
+            .encrypt(&Nonce::default(), payload)
+            .map_err(|_| anyhow!("Unable to encrypt name {:?}", name))?;
+        Ok(BASE32_NOPAD.encode(&encrypted))
+    }
+
+    /// Decrypts the name of an entry of the `parent` directory, as given when encrypted.
+    pub fn decrypt_name(&self, parent: &Path, encoded: &str) -> anyhow::Result<OsString> {
+        let encrypted = BASE32_NOPAD
+            .decode(encoded.as_bytes())
+            .with_context(|| format!("Invalid encrypted name {}", encoded))?;
+        let payload = Payload {
+            msg: encrypted.as_ref(),
+            aad: os_str_bytes(parent.as_os_str()),
+        };
+        let name = self
+            .cipher
+            .decrypt(&Nonce::default(), payload)
+            .map_err(|_| anyhow!("Unable to decrypt name {}, wrong key or modified", encoded))?;
+        Ok(os_string_from_bytes(name))
+    }
+
+    /// Encrypted name of an entry in the directory of the tree rooted at `root`. Names too
+    /// long for the filesystem are replaced with their hash, the full name is kept in a
+    /// `<hash>.name` file next to them.
+    fn encrypt_component(&self, root: &Path, dir: &Path, name: &OsStr) -> anyhow::Result<OsString> {
+        let encoded = self.encrypt_name(dir.strip_prefix(root)?, name)?;
+        if encoded.len() <= MAX_NAME_LEN {
+            return Ok(encoded.into());
+        }
+        let hash = BASE32_NOPAD.encode(&Sha256::digest(encoded.as_bytes()));
+        let long_name_file = dir.join(format!("{}{}", hash, LONG_NAME_FILE_SUFFIX));
+        if !long_name_file.exists() {
+            fs::write(&long_name_file, &encoded)
+                .with_context(|| format!("Unable to write long name {:?}", long_name_file))?;
+        }
+        Ok(format!("{}{}", hash, LONG_NAME_SUFFIX).into())
+    }
+
+    fn decrypt_component(&self, root: &Path, dir: &Path, name: &OsStr) -> anyhow::Result<OsString> {
+        let parent = dir.strip_prefix(root)?;
+        let name = name
+            .to_str()
+            .ok_or_else(|| anyhow!("Invalid encrypted name {:?}", name))?;
+        match name.strip_suffix(LONG_NAME_SUFFIX) {
+            Some(hash) => {
+                let long_name_file = dir.join(format!("{}{}", hash, LONG_NAME_FILE_SUFFIX));
+                let encoded = fs::read_to_string(&long_name_file)
+                    .with_context(|| format!("Unable to read long name {:?}", long_name_file))?;
+                self.decrypt_name(parent, encoded.trim())
+            }
+            None => self.decrypt_name(parent, name),
+        }
+    }
+}
+
+/// Names of files in the target tree.
+#[derive(Default)]
+pub(crate) struct Names {
+    /// Cipher of names written when encrypting, plain names are kept without it.
+    encryptor: Option<NameCipher>,
+    /// Ciphers of trees with encrypted names found when decrypting, by their root.
+    decryptors: Mutex<HashMap<PathBuf, Arc<NameCipher>>>,
+}
+
+impl Names {
+    /// Encrypts names with the key of the target tree. The key is unwrapped from the tree
+    /// when not given, a new tree stores the given or a new key wrapped by the holder.
+    pub(crate) fn encrypted(
+        target_dir: &Path,
+        holder: &dyn KeyHolder,
+        key: Option<DataKey>,
+    ) -> anyhow::Result<Self> {
+        let names_file = target_dir.join(NAMES_FILE);
+        let key = open_or_create_wrapped_key(&names_file, NAMES_FILE, holder, key)
+            .with_context(|| format!("Unable to use names key of {:?}", target_dir))?;
+        Ok(Self {
+            encryptor: Some(NameCipher::new(&key)),
+            ..Self::default()
+        })
+    }
+
+    /// Target of the encrypted source file, every component of its path is encrypted.
+    pub(crate) fn encrypted_path(
+        &self,
+        source: &Path,
+        target_dir: &Path,
+    ) -> anyhow::Result<PathBuf> {
+        let cipher = match &self.encryptor {
+            Some(cipher) => cipher,
+            None => return Ok(build_relative_path(source, target_dir)?),
+        };
+        let source = source.canonicalize().map_err(RelativePathError::IOError)?;
+        let mut target = target_dir.to_path_buf();
+        for component in source.components() {
+            if let Component::Normal(name) = component {
+                fs::create_dir_all(&target).map_err(RelativePathError::IOError)?;
+                target.push(cipher.encrypt_component(target_dir, &target, name)?);
+            }
+        }
+        Ok(target)
+    }
+
+    /// Source of the encrypted file in the target tree, as mapped by `encrypted_path`.
+    pub(crate) fn source_path(&self, target: &Path, target_dir: &Path) -> anyhow::Result<PathBuf> {
+        let relative = target.strip_prefix(target_dir)?;
+        let mut source = PathBuf::from("/");
+        let cipher = match &self.encryptor {
+            Some(cipher) => cipher,
+            None => return Ok(source.join(relative)),
+        };
+        let mut dir = target_dir.to_path_buf();
+        for component in relative.components() {
+            source.push(cipher.decrypt_component(target_dir, &dir, component.as_os_str())?);
+            dir.push(component);
+        }
+        Ok(source)
+    }
+
+    /// Tells whether the file in the target tree keeps a key or a long name, rather than
+    /// content of an encrypted file.
+    pub(crate) fn is_names_file(&self, path: &Path) -> bool {
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        self.encryptor.is_some()
+            && (file_name == NAMES_FILE || file_name.ends_with(LONG_NAME_FILE_SUFFIX))
+    }
+
+    /// Target of the decrypted file, `None` for files keeping encrypted names.
+    pub(crate) fn decrypted_path(
+        &self,
+        source: &Path,
+        target_dir: &Path,
+        holder: &dyn KeyHolder,
+    ) -> anyhow::Result<Option<PathBuf>> {
+        let plain = match self.plain_path(source, holder)? {
+            Some(plain) => plain,
+            None => return Ok(None),
+        };
+        let target = target_dir.join(without_root(&plain));
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).map_err(RelativePathError::IOError)?;
+        }
+        Ok(Some(target))
+    }
+
+    /// Canonical path of the encrypted file with its names decrypted, `None` for files
+    /// keeping encrypted names.
+    pub(crate) fn plain_path(
+        &self,
+        source: &Path,
+        holder: &dyn KeyHolder,
+    ) -> anyhow::Result<Option<PathBuf>> {
+        let source = source.canonicalize().map_err(RelativePathError::IOError)?;
+        let root = match source
+            .ancestors()
+            .skip(1)
+            .find(|dir| dir.join(NAMES_FILE).is_file())
+        {
+            Some(root) => root,
+            None => return Ok(Some(source)),
+        };
+        let file_name = source.file_name().unwrap_or_default().to_string_lossy();
+        if file_name == NAMES_FILE || file_name.ends_with(LONG_NAME_FILE_SUFFIX) {
+            return Ok(None);
+        }
+        let cipher = self.decryptor(root, holder)?;
+        let mut plain = root.to_path_buf();
+        let mut dir = root.to_path_buf();
+        for component in source.strip_prefix(root)?.components() {
+            plain.push(cipher.decrypt_component(root, &dir, component.as_os_str())?);
+            dir.push(component);
+        }
+        Ok(Some(plain))
+    }
+
+    fn decryptor(&self, root: &Path, holder: &dyn KeyHolder) -> anyhow::Result<Arc<NameCipher>> {
+        let mut decryptors = self.decryptors.lock().unwrap();
+        if let Some(cipher) = decryptors.get(root) {
+            return Ok(cipher.clone());
+        }
+        let key = unwrap_key_file(&root.join(NAMES_FILE), holder)
+            .with_context(|| format!("Unable to unwrap names key of {:?}", root))?;
+        let cipher = Arc::new(NameCipher::new(&key));
+        decryptors.insert(root.to_path_buf(), cipher.clone());
+        Ok(cipher)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn should_encrypt_names() {
+        let tmp = tempfile::TempDir::new().expect("Unable to create temp dir");
+        let cipher = NameCipher::new(&DataKey::generate());
+        let short = OsStr::new("notes about Zażółć.txt");
+        let long = OsString::from("x".repeat(200));
+        let root = tmp.path();
+        for name in [short, long.as_os_str()] {
+            let encrypted = cipher
+                .encrypt_component(root, root, name)
+                .expect("Unable to encrypt name");
+            assert_eq!(
+                encrypted,
+                cipher.encrypt_component(root, root, name).expect("No name")
+            );
+            assert!(encrypted.len() <= MAX_NAME_LEN);
+            assert!(!encrypted.to_string_lossy().contains('x'));
+            let decrypted = cipher
+                .decrypt_component(root, root, &encrypted)
+                .expect("Unable to decrypt name");
+            assert_eq!(name, decrypted);
+        }
+        let other = NameCipher::new(&DataKey::generate());
+        let encrypted = cipher
+            .encrypt_name(Path::new(""), short)
+            .expect("Unable to encrypt name");
+        assert!(other.decrypt_name(Path::new(""), &encrypted).is_err());
+
+        // Equal names in other directories don't tell they are equal.
+        let nested = cipher
+            .encrypt_name(Path::new("ABC"), short)
+            .expect("Unable to encrypt name");
+        assert_ne!(encrypted, nested);
+        assert!(cipher.decrypt_name(Path::new("ABC"), &encrypted).is_err());
+        assert_eq!(
+            short,
+            cipher
+                .decrypt_name(Path::new("ABC"), &nested)
+                .expect("Unable to decrypt name")
+        );
+    }
+}
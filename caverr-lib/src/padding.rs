@@ -0,0 +1,106 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Hides the exact size of files by padding their content before encryption.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Padding {
+    #[default]
+    None,
+    /// PADMÉ, overhead of at most 12% and usually much less.
+    Padme,
+    /// Next power of two, hides more but doubles the size in the worst case.
+    PowerOfTwo,
+}
+
+#[derive(Debug, Error)]
+pub enum PaddingError {
+    #[error("unknown padding id {0}")]
+    UnknownPadding(u8),
+}
+
+impl Padding {
+    pub fn id(&self) -> u8 {
+        match self {
+            Padding::None => 0,
+            Padding::Padme => 1,
+            Padding::PowerOfTwo => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Result<Self, PaddingError> {
+        match id {
+            0 => Ok(Padding::None),
+            1 => Ok(Padding::Padme),
+            2 => Ok(Padding::PowerOfTwo),
+            other => Err(PaddingError::UnknownPadding(other)),
+        }
+    }
+
+    /// Size of content of the given length after padding.
+    pub fn padded_len(&self, len: u64) -> u64 {
+        match self {
+            Padding::None => len,
+            Padding::Padme => padme(len),
+            Padding::PowerOfTwo => len.checked_next_power_of_two().unwrap_or(len),
+        }
+    }
+}
+
+/// Rounds the length so only its top `log2(log2(len))` bits are kept, see
+/// "Reducing Metadata Leakage from Encrypted Files and Communication with PURBs".
+fn padme(len: u64) -> u64 {
+    if len < 2 {
+        return len;
+    }
+    let exponent = 63 - len.leading_zeros();
+    let significant = 32 - exponent.leading_zeros();
+    let mask = (1u64 << (exponent - significant)) - 1;
+    len.checked_add(mask).map_or(len, |len| len & !mask)
+}
+
+impl FromStr for Padding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Padding::None),
+            "padme" => Ok(Padding::Padme),
+            "pow2" | "power-of-two" => Ok(Padding::PowerOfTwo),
+            other => Err(format!(
+                "Invalid padding `{}`. Must be either: `none`, `padme` or `power-of-two`",
+                other
+            )),
+        }
+    }
+}
+
+impl Display for Padding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Padding::None => write!(f, "none"),
+            Padding::Padme => write!(f, "padme"),
+            Padding::PowerOfTwo => write!(f, "power-of-two"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn should_pad_lengths() {
+        for (len, padded) in [(0, 0), (1, 1), (2, 2), (9, 10), (100, 104), (1000, 1024)] {
+            assert_eq!(Padding::Padme.padded_len(len), padded);
+        }
+        assert_eq!(Padding::Padme.padded_len(1_000_000), 1_015_808);
+        assert_eq!(Padding::PowerOfTwo.padded_len(1000), 1024);
+        assert_eq!(Padding::PowerOfTwo.padded_len(1024), 1024);
+        assert_eq!(Padding::None.padded_len(1000), 1000);
+        for len in (0..100_000).step_by(7) {
+            let padded = Padding::Padme.padded_len(len);
+            assert!(padded >= len && padded - len <= len / 8);
+        }
+    }
+}
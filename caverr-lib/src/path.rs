@@ -1,4 +1,5 @@
-use std::path::{Path, PathBuf};
+use std::ffi::{OsStr, OsString};
+use std::path::{Component, Path, PathBuf};
 use std::{fs, io};
 use thiserror::Error;
 
@@ -15,19 +16,40 @@ pub fn build_relative_path(source: &Path, target_dir: &Path) -> Result<PathBuf,
         RelativePathError::InvalidSourcePath(format!("missing file name in path: {:?}", source))
     })?;
     let root = source.canonicalize().map_err(RelativePathError::IOError)?;
-    let mut components = root
-        .parent()
-        .ok_or_else(|| {
-            RelativePathError::InvalidSourcePath(format!("no parent in path {:?}", root))
-        })?
-        .components();
-    components.next().ok_or_else(|| {
-        RelativePathError::InvalidSourcePath(format!(
-            "unable to remove root component from path {:?}",
-            components.as_path()
-        ))
+    let parent = root.parent().ok_or_else(|| {
+        RelativePathError::InvalidSourcePath(format!("no parent in path {:?}", root))
     })?;
-    let target_dir = target_dir.join(components.as_path());
+    let target_dir = target_dir.join(without_root(parent));
     fs::create_dir_all(&target_dir).map_err(RelativePathError::IOError)?;
     Ok(target_dir.join(file_name))
 }
+
+/// The absolute path relative to its root, without the prefix and root directory it starts
+/// with, so it can be joined to a target directory.
+pub(crate) fn without_root(path: &Path) -> PathBuf {
+    path.components()
+        .skip_while(|component| matches!(component, Component::Prefix(_) | Component::RootDir))
+        .collect()
+}
+
+/// Raw bytes of an OS string, as written to encrypted files.
+pub(crate) fn os_str_bytes(s: &OsStr) -> &[u8] {
+    s.as_encoded_bytes()
+}
+
+/// OS string read back from encrypted files.
+#[cfg(unix)]
+pub(crate) fn os_string_from_bytes(bytes: Vec<u8>) -> OsString {
+    use std::os::unix::ffi::OsStringExt;
+    OsString::from_vec(bytes)
+}
+
+/// OS string read back from encrypted files. Bytes written on Unix don't have to be
+/// valid elsewhere, so invalid sequences are replaced.
+#[cfg(not(unix))]
+pub(crate) fn os_string_from_bytes(bytes: Vec<u8>) -> OsString {
+    match String::from_utf8(bytes) {
+        Ok(s) => s.into(),
+        Err(e) => String::from_utf8_lossy(e.as_bytes()).into_owned().into(),
+    }
+}
@@ -0,0 +1,71 @@
+use crate::cipher::CipherSuite;
+use crate::repository::{ChunkId, Keys, CHUNK_ID_SIZE};
+use anyhow::{bail, Context};
+use std::fs;
+use std::path::Path;
+
+const ENTRY_SIZE: usize = CHUNK_ID_SIZE + 8 + 4;
+
+/// Position of a sealed chunk in its pack.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(super) struct Entry {
+    pub(super) id: ChunkId,
+    pub(super) offset: u64,
+    pub(super) len: u32,
+}
+
+/// Writes the index of the pack, named as the pack itself.
+///
+/// Layout: sealed `cipher u8 | entry...`, every entry is `chunk id[32] | offset u64 | len u32`
+/// and the cipher is the one chunks of the pack were sealed with.
+pub(super) fn write(
+    dir: &Path,
+    pack: &str,
+    cipher: CipherSuite,
+    entries: &[Entry],
+    keys: &Keys,
+) -> anyhow::Result<()> {
+    let mut bytes = Vec::with_capacity(1 + entries.len() * ENTRY_SIZE);
+    bytes.push(cipher.id());
+    for entry in entries {
+        bytes.extend_from_slice(&entry.id);
+        bytes.extend_from_slice(&entry.offset.to_be_bytes());
+        bytes.extend_from_slice(&entry.len.to_be_bytes());
+    }
+    let sealed = keys.index_cipher(pack)?.encrypt(0, true, &bytes)?;
+    let path = dir.join(pack);
+    let tmp_path = dir.join(format!("{}.tmp", pack));
+    fs::write(&tmp_path, sealed).with_context(|| format!("Unable to write index {:?}", path))?;
+    fs::rename(&tmp_path, &path).with_context(|| format!("Unable to write index {:?}", path))?;
+    Ok(())
+}
+
+pub(super) fn read(
+    dir: &Path,
+    pack: &str,
+    keys: &Keys,
+) -> anyhow::Result<(CipherSuite, Vec<Entry>)> {
+    let path = dir.join(pack);
+    let sealed = fs::read(&path).with_context(|| format!("Unable to read index {:?}", path))?;
+    let bytes = keys
+        .index_cipher(pack)?
+        .decrypt(0, true, &sealed)
+        .with_context(|| format!("Index {:?} is corrupted or was modified", path))?;
+    let (cipher, entries) = match bytes.split_first() {
+        Some((cipher, entries)) if entries.len() % ENTRY_SIZE == 0 => (cipher, entries),
+        _ => bail!("Invalid index {:?}", path),
+    };
+    let entries = entries
+        .chunks_exact(ENTRY_SIZE)
+        .map(|entry| {
+            let (id, entry) = entry.split_at(CHUNK_ID_SIZE);
+            let (offset, len) = entry.split_at(8);
+            Ok(Entry {
+                id: id.try_into()?,
+                offset: u64::from_be_bytes(offset.try_into()?),
+                len: u32::from_be_bytes(len.try_into()?),
+            })
+        })
+        .collect::<anyhow::Result<_>>()?;
+    Ok((CipherSuite::from_id(*cipher)?, entries))
+}
@@ -0,0 +1,465 @@
+mod index;
+mod pack;
+
+use crate::cipher::{ChunkCipher, CipherSuite, DataKey};
+use crate::compression::{Compression, ZSTD_LEVEL};
+use crate::key_file::{open_or_create_wrapped_key, unwrap_key_file};
+use crate::repository::index::Entry;
+use crate::repository::pack::{PackReader, PackWriter};
+use crate::worker::KeyHolder;
+use anyhow::{anyhow, bail, Context};
+use data_encoding::HEXLOWER;
+use fastcdc::v2020::StreamCDC;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Directory in the root of a target tree holding chunks of its files.
+pub const REPOSITORY_DIR: &str = ".caverr-repo";
+
+/// Holds the repository key wrapped by the holder.
+const KEY_FILE: &str = "key";
+const PACKS_DIR: &str = "packs";
+const INDEX_DIR: &str = "index";
+
+/// Bounds of content defined chunks, a change in a file only affects chunks around it.
+const MIN_CHUNK_SIZE: u32 = 256 * 1024;
+const AVG_CHUNK_SIZE: u32 = 1024 * 1024;
+const MAX_CHUNK_SIZE: u32 = 4 * 1024 * 1024;
+
+/// Packs are finished once this large, a new one is started for further chunks.
+const PACK_SIZE: u64 = 64 * 1024 * 1024;
+
+pub const CHUNK_ID_SIZE: usize = 32;
+
+/// Keyed hash of chunk content, equal chunks get equal ids only within a repository.
+pub type ChunkId = [u8; CHUNK_ID_SIZE];
+
+/// Where a chunk is stored.
+#[derive(Clone, Debug)]
+struct Location {
+    pack: Arc<str>,
+    cipher: CipherSuite,
+    offset: u64,
+    len: u32,
+}
+
+/// Keys derived from the repository key.
+pub(super) struct Keys {
+    ids: [u8; 32],
+    chunks: [u8; 32],
+    index: [u8; 32],
+}
+
+impl Keys {
+    fn derive(key: &DataKey) -> Self {
+        Self {
+            ids: blake3::derive_key("caverr repository chunk ids", key.as_bytes()),
+            chunks: blake3::derive_key("caverr repository chunks", key.as_bytes()),
+            index: blake3::derive_key("caverr repository index", key.as_bytes()),
+        }
+    }
+
+    fn chunk_id(&self, data: &[u8]) -> ChunkId {
+        *blake3::keyed_hash(&self.ids, data).as_bytes()
+    }
+
+    /// Every chunk gets its own key, so a fixed nonce is never reused for different content.
+    fn chunk_cipher(&self, suite: CipherSuite, id: &ChunkId) -> anyhow::Result<ChunkCipher> {
+        let key = DataKey::from_bytes(blake3::keyed_hash(&self.chunks, id).as_bytes())?;
+        Ok(ChunkCipher::new(suite, &key, id.to_vec()))
+    }
+
+    pub(super) fn index_cipher(&self, pack: &str) -> anyhow::Result<ChunkCipher> {
+        let key = DataKey::from_bytes(blake3::keyed_hash(&self.index, pack.as_bytes()).as_bytes())?;
+        Ok(ChunkCipher::new(
+            CipherSuite::default(),
+            &key,
+            pack.as_bytes().to_vec(),
+        ))
+    }
+}
+
+/// Chunks of files in a target tree, each of them stored once. New chunks of all files of
+/// a run are appended to a shared pack, it's finished once large enough or when the run is
+/// finished, then the index of the pack is written next to it.
+///
+/// Layout: `.caverr-repo/key`, `.caverr-repo/packs/<pack>` and `.caverr-repo/index/<pack>`.
+pub(crate) struct Repository {
+    dir: PathBuf,
+    keys: Keys,
+    index: Mutex<HashMap<ChunkId, Location>>,
+    /// Pack being written, with the cipher its chunks are sealed with.
+    pack: Mutex<Option<(PackWriter, CipherSuite)>>,
+}
+
+impl Repository {
+    /// Opens the repository of the target tree, a new one is created when missing. The key
+    /// is unwrapped from the repository when not given.
+    fn create(
+        target_dir: &Path,
+        holder: &dyn KeyHolder,
+        key: Option<DataKey>,
+    ) -> anyhow::Result<Self> {
+        let dir = target_dir.join(REPOSITORY_DIR);
+        for sub_dir in [PACKS_DIR, INDEX_DIR] {
+            fs::create_dir_all(dir.join(sub_dir))
+                .with_context(|| format!("Unable to create repository in {:?}", target_dir))?;
+        }
+        let key = open_or_create_wrapped_key(&dir.join(KEY_FILE), REPOSITORY_DIR, holder, key)
+            .with_context(|| format!("Unable to use repository key of {:?}", target_dir))?;
+        Self::with_key(dir, &key)
+    }
+
+    fn open(root: &Path, holder: &dyn KeyHolder) -> anyhow::Result<Self> {
+        let dir = root.join(REPOSITORY_DIR);
+        let key = unwrap_key_file(&dir.join(KEY_FILE), holder)
+            .with_context(|| format!("Unable to unwrap repository key of {:?}", root))?;
+        Self::with_key(dir, &key)
+    }
+
+    fn with_key(dir: PathBuf, key: &DataKey) -> anyhow::Result<Self> {
+        let keys = Keys::derive(key);
+        let index_dir = dir.join(INDEX_DIR);
+        let mut index = HashMap::new();
+        for file in fs::read_dir(&index_dir)
+            .with_context(|| format!("Unable to read index {:?}", index_dir))?
+        {
+            let name = file?.file_name();
+            let pack = match name.to_str() {
+                Some(pack) if !pack.ends_with(".tmp") => Arc::<str>::from(pack),
+                _ => continue,
+            };
+            let (cipher, entries) = index::read(&index_dir, &pack, &keys)?;
+            for entry in entries {
+                let location = Location {
+                    pack: pack.clone(),
+                    cipher,
+                    offset: entry.offset,
+                    len: entry.len,
+                };
+                index.insert(entry.id, location);
+            }
+        }
+        Ok(Self {
+            dir,
+            keys,
+            index: Mutex::new(index),
+            pack: Mutex::new(None),
+        })
+    }
+
+    /// Stores chunks of the source missing in the repository, returns ids of all its chunks.
+    pub(crate) fn store<R: Read>(
+        &self,
+        source: R,
+        cipher: CipherSuite,
+        compression: Compression,
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut ids = Vec::new();
+        for chunk in StreamCDC::new(source, MIN_CHUNK_SIZE, AVG_CHUNK_SIZE, MAX_CHUNK_SIZE) {
+            let data = chunk?.data;
+            let id = self.keys.chunk_id(&data);
+            ids.extend_from_slice(&id);
+            if self.is_stored(&self.pack.lock().unwrap(), &id) {
+                continue;
+            }
+            let sealed = self.seal_chunk(&id, &data, cipher, compression)?;
+            self.append(id, &sealed, cipher)?;
+        }
+        Ok(ids)
+    }
+
+    /// Finishes the pack being written and writes its index, chunks in it can't be restored
+    /// before.
+    pub(crate) fn finish(&self) -> anyhow::Result<()> {
+        self.finish_shared(&mut self.pack.lock().unwrap())
+    }
+
+    fn is_stored(&self, pack: &Option<(PackWriter, CipherSuite)>, id: &ChunkId) -> bool {
+        pack.as_ref().is_some_and(|(pack, _)| pack.contains(id))
+            || self.index.lock().unwrap().contains_key(id)
+    }
+
+    /// Appends the sealed chunk to the shared pack, unless another file stored it meanwhile.
+    /// Chunks sealed with another cipher go to a new pack, as a pack has a single one.
+    fn append(&self, id: ChunkId, sealed: &[u8], cipher: CipherSuite) -> anyhow::Result<()> {
+        let mut shared = self.pack.lock().unwrap();
+        if self.is_stored(&shared, &id) {
+            return Ok(());
+        }
+        if shared.as_ref().is_some_and(|(_, shared)| *shared != cipher) {
+            self.finish_shared(&mut shared)?;
+        }
+        let (pack, _) = match &mut *shared {
+            Some(shared) => shared,
+            None => shared.insert((PackWriter::create(&self.dir.join(PACKS_DIR))?, cipher)),
+        };
+        pack.append(id, sealed)?;
+        if pack.len() >= PACK_SIZE {
+            self.finish_shared(&mut shared)?;
+        }
+        Ok(())
+    }
+
+    /// The lock is held until the chunks are in the index, so they are always found stored.
+    fn finish_shared(&self, shared: &mut Option<(PackWriter, CipherSuite)>) -> anyhow::Result<()> {
+        let (pack, cipher) = match shared.take() {
+            Some(shared) => shared,
+            None => return Ok(()),
+        };
+        let (name, entries) = pack.finish()?;
+        index::write(
+            &self.dir.join(INDEX_DIR),
+            &name,
+            cipher,
+            &entries,
+            &self.keys,
+        )?;
+        self.add_to_index(name, cipher, entries);
+        Ok(())
+    }
+
+    /// Writes content of the chunks with the given ids into the target.
+    pub(crate) fn restore<W: Write>(&self, ids: &[u8], target: &mut W) -> anyhow::Result<()> {
+        if !ids.len().is_multiple_of(CHUNK_ID_SIZE) {
+            bail!("Invalid list of chunks");
+        }
+        let packs_dir = self.dir.join(PACKS_DIR);
+        let mut packs = PackReader::default();
+        for id in ids.chunks_exact(CHUNK_ID_SIZE) {
+            let id: ChunkId = id.try_into()?;
+            let location = self.index.lock().unwrap().get(&id).cloned();
+            let location = location.ok_or_else(|| {
+                anyhow!("Chunk {} is missing in repository", HEXLOWER.encode(&id))
+            })?;
+            let sealed = packs.read(&packs_dir, &location.pack, location.offset, location.len)?;
+            let data = self.open_chunk(&id, &sealed, location.cipher)?;
+            if self.keys.chunk_id(&data) != id {
+                bail!("Chunk {} doesn't match its id", HEXLOWER.encode(&id));
+            }
+            target.write_all(&data)?;
+        }
+        Ok(())
+    }
+
+    /// Layout: sealed `compression u8 | data`, chunks are only compressed if it helps.
+    fn seal_chunk(
+        &self,
+        id: &ChunkId,
+        data: &[u8],
+        cipher: CipherSuite,
+        compression: Compression,
+    ) -> anyhow::Result<Vec<u8>> {
+        let compressed = match compression {
+            Compression::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL)
+                .ok()
+                .filter(|compressed| compressed.len() < data.len()),
+            Compression::None => None,
+        };
+        let mut bytes = Vec::with_capacity(data.len() + 1);
+        match compressed {
+            Some(compressed) => {
+                bytes.push(Compression::Zstd.id());
+                bytes.extend(compressed);
+            }
+            None => {
+                bytes.push(Compression::None.id());
+                bytes.extend_from_slice(data);
+            }
+        }
+        Ok(self
+            .keys
+            .chunk_cipher(cipher, id)?
+            .encrypt(0, true, &bytes)?)
+    }
+
+    fn open_chunk(
+        &self,
+        id: &ChunkId,
+        sealed: &[u8],
+        cipher: CipherSuite,
+    ) -> anyhow::Result<Vec<u8>> {
+        let bytes = self
+            .keys
+            .chunk_cipher(cipher, id)?
+            .decrypt(0, true, sealed)
+            .with_context(|| format!("Chunk {} is corrupted", HEXLOWER.encode(id)))?;
+        let (compression, data) = bytes
+            .split_first()
+            .ok_or_else(|| anyhow!("Chunk {} is empty", HEXLOWER.encode(id)))?;
+        match Compression::from_id(*compression)? {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Zstd => Ok(zstd::bulk::decompress(data, MAX_CHUNK_SIZE as usize)?),
+        }
+    }
+
+    fn add_to_index(&self, pack: String, cipher: CipherSuite, entries: Vec<Entry>) {
+        let pack = Arc::<str>::from(pack);
+        let mut index = self.index.lock().unwrap();
+        for entry in entries {
+            let location = Location {
+                pack: pack.clone(),
+                cipher,
+                offset: entry.offset,
+                len: entry.len,
+            };
+            index.insert(entry.id, location);
+        }
+    }
+}
+
+/// Repositories of target trees.
+#[derive(Default)]
+pub(crate) struct Repositories {
+    /// Repository chunks are stored in when encrypting, files are stored whole without it.
+    encryptor: Option<Repository>,
+    /// Repositories found when decrypting, by their root.
+    decryptors: Mutex<HashMap<PathBuf, Arc<Repository>>>,
+}
+
+impl Repositories {
+    /// Stores chunks in the repository of the target tree, created when missing.
+    pub(crate) fn encrypted(
+        target_dir: &Path,
+        holder: &dyn KeyHolder,
+        key: Option<DataKey>,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            encryptor: Some(Repository::create(target_dir, holder, key)?),
+            ..Self::default()
+        })
+    }
+
+    pub(crate) fn encryptor(&self) -> Option<&Repository> {
+        self.encryptor.as_ref()
+    }
+
+    /// Finishes the pack the run was writing, if any.
+    pub(crate) fn finish(&self) -> anyhow::Result<()> {
+        match &self.encryptor {
+            Some(repository) => repository.finish(),
+            None => Ok(()),
+        }
+    }
+
+    /// Repository holding chunks of the encrypted file, found in one of its ancestors.
+    pub(crate) fn decryptor(
+        &self,
+        source: &Path,
+        holder: &dyn KeyHolder,
+    ) -> anyhow::Result<Arc<Repository>> {
+        let source = source.canonicalize()?;
+        let root = source
+            .ancestors()
+            .skip(1)
+            .find(|dir| dir.join(REPOSITORY_DIR).is_dir())
+            .ok_or_else(|| anyhow!("No repository found for file {:?}", source))?;
+        let mut decryptors = self.decryptors.lock().unwrap();
+        if let Some(repository) = decryptors.get(root) {
+            return Ok(repository.clone());
+        }
+        let repository = Arc::new(Repository::open(root, holder)?);
+        decryptors.insert(root.to_path_buf(), repository.clone());
+        Ok(repository)
+    }
+}
+
+/// Tells whether the file belongs to a repository rather than being an encrypted file.
+pub(crate) fn is_repository_file(path: &Path) -> bool {
+    path.components()
+        .any(|component| component.as_os_str() == REPOSITORY_DIR)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::worker::pass::holder::PassKey;
+    use rand::{thread_rng, RngCore};
+
+    #[test]
+    fn should_store_chunks_once() {
+        let tmp = tempfile::TempDir::new().expect("Unable to create temp dir");
+        let holder = PassKey::encryption("secret").expect("No key");
+        let repository = Repository::create(tmp.path(), &holder, None).expect("No repository");
+        let mut original = vec![0u8; 6 * 1024 * 1024];
+        thread_rng().fill_bytes(&mut original);
+        let ids = repository
+            .store(
+                original.as_slice(),
+                CipherSuite::Aes256Gcm,
+                Compression::Zstd,
+            )
+            .expect("Unable to store");
+        let packs_len = || {
+            fs::read_dir(tmp.path().join(REPOSITORY_DIR).join(PACKS_DIR))
+                .expect("No packs")
+                .map(|pack| {
+                    pack.expect("No pack")
+                        .metadata()
+                        .expect("No metadata")
+                        .len()
+                })
+                .sum::<u64>()
+        };
+        let stored = packs_len();
+        assert!(stored > original.len() as u64);
+        let packs = || {
+            fs::read_dir(tmp.path().join(REPOSITORY_DIR).join(PACKS_DIR))
+                .expect("No packs")
+                .count()
+        };
+
+        let mut modified = original.clone();
+        modified[3 * 1024 * 1024] ^= 1;
+        let modified_ids = repository
+            .store(
+                modified.as_slice(),
+                CipherSuite::ChaCha20Poly1305,
+                Compression::None,
+            )
+            .expect("Unable to store");
+        assert_ne!(ids, modified_ids);
+        assert!(packs_len() - stored <= 2 * MAX_CHUNK_SIZE as u64 + 1024);
+        let same_ids = repository
+            .store(
+                original.as_slice(),
+                CipherSuite::Aes256Gcm,
+                Compression::None,
+            )
+            .expect("Unable to store");
+        assert_eq!(ids, same_ids);
+        // Files of a run share the pack, a new one is started for another cipher.
+        assert_eq!(2, packs());
+        let mut other = vec![0u8; 1024 * 1024];
+        thread_rng().fill_bytes(&mut other);
+        let other_ids = repository
+            .store(
+                other.as_slice(),
+                CipherSuite::ChaCha20Poly1305,
+                Compression::None,
+            )
+            .expect("Unable to store");
+        assert_eq!(2, packs());
+        repository.finish().expect("Unable to finish");
+        assert_eq!(2, packs());
+
+        let decryptor = PassKey::decryption("secret");
+        let reopened = Repository::open(tmp.path(), &decryptor).expect("No repository");
+        for (ids, content) in [
+            (ids, original),
+            (modified_ids, modified),
+            (other_ids, other),
+        ] {
+            let mut restored = Vec::new();
+            reopened
+                .restore(&ids, &mut restored)
+                .expect("Unable to restore");
+            assert_eq!(content, restored);
+        }
+        let wrong = PassKey::decryption("wrong");
+        assert!(Repository::open(tmp.path(), &wrong).is_err());
+    }
+}
@@ -0,0 +1,119 @@
+use crate::repository::index::Entry;
+use crate::repository::ChunkId;
+use anyhow::{bail, Context};
+use data_encoding::HEXLOWER;
+use rand::{thread_rng, RngCore};
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Sealed chunks stored one after another. A pack is written to a temporary file
+/// and renamed once complete, an unfinished one is removed.
+pub(super) struct PackWriter {
+    name: String,
+    tmp_path: PathBuf,
+    file: Option<BufWriter<File>>,
+    len: u64,
+    entries: Vec<Entry>,
+    ids: HashSet<ChunkId>,
+}
+
+impl PackWriter {
+    pub(super) fn create(dir: &Path) -> anyhow::Result<Self> {
+        let mut name = [0u8; 16];
+        thread_rng().fill_bytes(&mut name);
+        let name = HEXLOWER.encode(&name);
+        let tmp_path = dir.join(format!("{}.tmp", name));
+        let file = File::create(&tmp_path)
+            .with_context(|| format!("Unable to write pack {:?}", tmp_path))?;
+        Ok(Self {
+            name,
+            tmp_path,
+            file: Some(BufWriter::new(file)),
+            len: 0,
+            entries: Vec::new(),
+            ids: HashSet::new(),
+        })
+    }
+
+    pub(super) fn contains(&self, id: &ChunkId) -> bool {
+        self.ids.contains(id)
+    }
+
+    /// Bytes of sealed chunks written so far.
+    pub(super) fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub(super) fn append(&mut self, id: ChunkId, sealed: &[u8]) -> anyhow::Result<()> {
+        let file = match self.file.as_mut() {
+            Some(file) => file,
+            None => bail!("Pack {} is finished", self.name),
+        };
+        file.write_all(sealed)?;
+        self.entries.push(Entry {
+            id,
+            offset: self.len,
+            len: sealed.len() as u32,
+        });
+        self.ids.insert(id);
+        self.len += sealed.len() as u64;
+        Ok(())
+    }
+
+    /// Name of the complete pack and its chunks.
+    pub(super) fn finish(mut self) -> anyhow::Result<(String, Vec<Entry>)> {
+        if let Some(mut file) = self.file.take() {
+            file.flush()?;
+            file.get_ref().sync_all()?;
+        }
+        let path = self.tmp_path.with_file_name(&self.name);
+        fs::rename(&self.tmp_path, &path)
+            .with_context(|| format!("Unable to rename pack to {:?}", path))?;
+        Ok((
+            std::mem::take(&mut self.name),
+            std::mem::take(&mut self.entries),
+        ))
+    }
+}
+
+impl Drop for PackWriter {
+    fn drop(&mut self) {
+        if self.file.take().is_some() {
+            let _ = fs::remove_file(&self.tmp_path);
+        }
+    }
+}
+
+/// Reads packs, keeping the last one open as chunks of a file are mostly stored together.
+#[derive(Default)]
+pub(super) struct PackReader {
+    open: Option<(String, File)>,
+}
+
+impl PackReader {
+    pub(super) fn read(
+        &mut self,
+        dir: &Path,
+        pack: &str,
+        offset: u64,
+        len: u32,
+    ) -> anyhow::Result<Vec<u8>> {
+        let file = match &mut self.open {
+            Some((name, file)) if name == pack => file,
+            open => {
+                let path = dir.join(pack);
+                let file =
+                    File::open(&path).with_context(|| format!("Unable to read pack {:?}", path))?;
+                &mut open.insert((pack.to_string(), file)).1
+            }
+        };
+        let mut sealed = vec![0u8; len as usize];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut sealed)
+            .with_context(|| format!("Pack {} is truncated", pack))?;
+        Ok(sealed)
+    }
+}
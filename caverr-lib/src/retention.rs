@@ -0,0 +1,364 @@
+use crate::file::metadata::Timestamp;
+use crate::repository::REPOSITORY_DIR;
+use crate::snapshot::{Manifest, SNAPSHOTS_DIR};
+use crate::version::{parse_version, version_base, version_path};
+use anyhow::{bail, Context};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const SECONDS_PER_DAY: i64 = 86400;
+
+/// Number of the period a time is in.
+type Period = fn(Timestamp) -> i64;
+
+/// How many versions to keep, counted from the newest one. Every rule keeps the newest
+/// version of each of its periods, versions kept by none of the rules are pruned.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Retention {
+    pub last: usize,
+    pub daily: usize,
+    pub weekly: usize,
+    pub monthly: usize,
+}
+
+/// Files removed by pruning, or to be removed in a dry run.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Pruned {
+    pub files: Vec<PathBuf>,
+    pub bytes: u64,
+}
+
+impl Retention {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// Tells which of the times, sorted from the newest, are kept.
+    fn keep(&self, times: &[Timestamp]) -> Vec<bool> {
+        let mut kept = vec![false; times.len()];
+        kept.iter_mut()
+            .take(self.last)
+            .for_each(|kept| *kept = true);
+        let periods: [(usize, Period); 3] = [
+            (self.daily, day),
+            (self.weekly, week),
+            (self.monthly, month),
+        ];
+        for (count, period) in periods {
+            let mut last_period = None;
+            let mut remaining = count;
+            for (time, kept) in times.iter().zip(kept.iter_mut()) {
+                if remaining == 0 {
+                    break;
+                }
+                let period = period(*time);
+                if last_period != Some(period) {
+                    last_period = Some(period);
+                    remaining -= 1;
+                    *kept = true;
+                }
+            }
+        }
+        kept
+    }
+}
+
+/// Removes kept versions of encrypted files and snapshots of the backup directory that the
+/// retention doesn't keep any more. Snapshots are pruned first, versions the manifests of the
+/// retained ones refer to are kept, so they can still be restored. Current encrypted files are
+/// never removed, neither are chunks of a repository. Nothing is removed in a dry run.
+pub fn prune(
+    backup_dir: &Path,
+    retention: &Retention,
+    manifests: &[Manifest],
+    dry_run: bool,
+) -> anyhow::Result<Pruned> {
+    if retention.is_empty() {
+        bail!("Retention keeps nothing");
+    }
+    let mut versions = HashMap::new();
+    collect_versions(backup_dir, &mut versions)?;
+    let mut pruned = Pruned::default();
+    let snapshots = versions
+        .remove(&backup_dir.join(SNAPSHOTS_DIR))
+        .unwrap_or_default();
+    let retained = prune_versions(snapshots, retention, &HashSet::new(), dry_run, &mut pruned)?;
+    let referenced: HashSet<_> = manifests
+        .iter()
+        .filter(|manifest| {
+            retained
+                .iter()
+                .any(|path| path.file_name() == Some(OsStr::new(&manifest.id)))
+        })
+        .flat_map(|manifest| &manifest.entries)
+        .map(|entry| version_path(&backup_dir.join(&entry.target), entry.written))
+        .collect();
+    for versions in versions.into_values() {
+        prune_versions(versions, retention, &referenced, dry_run, &mut pruned)?;
+    }
+    pruned.files.sort();
+    Ok(pruned)
+}
+
+/// Removes the versions of a file the retention doesn't keep, unless current or referenced,
+/// returns paths of the retained ones.
+fn prune_versions(
+    mut versions: Vec<(Timestamp, PathBuf, bool)>,
+    retention: &Retention,
+    referenced: &HashSet<PathBuf>,
+    dry_run: bool,
+    pruned: &mut Pruned,
+) -> anyhow::Result<Vec<PathBuf>> {
+    versions.sort_by_key(|(time, _, _)| Reverse(*time));
+    let times: Vec<_> = versions.iter().map(|(time, _, _)| *time).collect();
+    let kept = retention.keep(&times);
+    let mut retained = Vec::new();
+    for ((_, path, current), kept) in versions.into_iter().zip(kept) {
+        if kept || current || referenced.contains(&path) {
+            retained.push(path);
+            continue;
+        }
+        pruned.bytes += fs::metadata(&path)?.len();
+        if !dry_run {
+            fs::remove_file(&path).with_context(|| format!("Unable to remove file {:?}", path))?;
+        }
+        pruned.files.push(path);
+    }
+    Ok(retained)
+}
+
+/// Encrypted files and snapshots by their current path, with every version as
+/// `(time written, path, current)`.
+fn collect_versions(
+    dir: &Path,
+    versions: &mut HashMap<PathBuf, Vec<(Timestamp, PathBuf, bool)>>,
+) -> anyhow::Result<()> {
+    for entry in fs::read_dir(dir).with_context(|| format!("Unable to read dir {:?}", dir))? {
+        let entry = entry?;
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        let name = entry.file_name();
+        if file_type.is_dir() && name == SNAPSHOTS_DIR {
+            for snapshot in fs::read_dir(&path)? {
+                let snapshot = snapshot?.path();
+                let id = snapshot.file_name().unwrap_or_default().to_string_lossy();
+                if let Ok(time) = humantime::parse_rfc3339(&id) {
+                    versions
+                        .entry(path.clone())
+                        .or_default()
+                        .push((time.into(), snapshot, false));
+                }
+            }
+        } else if file_type.is_dir() && name != REPOSITORY_DIR {
+            collect_versions(&path, versions)?;
+        } else if file_type.is_file() {
+            let (target, time, current) = match parse_version(&path) {
+                Some((target, written)) => (target, written, false),
+                None => (
+                    version_base(&path),
+                    Timestamp::modified(&entry.metadata()?),
+                    true,
+                ),
+            };
+            versions
+                .entry(target)
+                .or_default()
+                .push((time, path, current));
+        }
+    }
+    Ok(())
+}
+
+fn day(time: Timestamp) -> i64 {
+    time.secs.div_euclid(SECONDS_PER_DAY)
+}
+
+/// Weeks start on Monday, the epoch was on Thursday.
+fn week(time: Timestamp) -> i64 {
+    (day(time) + 3).div_euclid(7)
+}
+
+/// Months since the epoch, from the civil date of the day.
+fn month(time: Timestamp) -> i64 {
+    // Days since 0000-03-01, years starting in March end with the leap day.
+    let days = day(time) + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let year = year_of_era + era * 400 + i64::from(month_from_march >= 10);
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    year * 12 + month - 1
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::snapshot::SnapshotEntry;
+    use std::fs::File;
+
+    fn at(time: &str) -> Timestamp {
+        humantime::parse_rfc3339(time).expect("Invalid time").into()
+    }
+
+    #[test]
+    fn should_keep_versions_by_rules() {
+        assert_eq!(
+            month(at("1970-01-31T23:59:59Z")),
+            month(at("1970-01-01T00:00:00Z"))
+        );
+        assert_eq!(
+            month(at("2024-02-29T12:00:00Z")) + 1,
+            month(at("2024-03-01T00:00:00Z"))
+        );
+        assert_eq!(
+            week(at("2026-10-12T00:00:00Z")),
+            week(at("2026-10-18T23:59:59Z"))
+        );
+        assert_eq!(
+            week(at("2026-10-18T23:59:59Z")) + 1,
+            week(at("2026-10-19T00:00:00Z"))
+        );
+
+        let times = [
+            at("2026-10-17T18:00:00Z"),
+            at("2026-10-17T08:00:00Z"),
+            at("2026-10-16T18:00:00Z"),
+            at("2026-10-12T18:00:00Z"),
+            at("2026-10-11T18:00:00Z"),
+            at("2026-09-30T18:00:00Z"),
+            at("2026-08-01T18:00:00Z"),
+        ];
+        let keep = |last, daily, weekly, monthly| {
+            Retention {
+                last,
+                daily,
+                weekly,
+                monthly,
+            }
+            .keep(&times)
+        };
+        let (t, f) = (true, false);
+        assert_eq!(vec![t, t, f, f, f, f, f], keep(2, 0, 0, 0));
+        assert_eq!(vec![t, f, t, t, f, f, f], keep(0, 3, 0, 0));
+        assert_eq!(vec![t, f, f, f, t, t, f], keep(0, 0, 3, 0));
+        assert_eq!(vec![t, f, f, f, f, t, t], keep(0, 0, 0, 5));
+        assert_eq!(vec![t, t, t, f, f, t, t], keep(2, 2, 0, 3));
+    }
+
+    #[test]
+    fn should_prune_versions() {
+        let tmp = tempfile::TempDir::new().expect("Unable to create temp dir");
+        let target = tmp.path().join("dir").join("file");
+        fs::create_dir_all(target.parent().expect("No parent")).expect("Unable to create dir");
+        fs::write(&target, b"current").expect("Unable to write");
+        File::options()
+            .write(true)
+            .open(&target)
+            .and_then(|file| file.set_modified(at("2026-10-17T10:00:00Z").to_system_time()))
+            .expect("Unable to set time");
+        let versions = [
+            version_path(&target, at("2026-10-16T18:00:00Z")),
+            version_path(&target, at("2026-10-16T08:00:00Z")),
+            version_path(&target, at("2026-10-15T18:00:00Z")),
+        ];
+        for version in &versions {
+            fs::write(version, b"version").expect("Unable to write");
+        }
+        let snapshots = tmp.path().join(SNAPSHOTS_DIR);
+        fs::create_dir_all(&snapshots).expect("Unable to create dir");
+        for id in ["2026-10-16T18:00:01.000000Z", "2026-10-16T08:00:01.000000Z"] {
+            fs::write(snapshots.join(id), b"snapshot").expect("Unable to write");
+        }
+        let repository = tmp.path().join(REPOSITORY_DIR);
+        fs::create_dir_all(&repository).expect("Unable to create dir");
+        fs::write(repository.join("key"), b"key").expect("Unable to write");
+
+        let retention = Retention {
+            last: 1,
+            daily: 3,
+            ..Retention::default()
+        };
+        let expected = Pruned {
+            files: vec![
+                snapshots.join("2026-10-16T08:00:01.000000Z"),
+                versions[1].clone(),
+            ],
+            bytes: 15,
+        };
+        assert_eq!(
+            expected,
+            prune(tmp.path(), &retention, &[], true).expect("Unable to prune")
+        );
+        assert!(versions[1].exists());
+        assert_eq!(
+            expected,
+            prune(tmp.path(), &retention, &[], false).expect("Unable to prune")
+        );
+        assert!(!versions[1].exists());
+        assert!(target.exists() && versions[0].exists() && versions[2].exists());
+        assert!(repository.join("key").exists());
+        assert!(prune(tmp.path(), &Retention::default(), &[], true).is_err());
+    }
+
+    #[test]
+    fn should_keep_versions_of_retained_snapshots() {
+        let tmp = tempfile::TempDir::new().expect("Unable to create temp dir");
+        let target = tmp.path().join("file");
+        fs::write(&target, b"current").expect("Unable to write");
+        let versions = [
+            version_path(&target, at("2026-10-16T18:00:00Z")),
+            version_path(&target, at("2026-10-16T08:00:00Z")),
+        ];
+        for version in &versions {
+            fs::write(version, b"version").expect("Unable to write");
+        }
+        let snapshots = tmp.path().join(SNAPSHOTS_DIR);
+        fs::create_dir_all(&snapshots).expect("Unable to create dir");
+        let ids = ["2026-10-16T08:00:01.000000Z", "2026-10-15T08:00:01.000000Z"];
+        for id in ids {
+            fs::write(snapshots.join(id), b"snapshot").expect("Unable to write");
+        }
+        let manifests: Vec<_> = ids
+            .iter()
+            .map(|id| Manifest {
+                id: id.to_string(),
+                time: at(id),
+                host: "host".into(),
+                roots: vec![PathBuf::from("/")],
+                entries: vec![SnapshotEntry {
+                    path: PathBuf::from("/file"),
+                    size: 7,
+                    modified: at("2026-10-16T07:00:00Z"),
+                    hash: [0; 32],
+                    target: PathBuf::from("file"),
+                    written: at("2026-10-16T08:00:00Z"),
+                }],
+            })
+            .collect();
+
+        let retention = Retention {
+            last: 1,
+            ..Retention::default()
+        };
+        let expected = Pruned {
+            files: vec![snapshots.join(ids[1]), versions[0].clone()],
+            bytes: 15,
+        };
+        assert_eq!(
+            expected,
+            prune(tmp.path(), &retention, &manifests, false).expect("Unable to prune")
+        );
+        assert!(versions[1].exists());
+        assert!(snapshots.join(ids[0]).exists());
+    }
+}
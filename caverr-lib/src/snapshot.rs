@@ -0,0 +1,527 @@
+use crate::cipher::{ChunkCipher, CipherSuite, DataKey, TAG_SIZE};
+use crate::compression::{Compression, ZSTD_LEVEL};
+use crate::file::header::Header;
+use crate::file::metadata::Timestamp;
+use crate::file::ContentHash;
+use crate::padding::Padding;
+use crate::path::{os_str_bytes, os_string_from_bytes};
+use crate::version::version_path;
+use crate::worker::KeyHolder;
+use anyhow::{bail, Context};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Directory in the target directory holding manifests of encryption runs.
+pub const SNAPSHOTS_DIR: &str = ".caverr-snapshots";
+
+/// Size of manifest plain text sealed in a single AEAD chunk.
+const CHUNK_SIZE: usize = 65536;
+
+/// A source file as it was when the snapshot was taken.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotEntry {
+    pub path: PathBuf,
+    pub size: u64,
+    pub modified: Timestamp,
+    pub hash: ContentHash,
+    /// Encrypted file, relative to the target directory.
+    pub target: PathBuf,
+    /// Modification time of the encrypted file, tells its versions apart.
+    pub written: Timestamp,
+}
+
+/// Record of an encryption run.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Manifest {
+    /// Name of the manifest file, the time of the run.
+    pub id: String,
+    pub time: Timestamp,
+    pub host: String,
+    pub roots: Vec<PathBuf>,
+    /// Sorted by path.
+    pub entries: Vec<SnapshotEntry>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    Added(PathBuf),
+    Removed(PathBuf),
+    Modified(PathBuf),
+}
+
+impl Change {
+    pub fn path(&self) -> &Path {
+        match self {
+            Change::Added(path) | Change::Removed(path) | Change::Modified(path) => path,
+        }
+    }
+}
+
+impl Manifest {
+    /// Total size of the source files.
+    pub fn size(&self) -> u64 {
+        self.entries.iter().map(|entry| entry.size).sum()
+    }
+
+    /// Files added, removed or modified in the newer snapshot.
+    pub fn diff(&self, newer: &Manifest) -> Vec<Change> {
+        let older: HashMap<&Path, &SnapshotEntry> = self
+            .entries
+            .iter()
+            .map(|entry| (entry.path.as_path(), entry))
+            .collect();
+        let newer_paths: HashSet<&Path> = newer
+            .entries
+            .iter()
+            .map(|entry| entry.path.as_path())
+            .collect();
+        let mut changes = Vec::new();
+        for entry in &newer.entries {
+            match older.get(entry.path.as_path()) {
+                None => changes.push(Change::Added(entry.path.clone())),
+                Some(old) if old.hash != entry.hash => {
+                    changes.push(Change::Modified(entry.path.clone()))
+                }
+                Some(_) => {}
+            }
+        }
+        for entry in &self.entries {
+            if !newer_paths.contains(entry.path.as_path()) {
+                changes.push(Change::Removed(entry.path.clone()));
+            }
+        }
+        changes.sort_by(|a, b| a.path().cmp(b.path()));
+        changes
+    }
+
+    /// Layout (integers are big endian):
+    /// `time i64 u32 | host len u16 | host | roots u32 | root... | entries u64 | entry...`
+    /// where every root is `len u32 | path` and every entry is
+    /// `path len u32 | path | size u64 | mtime i64 u32 | hash[32] | target len u32 | target |
+    /// written i64 u32`.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_timestamp(&mut bytes, self.time);
+        bytes.extend_from_slice(&(self.host.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(self.host.as_bytes());
+        bytes.extend_from_slice(&(self.roots.len() as u32).to_be_bytes());
+        for root in &self.roots {
+            write_path(&mut bytes, root);
+        }
+        bytes.extend_from_slice(&(self.entries.len() as u64).to_be_bytes());
+        for entry in &self.entries {
+            write_path(&mut bytes, &entry.path);
+            bytes.extend_from_slice(&entry.size.to_be_bytes());
+            write_timestamp(&mut bytes, entry.modified);
+            bytes.extend_from_slice(&entry.hash);
+            write_path(&mut bytes, &entry.target);
+            write_timestamp(&mut bytes, entry.written);
+        }
+        bytes
+    }
+
+    fn from_bytes(id: String, mut bytes: &[u8]) -> anyhow::Result<Self> {
+        let r = &mut bytes;
+        let time = read_timestamp(r)?;
+        let host_len = u16::from_be_bytes(read_array(r)?) as usize;
+        let host = String::from_utf8_lossy(read_slice(r, host_len)?).into_owned();
+        let roots = (0..u32::from_be_bytes(read_array(r)?))
+            .map(|_| read_path(r))
+            .collect::<anyhow::Result<_>>()?;
+        let count = u64::from_be_bytes(read_array(r)?);
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            entries.push(SnapshotEntry {
+                path: read_path(r)?,
+                size: u64::from_be_bytes(read_array(r)?),
+                modified: read_timestamp(r)?,
+                hash: read_array(r)?,
+                target: read_path(r)?,
+                written: read_timestamp(r)?,
+            });
+        }
+        if !r.is_empty() {
+            bail!("Unexpected data after manifest entries");
+        }
+        Ok(Self {
+            id,
+            time,
+            host,
+            roots,
+            entries,
+        })
+    }
+}
+
+/// Files of an encryption run, their manifest is written to the target directory at the end.
+/// Entries of the previous manifest, if any, spare hashing unchanged files again.
+pub(crate) struct Snapshot {
+    target_dir: PathBuf,
+    time: SystemTime,
+    roots: Vec<PathBuf>,
+    entries: Mutex<Vec<SnapshotEntry>>,
+    previous: HashMap<PathBuf, SnapshotEntry>,
+}
+
+impl Snapshot {
+    pub(crate) fn new(target_dir: &Path, roots: Vec<PathBuf>, previous: Option<Manifest>) -> Self {
+        let previous = previous
+            .map(|manifest| manifest.entries)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|entry| (entry.path.clone(), entry))
+            .collect();
+        Self {
+            target_dir: target_dir.to_path_buf(),
+            time: SystemTime::now(),
+            roots,
+            entries: Mutex::default(),
+            previous,
+        }
+    }
+
+    /// Hash of the source recorded in the previous manifest, when the source still has the
+    /// size and modification time it had then.
+    pub(crate) fn previous_hash(&self, source: &Path) -> anyhow::Result<Option<ContentHash>> {
+        let previous = match self.previous.get(&source.canonicalize()?) {
+            Some(previous) => previous,
+            None => return Ok(None),
+        };
+        let metadata = fs::metadata(source)?;
+        let unchanged =
+            previous.size == metadata.len() && previous.modified == Timestamp::modified(&metadata);
+        Ok(unchanged.then_some(previous.hash))
+    }
+
+    /// Records the source with its encrypted file, the hash is computed when not given.
+    pub(crate) fn add(
+        &self,
+        source: &Path,
+        target: &Path,
+        hash: ContentHash,
+    ) -> anyhow::Result<()> {
+        let metadata = fs::metadata(source)?;
+        let entry = SnapshotEntry {
+            path: source.canonicalize()?,
+            size: metadata.len(),
+            modified: Timestamp::modified(&metadata),
+            hash,
+            target: target.strip_prefix(&self.target_dir)?.to_path_buf(),
+            written: Timestamp::modified(&fs::metadata(target)?),
+        };
+        self.entries.lock().unwrap().push(entry);
+        Ok(())
+    }
+
+    /// Writes the manifest, sealed for the holder. Returns its id.
+    pub(crate) fn write(
+        &self,
+        holder: &dyn KeyHolder,
+        cipher: CipherSuite,
+    ) -> anyhow::Result<String> {
+        let mut entries = self.entries.lock().unwrap().clone();
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        let manifest = Manifest {
+            id: humantime::format_rfc3339_micros(self.time).to_string(),
+            time: self.time.into(),
+            host: host_name(),
+            roots: self.roots.clone(),
+            entries,
+        };
+        let dir = self.target_dir.join(SNAPSHOTS_DIR);
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Unable to create snapshots directory {:?}", dir))?;
+        seal(&dir, &manifest.id, &manifest.to_bytes(), holder, cipher)?;
+        Ok(manifest.id)
+    }
+}
+
+/// Manifests of encryption runs into the backup directory, oldest first.
+pub(crate) fn read_manifests(
+    backup_dir: &Path,
+    holder: &dyn KeyHolder,
+) -> anyhow::Result<Vec<Manifest>> {
+    let dir = backup_dir.join(SNAPSHOTS_DIR);
+    manifest_ids(&dir)?
+        .into_iter()
+        .map(|id| read_manifest(&dir, id, holder))
+        .collect()
+}
+
+/// Manifest of the latest encryption run into the backup directory, `None` when there is
+/// none or the holder can't open it, as public keys can't.
+pub(crate) fn latest_manifest(backup_dir: &Path, holder: &dyn KeyHolder) -> Option<Manifest> {
+    let dir = backup_dir.join(SNAPSHOTS_DIR);
+    let id = manifest_ids(&dir).ok()?.pop()?;
+    read_manifest(&dir, id, holder).ok()
+}
+
+/// Ids of manifests in the snapshots directory, oldest first.
+fn manifest_ids(dir: &Path) -> anyhow::Result<Vec<String>> {
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut ids = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let id = entry?.file_name().to_string_lossy().into_owned();
+        if !id.ends_with(".tmp") {
+            ids.push(id);
+        }
+    }
+    ids.sort();
+    Ok(ids)
+}
+
+fn read_manifest(dir: &Path, id: String, holder: &dyn KeyHolder) -> anyhow::Result<Manifest> {
+    let bytes =
+        open(&dir.join(&id), holder).with_context(|| format!("Unable to read snapshot {}", id))?;
+    Manifest::from_bytes(id.clone(), &bytes).with_context(|| format!("Invalid snapshot {}", id))
+}
+
+/// Encrypted file holding the version of the entry, either the current one or a kept version.
+pub(crate) fn locate(backup_dir: &Path, entry: &SnapshotEntry) -> anyhow::Result<PathBuf> {
+    let path = backup_dir.join(&entry.target);
+    let written = fs::metadata(&path).map(|metadata| Timestamp::modified(&metadata));
+    if written.ok() == Some(entry.written) {
+        return Ok(path);
+    }
+    let version = version_path(&path, entry.written);
+    if !version.is_file() {
+        bail!(
+            "Version of {:?} from the snapshot is no longer available",
+            entry.path
+        );
+    }
+    Ok(version)
+}
+
+/// Tells whether the file is a snapshot manifest rather than an encrypted file.
+pub(crate) fn is_snapshot_file(path: &Path) -> bool {
+    path.components()
+        .any(|component| component.as_os_str() == SNAPSHOTS_DIR)
+}
+
+/// Layout: `header | sealed chunk...`, the plain text is the zstd compressed manifest.
+fn seal(
+    dir: &Path,
+    id: &str,
+    bytes: &[u8],
+    holder: &dyn KeyHolder,
+    cipher: CipherSuite,
+) -> anyhow::Result<()> {
+    let key = DataKey::generate();
+    let header = Header::new(
+        cipher,
+        CHUNK_SIZE as u32,
+        Padding::None,
+        Compression::Zstd,
+        holder.wrap_key(&key)?,
+    );
+    let mut header_bytes = Vec::new();
+    header.write(&mut header_bytes)?;
+    let chunk_cipher = ChunkCipher::new(cipher, &key, header_bytes.clone());
+    let compressed = zstd::stream::encode_all(bytes, ZSTD_LEVEL)?;
+    let path = dir.join(id);
+    let tmp_path = dir.join(format!("{}.tmp", id));
+    let mut file = File::create(&tmp_path)
+        .with_context(|| format!("Unable to write snapshot {:?}", tmp_path))?;
+    file.write_all(&header_bytes)?;
+    let count = compressed.len().div_ceil(CHUNK_SIZE);
+    for (index, chunk) in compressed.chunks(CHUNK_SIZE).enumerate() {
+        file.write_all(&chunk_cipher.encrypt(index, index + 1 == count, chunk)?)?;
+    }
+    file.sync_all()?;
+    fs::rename(&tmp_path, &path).with_context(|| format!("Unable to write snapshot {:?}", path))
+}
+
+fn open(path: &Path, holder: &dyn KeyHolder) -> anyhow::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    let header = Header::read(&mut file)?;
+    let key = holder.unwrap_key(&header.key)?;
+    let mut header_bytes = Vec::new();
+    header.write(&mut header_bytes)?;
+    let cipher = ChunkCipher::new(header.cipher, &key, header_bytes);
+    let mut sealed = Vec::new();
+    file.read_to_end(&mut sealed)?;
+    let chunk_len = header.chunk_size as usize + TAG_SIZE;
+    let count = sealed.len().div_ceil(chunk_len);
+    let mut compressed = Vec::with_capacity(sealed.len());
+    for (index, chunk) in sealed.chunks(chunk_len).enumerate() {
+        compressed.extend(cipher.decrypt(index, index + 1 == count, chunk)?);
+    }
+    match header.compression {
+        Compression::Zstd => Ok(zstd::stream::decode_all(compressed.as_slice())?),
+        Compression::None => Ok(compressed),
+    }
+}
+
+#[cfg(unix)]
+fn host_name() -> String {
+    let mut name = [0u8; 256];
+    // The buffer outlives the call and its whole length is given.
+    let result = unsafe { libc::gethostname(name.as_mut_ptr().cast(), name.len()) };
+    if result != 0 {
+        return String::new();
+    }
+    let len = name
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(name.len());
+    String::from_utf8_lossy(&name[..len]).into_owned()
+}
+
+#[cfg(not(unix))]
+fn host_name() -> String {
+    std::env::var("COMPUTERNAME")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .unwrap_or_default()
+}
+
+pub(crate) fn write_timestamp(bytes: &mut Vec<u8>, timestamp: Timestamp) {
+    bytes.extend_from_slice(&timestamp.secs.to_be_bytes());
+    bytes.extend_from_slice(&timestamp.nanos.to_be_bytes());
+}
+
+pub(crate) fn write_path(bytes: &mut Vec<u8>, path: &Path) {
+    let path = os_str_bytes(path.as_os_str());
+    bytes.extend_from_slice(&(path.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(path);
+}
+
+pub(crate) fn read_timestamp(r: &mut &[u8]) -> anyhow::Result<Timestamp> {
+    Ok(Timestamp {
+        secs: i64::from_be_bytes(read_array(r)?),
+        nanos: u32::from_be_bytes(read_array(r)?),
+    })
+}
+
+pub(crate) fn read_path(r: &mut &[u8]) -> anyhow::Result<PathBuf> {
+    let len = u32::from_be_bytes(read_array(r)?) as usize;
+    Ok(os_string_from_bytes(read_slice(r, len)?.to_vec()).into())
+}
+
+pub(crate) fn read_array<const N: usize>(r: &mut &[u8]) -> anyhow::Result<[u8; N]> {
+    Ok(read_slice(r, N)?.try_into()?)
+}
+
+fn read_slice<'a>(r: &mut &'a [u8], len: usize) -> anyhow::Result<&'a [u8]> {
+    if r.len() < len {
+        bail!("Data is truncated");
+    }
+    let (bytes, rest) = r.split_at(len);
+    *r = rest;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::worker::pass::holder::PassKey;
+
+    fn entry(path: &str, hash: u8) -> SnapshotEntry {
+        SnapshotEntry {
+            path: PathBuf::from(path),
+            size: 1000,
+            modified: Timestamp {
+                secs: 1_600_000_000,
+                nanos: 5,
+            },
+            hash: [hash; 32],
+            target: PathBuf::from(format!("target{}", path)),
+            written: Timestamp {
+                secs: -1,
+                nanos: 999,
+            },
+        }
+    }
+
+    #[test]
+    fn should_read_written_manifest() {
+        let tmp = tempfile::TempDir::new().expect("Unable to create temp dir");
+        let snapshot = Snapshot::new(tmp.path(), vec![PathBuf::from("/home")], None);
+        snapshot
+            .entries
+            .lock()
+            .unwrap()
+            .extend([entry("/home/b", 1), entry("/home/a", 2)]);
+        let holder = PassKey::encryption("secret").expect("No key");
+        let id = snapshot
+            .write(&holder, CipherSuite::ChaCha20Poly1305)
+            .expect("Unable to write snapshot");
+
+        let decryptor = PassKey::decryption("secret");
+        let manifests = read_manifests(tmp.path(), &decryptor).expect("Unable to read");
+        assert_eq!(1, manifests.len());
+        let manifest = &manifests[0];
+        assert_eq!(id, manifest.id);
+        assert_eq!(Timestamp::from(snapshot.time), manifest.time);
+        assert_eq!(host_name(), manifest.host);
+        assert_eq!(vec![PathBuf::from("/home")], manifest.roots);
+        assert_eq!(
+            vec![entry("/home/a", 2), entry("/home/b", 1)],
+            manifest.entries
+        );
+        assert_eq!(2000, manifest.size());
+        let wrong = PassKey::decryption("wrong");
+        assert!(read_manifests(tmp.path(), &wrong).is_err());
+        assert!(latest_manifest(tmp.path(), &wrong).is_none());
+        assert_eq!(
+            Some(manifest),
+            latest_manifest(tmp.path(), &holder).as_ref()
+        );
+    }
+
+    #[test]
+    fn should_reuse_previous_hashes() {
+        let tmp = tempfile::TempDir::new().expect("Unable to create temp dir");
+        let source = tmp.path().join("source");
+        fs::write(&source, b"content").expect("Unable to write");
+        let metadata = fs::metadata(&source).expect("No metadata");
+        let previous = Manifest {
+            id: String::new(),
+            time: Timestamp::default(),
+            host: String::new(),
+            roots: Vec::new(),
+            entries: vec![SnapshotEntry {
+                path: source.canonicalize().expect("No source"),
+                size: metadata.len(),
+                modified: Timestamp::modified(&metadata),
+                ..entry("/source", 7)
+            }],
+        };
+        let snapshot = Snapshot::new(tmp.path(), Vec::new(), Some(previous));
+        let hash = snapshot.previous_hash(&source).expect("Unable to look up");
+        assert_eq!(Some([7; 32]), hash);
+
+        fs::write(&source, b"changed content").expect("Unable to write");
+        let hash = snapshot.previous_hash(&source).expect("Unable to look up");
+        assert_eq!(None, hash);
+    }
+
+    #[test]
+    fn should_diff_manifests() {
+        let manifest = |entries| Manifest {
+            id: String::new(),
+            time: Timestamp::default(),
+            host: String::new(),
+            roots: Vec::new(),
+            entries,
+        };
+        let older = manifest(vec![entry("/a", 1), entry("/b", 1), entry("/c", 1)]);
+        let newer = manifest(vec![entry("/a", 1), entry("/b", 2), entry("/d", 1)]);
+        assert_eq!(
+            vec![
+                Change::Modified(PathBuf::from("/b")),
+                Change::Removed(PathBuf::from("/c")),
+                Change::Added(PathBuf::from("/d")),
+            ],
+            older.diff(&newer)
+        );
+        assert!(newer.diff(&newer).is_empty());
+    }
+}
@@ -0,0 +1,139 @@
+use crate::file::{file_verify, hash_file};
+use crate::journal::is_journal_file;
+use crate::names::Names;
+use crate::repository::{is_repository_file, Repositories};
+use crate::snapshot::is_snapshot_file;
+use crate::version::parse_version;
+use crate::worker::{KeyHolder, Transformed};
+use anyhow::{bail, Context};
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+/// How decrypted content is compared with its source file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Comparison {
+    Bytes,
+    Hash,
+}
+
+impl FromStr for Comparison {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bytes" => Ok(Comparison::Bytes),
+            "hash" => Ok(Comparison::Hash),
+            other => Err(format!(
+                "Invalid comparison `{}`. Must be either: `bytes` or `hash`",
+                other
+            )),
+        }
+    }
+}
+
+impl Display for Comparison {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Comparison::Bytes => write!(f, "bytes"),
+            Comparison::Hash => write!(f, "hash"),
+        }
+    }
+}
+
+/// Decrypts the encrypted file of the backup directory in memory, nothing is written.
+/// When compared, its source is looked up in `sources_dir` at the path it was encrypted
+/// from, so `/` finds sources where they were. Returns the path of the source.
+pub(crate) fn verify(
+    backup_dir: &Path,
+    path: &Path,
+    holder: &dyn KeyHolder,
+    names: &Names,
+    repositories: &Repositories,
+    comparison: Option<(Comparison, &Path)>,
+) -> anyhow::Result<Transformed> {
+    if is_repository_file(path)
+        || is_snapshot_file(path)
+        || is_journal_file(path)
+        || parse_version(path).is_some()
+    {
+        return Ok(Transformed::Skipped);
+    }
+    let plain = match names.plain_path(path, holder)? {
+        Some(plain) => plain,
+        None => return Ok(Transformed::Skipped),
+    };
+    let relative = plain.strip_prefix(backup_dir.canonicalize()?)?;
+    let (comparison, sources_dir) = match comparison {
+        Some(comparison) => comparison,
+        None => {
+            let bytes = file_verify(path, holder, repositories, io::sink())?;
+            return Ok(Transformed::Processed(bytes, Path::new("/").join(relative)));
+        }
+    };
+    let source = sources_dir.join(relative);
+    let bytes = match comparison {
+        Comparison::Bytes => {
+            let original = File::open(&source)
+                .with_context(|| format!("Unable to read source file {:?}", source))?;
+            let mut target = ComparingWriter::new(BufReader::with_capacity(65536, original));
+            let bytes = file_verify(path, holder, repositories, &mut target)?;
+            if !target.finish()? {
+                bail!("Content differs from source file {:?}", source);
+            }
+            bytes
+        }
+        Comparison::Hash => {
+            let mut hasher = blake3::Hasher::new();
+            let bytes = file_verify(path, holder, repositories, &mut hasher)?;
+            let original = hash_file(&source)
+                .with_context(|| format!("Unable to hash source file {:?}", source))?;
+            if *hasher.finalize().as_bytes() != original {
+                bail!("Content hash differs from source file {:?}", source);
+            }
+            bytes
+        }
+    };
+    Ok(Transformed::Processed(bytes, source))
+}
+
+/// Compares everything written to it with the content of the reader.
+struct ComparingWriter<R> {
+    original: R,
+    equal: bool,
+}
+
+impl<R: BufRead> ComparingWriter<R> {
+    fn new(original: R) -> Self {
+        Self {
+            original,
+            equal: true,
+        }
+    }
+
+    /// Tells whether the content was equal, and the reader has nothing more.
+    fn finish(mut self) -> io::Result<bool> {
+        Ok(self.equal && self.original.fill_buf()?.is_empty())
+    }
+}
+
+impl<R: BufRead> Write for ComparingWriter<R> {
+    fn write(&mut self, mut buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len();
+        while self.equal && !buf.is_empty() {
+            let original = self.original.fill_buf()?;
+            let compared = original.len().min(buf.len());
+            self.equal = compared > 0 && original[..compared] == buf[..compared];
+            self.original.consume(compared);
+            buf = &buf[compared..];
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,118 @@
+use crate::file::metadata::Timestamp;
+use crate::path::{os_str_bytes, os_string_from_bytes};
+use data_encoding::BASE32_NOPAD;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Separates the name of an encrypted file from the time its kept version was written.
+const VERSION_SEPARATOR: u8 = b'~';
+
+/// Length of `YYYYMMDDTHHMMSS.nnnnnnnnnZ`.
+const STAMP_LEN: usize = 26;
+
+/// Longest file name most filesystems allow.
+const MAX_NAME_LEN: usize = 255;
+
+/// Keeps the encrypted file, about to be replaced, as a version named after the time it
+/// was written. It's linked, so the file is in place until the replacement is renamed over it.
+pub(crate) fn keep_version(target: &Path) -> io::Result<()> {
+    let written = match fs::metadata(target) {
+        Ok(metadata) => Timestamp::modified(&metadata),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let version = version_path(target, written);
+    if version.exists() {
+        return Ok(());
+    }
+    fs::hard_link(target, &version).or_else(|_| fs::copy(target, &version).map(|_| ()))
+}
+
+/// Path of the version of the encrypted file written at the given time.
+pub fn version_path(target: &Path, written: Timestamp) -> PathBuf {
+    let stamp = humantime::format_rfc3339_nanos(written.to_system_time())
+        .to_string()
+        .replace(['-', ':'], "");
+    let base = version_base(target);
+    let mut name = base.file_name().unwrap_or_default().to_os_string();
+    name.push(char::from(VERSION_SEPARATOR).to_string());
+    name.push(stamp);
+    base.with_file_name(name)
+}
+
+/// Path versions of the encrypted file are named after. Names too long to append the time
+/// to are replaced with their hash.
+pub(crate) fn version_base(target: &Path) -> PathBuf {
+    let name = os_str_bytes(target.file_name().unwrap_or_default());
+    if name.len() + 1 + STAMP_LEN <= MAX_NAME_LEN {
+        return target.to_path_buf();
+    }
+    target.with_file_name(BASE32_NOPAD.encode(&Sha256::digest(name)))
+}
+
+/// Base of the kept version and its write time, `None` for other files. The base is the
+/// encrypted file itself, unless its name was too long.
+pub fn parse_version(path: &Path) -> Option<(PathBuf, Timestamp)> {
+    let name = os_str_bytes(path.file_name()?);
+    let separator = name.iter().rposition(|byte| *byte == VERSION_SEPARATOR)?;
+    let stamp = std::str::from_utf8(&name[separator + 1..]).ok()?;
+    if separator == 0 || stamp.len() != STAMP_LEN || !stamp.is_ascii() {
+        return None;
+    }
+    let stamp = format!(
+        "{}-{}-{}T{}:{}:{}",
+        &stamp[..4],
+        &stamp[4..6],
+        &stamp[6..8],
+        &stamp[9..11],
+        &stamp[11..13],
+        &stamp[13..]
+    );
+    let written = humantime::parse_rfc3339(&stamp).ok()?;
+    let target = path.with_file_name(os_string_from_bytes(name[..separator].to_vec()));
+    Some((target, written.into()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn should_keep_versions() {
+        let tmp = tempfile::TempDir::new().expect("Unable to create temp dir");
+        let target = tmp.path().join("FILE~NAME");
+        keep_version(&target).expect("Unable to skip missing file");
+        fs::write(&target, b"first").expect("Unable to write");
+        let written = Timestamp::modified(&fs::metadata(&target).expect("No metadata"));
+        keep_version(&target).expect("Unable to keep version");
+        fs::write(tmp.path().join("replacement"), b"second").expect("Unable to write");
+        fs::rename(tmp.path().join("replacement"), &target).expect("Unable to rename");
+
+        let version = version_path(&target, written);
+        assert_eq!(b"first".to_vec(), fs::read(&version).expect("No version"));
+        assert_eq!(Some((target.clone(), written)), parse_version(&version));
+        assert_eq!(None, parse_version(&target));
+        assert_eq!(
+            None,
+            parse_version(&tmp.path().join("~20261017T180522.672649123Z"))
+        );
+
+        // Too long to append the time to.
+        let target = tmp.path().join("L".repeat(250));
+        fs::write(&target, b"first").expect("Unable to write");
+        keep_version(&target).expect("Unable to keep version");
+        let version = version_path(&target, written_at(&target));
+        assert!(version.file_name().unwrap().len() <= MAX_NAME_LEN);
+        assert_eq!(b"first".to_vec(), fs::read(&version).expect("No version"));
+        assert_eq!(
+            Some((version_base(&target), written_at(&target))),
+            parse_version(&version)
+        );
+    }
+
+    fn written_at(path: &Path) -> Timestamp {
+        Timestamp::modified(&fs::metadata(path).expect("No metadata"))
+    }
+}
@@ -0,0 +1,231 @@
+use crate::cipher::{CipherSuite, DataKey};
+use crate::compression::Compression;
+use crate::file::Encoding;
+use crate::index::Index;
+use crate::journal::Journal;
+use crate::mirror::Mirror;
+use crate::names::Names;
+use crate::padding::Padding;
+use crate::repository::Repositories;
+use crate::snapshot::{latest_manifest, read_manifests, Manifest, Snapshot, SnapshotEntry};
+use crate::verify::{verify, Comparison};
+use crate::worker::{
+    canonical_roots, restore, transform, transform_stream, Handler, HandlerKey, Records,
+    SnapshotReader, StreamHandler, Transformed, Verifier,
+};
+use anyhow::Context;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Encrypts and decrypts files into its target directory with the key, RSA keys or a
+/// passphrase. Settings are shared, constructors and settings that need more than the key
+/// differ by the type of the key.
+#[derive(Clone)]
+pub struct KeyHandler<K> {
+    pub(super) key: K,
+    pub(super) target_dir: PathBuf,
+    pub(super) encoding: Encoding,
+    pub(super) names: Arc<Names>,
+    pub(super) repositories: Arc<Repositories>,
+    pub(super) snapshot: Option<Arc<Snapshot>>,
+    pub(super) index: Option<Arc<Index>>,
+    pub(super) journal: Option<Arc<Journal>>,
+    pub(super) mirror: Option<Arc<Mirror>>,
+}
+
+impl<K> KeyHandler<K> {
+    pub(super) fn new(key: K, target_root: &Path) -> anyhow::Result<Self> {
+        let target_dir = target_root
+            .canonicalize()
+            .with_context(|| "Target directory doesn't exist")?;
+        Ok(Self {
+            key,
+            target_dir,
+            encoding: Encoding::default(),
+            names: Arc::default(),
+            repositories: Arc::default(),
+            snapshot: None,
+            index: None,
+            journal: None,
+            mirror: None,
+        })
+    }
+
+    /// Sets the cipher used for content of encrypted files. Decryption reads it from the file.
+    pub fn with_cipher(mut self, cipher: CipherSuite) -> Self {
+        self.encoding.cipher = cipher;
+        self
+    }
+
+    /// Pads content of encrypted files to hide their size. Decryption reads it from the file.
+    pub fn with_padding(mut self, padding: Padding) -> Self {
+        self.encoding.padding = padding;
+        self
+    }
+
+    /// Keeps replaced encrypted files as versions named after the time they were written,
+    /// so files of older snapshots can be restored.
+    pub fn with_versions(mut self) -> Self {
+        self.encoding.versions = true;
+        self
+    }
+
+    /// Compresses content of encrypted files, except for ones compressed already.
+    /// Decryption reads it from the file.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.encoding.compression = compression;
+        self
+    }
+
+    /// Tells unchanged files by their state and content hash kept in the local index file,
+    /// instead of modification times. In `checksum` mode content of every file is hashed.
+    pub fn with_index(mut self, index_file: &Path, checksum: bool) -> anyhow::Result<Self> {
+        self.index = Some(Arc::new(Index::open(
+            index_file,
+            &self.target_dir,
+            checksum,
+        )?));
+        Ok(self)
+    }
+
+    /// Deletes encrypted files in the targets of the roots whose source files are gone, at
+    /// most `max_deletions` of them. They are kept as versions when versions are kept.
+    pub fn with_deletions<P: AsRef<Path>>(
+        mut self,
+        roots: &[P],
+        max_deletions: usize,
+    ) -> anyhow::Result<Self> {
+        let roots = canonical_roots(roots)?;
+        self.mirror = Some(Arc::new(Mirror::new(roots, max_deletions)));
+        Ok(self)
+    }
+}
+
+// Keys are RSA keys or a passphrase, the bound isn't meant to be implemented elsewhere.
+#[allow(private_bounds)]
+impl<K: HandlerKey> KeyHandler<K> {
+    /// Encrypts or decrypts the file into the target directory, as [`Handler::transform`]
+    /// does, without the trait in scope.
+    pub fn transform(&self, path: &Path) -> anyhow::Result<Transformed> {
+        Handler::transform(self, path)
+    }
+
+    /// Records every file of the run in a snapshot, its manifest is written to the target
+    /// directory when finished. The roots are sources of the run.
+    pub fn with_snapshot<P: AsRef<Path>>(mut self, roots: &[P]) -> anyhow::Result<Self> {
+        let roots = canonical_roots(roots)?;
+        let previous = latest_manifest(&self.target_dir, &self.key.holder());
+        self.snapshot = Some(Arc::new(Snapshot::new(&self.target_dir, roots, previous)));
+        Ok(self)
+    }
+
+    /// Encrypts names in the target tree with the key stored there, wrapped by the key of the
+    /// handler. The given key is stored when there is none yet, a new one when not given.
+    pub(super) fn encrypt_names(mut self, key: Option<DataKey>) -> anyhow::Result<Self> {
+        let names = Names::encrypted(&self.target_dir, &self.key.holder(), key)?;
+        self.names = Arc::new(names);
+        Ok(self)
+    }
+
+    /// Stores content in a repository of deduplicated chunks in the target tree, with the key
+    /// stored there like the one of names.
+    pub(super) fn store_in_repository(mut self, key: Option<DataKey>) -> anyhow::Result<Self> {
+        let repositories = Repositories::encrypted(&self.target_dir, &self.key.holder(), key)?;
+        self.repositories = Arc::new(repositories);
+        Ok(self)
+    }
+
+    /// Keeps a journal in the target tree while encrypting, with the key stored there like the
+    /// one of names.
+    pub(super) fn keep_journal(mut self, key: Option<DataKey>) -> anyhow::Result<Self> {
+        let journal = Journal::open(&self.target_dir, &self.key.holder(), key)?;
+        self.journal = Some(Arc::new(journal));
+        Ok(self)
+    }
+}
+
+impl<K: HandlerKey> Handler for KeyHandler<K> {
+    fn transform(&self, path: &Path) -> anyhow::Result<Transformed> {
+        transform(
+            path,
+            &self.target_dir,
+            &self.key.holder(),
+            self.encoding,
+            &self.names,
+            &self.repositories,
+            Records {
+                snapshot: self.snapshot.as_deref(),
+                index: self.index.as_deref(),
+                journal: self.journal.as_deref(),
+            },
+        )
+    }
+
+    fn delete_stale(&self) -> anyhow::Result<Vec<PathBuf>> {
+        match &self.mirror {
+            Some(mirror) => {
+                mirror.delete_stale(&self.target_dir, &self.names, self.encoding.versions)
+            }
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn finish(&self) -> anyhow::Result<()> {
+        self.repositories.finish()?;
+        if let Some(snapshot) = &self.snapshot {
+            snapshot.write(&self.key.holder(), self.encoding.cipher)?;
+        }
+        if let Some(index) = &self.index {
+            index.write()?;
+        }
+        if let Some(journal) = &self.journal {
+            journal.finish()?;
+        }
+        Ok(())
+    }
+}
+
+impl<K: HandlerKey> StreamHandler for KeyHandler<K> {
+    fn transform_stream<R, W>(&self, source: R, target: W) -> anyhow::Result<()>
+    where
+        R: Read + Send,
+        W: Write + Send,
+    {
+        transform_stream(source, target, &self.key.holder(), self.encoding)
+    }
+}
+
+impl<K: HandlerKey> SnapshotReader for KeyHandler<K> {
+    fn snapshots(&self, backup_dir: &Path) -> anyhow::Result<Vec<Manifest>> {
+        read_manifests(backup_dir, &self.key.holder())
+    }
+
+    fn restore(&self, backup_dir: &Path, entry: &SnapshotEntry) -> anyhow::Result<Transformed> {
+        restore(
+            backup_dir,
+            entry,
+            &self.target_dir,
+            &self.key.holder(),
+            &self.repositories,
+        )
+    }
+}
+
+impl<K: HandlerKey> Verifier for KeyHandler<K> {
+    fn verify(
+        &self,
+        backup_dir: &Path,
+        path: &Path,
+        comparison: Option<(Comparison, &Path)>,
+    ) -> anyhow::Result<Transformed> {
+        verify(
+            backup_dir,
+            path,
+            &self.key.holder(),
+            &self.names,
+            &self.repositories,
+            comparison,
+        )
+    }
+}
@@ -1 +1,392 @@
+pub mod handler;
+pub mod pass;
 pub mod rsa;
+
+use crate::cipher::DataKey;
+use crate::file::header::KeySlot;
+use crate::file::metadata::Timestamp;
+use crate::file::{
+    decrypt_stream, encrypt_stream, hash_file, recorded_modified, transform_file, ContentHash,
+    Encoding,
+};
+use crate::index::{FileState, Index, Lookup, Original};
+use crate::journal::{is_journal_file, Journal};
+use crate::names::Names;
+use crate::path::without_root;
+use crate::repository::{is_repository_file, Repositories};
+use crate::snapshot::{is_snapshot_file, locate, Manifest, Snapshot, SnapshotEntry};
+use crate::verify::Comparison;
+use crate::version::{keep_version, parse_version};
+use anyhow::{bail, Context};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+/// Encrypts or decrypts files into its target directory.
+pub trait Handler: Sync {
+    fn transform(&self, path: &Path) -> anyhow::Result<Transformed>;
+
+    /// Deletes target files whose source files are gone once every file is transformed,
+    /// returns paths of the deleted files.
+    fn delete_stale(&self) -> anyhow::Result<Vec<PathBuf>> {
+        Ok(Vec::new())
+    }
+
+    /// Completes the run once every file is transformed.
+    fn finish(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads snapshots of encryption runs kept in a backup directory.
+pub trait SnapshotReader: Handler {
+    /// Manifests of the runs, oldest first.
+    fn snapshots(&self, backup_dir: &Path) -> anyhow::Result<Vec<Manifest>>;
+
+    /// Restores the file into the target directory as it was when the snapshot was taken.
+    fn restore(&self, backup_dir: &Path, entry: &SnapshotEntry) -> anyhow::Result<Transformed>;
+}
+
+/// Encrypts or decrypts streams, without files in the target directory.
+pub trait StreamHandler: Handler {
+    /// Encrypts or decrypts everything read from the source into the target.
+    fn transform_stream<R, W>(&self, source: R, target: W) -> anyhow::Result<()>
+    where
+        R: Read + Send,
+        W: Write + Send;
+}
+
+/// Checks encrypted files of a backup directory decrypt, without writing what they decrypt to.
+pub trait Verifier: Handler {
+    /// Decrypts the encrypted file in memory and compares it with its source file in
+    /// `sources_dir` when told to. Its source is the path of the processed file.
+    fn verify(
+        &self,
+        backup_dir: &Path,
+        path: &Path,
+        comparison: Option<(Comparison, &Path)>,
+    ) -> anyhow::Result<Transformed>;
+}
+
+#[derive(Debug)]
+pub enum Transformed {
+    Skipped,
+    Processed(u64, PathBuf),
+    /// The encrypted file of a moved or copied source was reused instead of encrypting it.
+    Reused(PathBuf),
+}
+
+/// Protects data keys of encrypted files.
+pub(crate) trait KeyHolder: Sync {
+    fn is_encryptor(&self) -> bool;
+
+    fn wrap_key(&self, data_key: &DataKey) -> anyhow::Result<KeySlot>;
+
+    fn unwrap_key(&self, slot: &KeySlot) -> anyhow::Result<DataKey>;
+
+    /// Size of blocks in files written before headers were introduced, if they can be decrypted.
+    fn legacy_block_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn decrypt_block(&self, _bytes: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        bail!("Unable to decrypt files without header")
+    }
+}
+
+impl<H: KeyHolder + ?Sized> KeyHolder for &H {
+    fn is_encryptor(&self) -> bool {
+        (**self).is_encryptor()
+    }
+
+    fn wrap_key(&self, data_key: &DataKey) -> anyhow::Result<KeySlot> {
+        (**self).wrap_key(data_key)
+    }
+
+    fn unwrap_key(&self, slot: &KeySlot) -> anyhow::Result<DataKey> {
+        (**self).unwrap_key(slot)
+    }
+
+    fn legacy_block_len(&self) -> Option<usize> {
+        (**self).legacy_block_len()
+    }
+
+    fn decrypt_block(&self, bytes: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        (**self).decrypt_block(bytes)
+    }
+}
+
+/// Key of a handler, RSA keys or a passphrase, giving the holder of data keys of its files.
+pub(crate) trait HandlerKey: Sync {
+    type Holder<'a>: KeyHolder
+    where
+        Self: 'a;
+
+    fn holder(&self) -> Self::Holder<'_>;
+}
+
+/// Canonical paths of sources of a run.
+pub(crate) fn canonical_roots<P: AsRef<Path>>(roots: &[P]) -> anyhow::Result<Vec<PathBuf>> {
+    roots
+        .iter()
+        .map(|root| root.as_ref().canonicalize())
+        .collect::<io::Result<_>>()
+        .with_context(|| "Source doesn't exist")
+}
+
+/// Where a run records the files it transforms.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Records<'a> {
+    pub(crate) snapshot: Option<&'a Snapshot>,
+    pub(crate) index: Option<&'a Index>,
+    pub(crate) journal: Option<&'a Journal>,
+}
+
+impl Records<'_> {
+    /// Records the source transformed to the target, or found up to date. Its content is
+    /// hashed unless the hash is known, from the transformation, the index or the previous
+    /// snapshot.
+    fn add(
+        &self,
+        source: &Path,
+        target: &Path,
+        lookup: Option<Lookup>,
+        hash: Option<ContentHash>,
+    ) -> anyhow::Result<()> {
+        if self.snapshot.is_none() && lookup.is_none() {
+            return Ok(());
+        }
+        let known = match hash.or_else(|| lookup.and_then(|lookup| lookup.hash())) {
+            Some(hash) => Some(hash),
+            None => match self.snapshot {
+                Some(snapshot) => snapshot.previous_hash(source)?,
+                None => None,
+            },
+        };
+        let hash = match known {
+            Some(hash) => hash,
+            None => {
+                hash_file(source).with_context(|| format!("Unable to hash file {:?}", source))?
+            }
+        };
+        if let (Some(index), Some(lookup)) = (self.index, lookup) {
+            index.add(source, lookup.state(), target, hash)?;
+        }
+        if let Some(snapshot) = self.snapshot {
+            snapshot.add(source, target, hash)?;
+        }
+        Ok(())
+    }
+
+    /// Records the source encrypted to the target in the journal, in the state it had before.
+    fn complete(
+        &self,
+        source: &Path,
+        state: FileState,
+        target: &Path,
+        hash: Option<ContentHash>,
+    ) -> anyhow::Result<()> {
+        match (self.journal, hash) {
+            (Some(journal), Some(hash)) => journal.complete(source, state, target, hash),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Transforms the file into the target directory, unless the target is already up to date.
+/// Encrypted files are told up to date by the journal of an interrupted run or the index,
+/// if given, or else by modification times. They are recorded either way. Decrypted files
+/// are up to date when they have the modification time recorded in the encrypted file.
+pub(crate) fn transform(
+    path: &Path,
+    target_dir: &Path,
+    holder: &dyn KeyHolder,
+    encoding: Encoding,
+    names: &Names,
+    repositories: &Repositories,
+    records: Records<'_>,
+) -> anyhow::Result<Transformed> {
+    let target_path = if holder.is_encryptor() {
+        names.encrypted_path(path, target_dir)?
+    } else if is_repository_file(path)
+        || is_snapshot_file(path)
+        || is_journal_file(path)
+        || parse_version(path).is_some()
+    {
+        return Ok(Transformed::Skipped);
+    } else {
+        match names.decrypted_path(path, target_dir, holder)? {
+            Some(target_path) => target_path,
+            None => return Ok(Transformed::Skipped),
+        }
+    };
+    let completed = match records.journal {
+        Some(journal) if holder.is_encryptor() => journal.completed(path, &target_path)?,
+        _ => None,
+    };
+    let lookup = match (completed, records.index) {
+        (Some(completed), _) => Some(completed),
+        (None, Some(index)) if holder.is_encryptor() => Some(index.lookup(path, &target_path)?),
+        _ => None,
+    };
+    let changed = match lookup {
+        Some(Lookup::Unchanged(_, _)) => false,
+        Some(Lookup::Changed(_)) => true,
+        Some(Lookup::Unknown(_)) | None if holder.is_encryptor() => {
+            is_newer(path, &target_path).unwrap_or(true)
+        }
+        Some(Lookup::Unknown(_)) | None => {
+            !is_decrypted(path, &target_path, holder).unwrap_or(false)
+        }
+    };
+    if let (Some(index), Some(lookup)) = (records.index, lookup) {
+        if changed && !target_path.exists() {
+            if let Some(original) = index.find_original(path, lookup.state())? {
+                if reuse(&original, &target_path, encoding.versions).is_ok() {
+                    records.add(path, &target_path, Some(lookup), Some(original.hash))?;
+                    records.complete(path, lookup.state(), &target_path, Some(original.hash))?;
+                    return Ok(Transformed::Reused(target_path));
+                }
+            }
+        }
+    }
+    if changed {
+        let state = match lookup {
+            Some(lookup) => lookup.state(),
+            None => FileState::of(&fs::metadata(path)?),
+        };
+        let (bytes, hash) = transform_file(
+            path,
+            holder,
+            &target_path,
+            encoding,
+            repositories,
+            records.journal,
+        )?;
+        records.add(path, &target_path, lookup, hash)?;
+        records.complete(path, state, &target_path, hash)?;
+        Ok(Transformed::Processed(bytes, target_path))
+    } else {
+        records.add(path, &target_path, lookup, None)?;
+        Ok(Transformed::Skipped)
+    }
+}
+
+/// Encrypts or decrypts the stream. Names, repositories and snapshots are left out, as
+/// there are no files.
+pub(crate) fn transform_stream<R, W>(
+    source: R,
+    target: W,
+    holder: &dyn KeyHolder,
+    encoding: Encoding,
+) -> anyhow::Result<()>
+where
+    R: Read + Send,
+    W: Write + Send,
+{
+    if holder.is_encryptor() {
+        encrypt_stream(source, holder, encoding, target)
+    } else {
+        decrypt_stream(source, holder, target)
+    }
+}
+
+/// Moves the encrypted file of the original source to the target when the original is gone,
+/// keeping a version of it if versions are kept. Links or copies it otherwise.
+fn reuse(original: &Original, target: &Path, versions: bool) -> io::Result<()> {
+    if fs::symlink_metadata(&original.source).is_ok() {
+        return fs::hard_link(&original.target, target)
+            .or_else(|_| fs::copy(&original.target, target).map(|_| ()));
+    }
+    if versions {
+        keep_version(&original.target)?;
+    }
+    fs::rename(&original.target, target)
+}
+
+/// Decrypts the version of the file recorded in the snapshot, its path in the target
+/// directory is the one of the source.
+pub(crate) fn restore(
+    backup_dir: &Path,
+    entry: &SnapshotEntry,
+    target_dir: &Path,
+    holder: &dyn KeyHolder,
+    repositories: &Repositories,
+) -> anyhow::Result<Transformed> {
+    let source = locate(backup_dir, entry)?;
+    let target_path = target_dir.join(without_root(&entry.path));
+    if let Some(parent) = target_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let (bytes, _) = transform_file(
+        &source,
+        holder,
+        &target_path,
+        Encoding::default(),
+        repositories,
+        None,
+    )?;
+    if hash_file(&target_path)? != entry.hash {
+        bail!("Restored file {:?} doesn't match the snapshot", target_path);
+    }
+    Ok(Transformed::Processed(bytes, target_path))
+}
+
+/// Tells whether the target was decrypted from the encrypted file, by the modification time
+/// it got from it. Files without one are told by modification times, as they get none.
+fn is_decrypted(encrypted: &Path, target: &Path, holder: &dyn KeyHolder) -> anyhow::Result<bool> {
+    let target_metadata = match fs::metadata(target) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    match recorded_modified(encrypted, holder)? {
+        Some(modified) => Ok(Timestamp::modified(&target_metadata) == modified),
+        None => Ok(!is_newer(encrypted, target)?),
+    }
+}
+
+fn is_newer(source: &Path, target: &Path) -> io::Result<bool> {
+    if !target.exists() {
+        Ok(true)
+    } else {
+        let source_time = source.metadata()?.modified()?;
+        let target_time = target.metadata()?.modified()?;
+        Ok(source_time > target_time)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::write;
+    use std::thread::sleep;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    #[test]
+    fn should_check_for_newer() {
+        let tmp = TempDir::new().expect("Unable to create TempDir");
+        let first_path = tmp.path().join("first");
+        write(&first_path, vec![1; 1024]).expect("Unable to write");
+
+        sleep(Duration::from_secs(2));
+        let second_path = tmp.path().join("second");
+        write(&second_path, vec![1; 1024]).expect("Unable to write");
+        let check = is_newer(&first_path, &second_path);
+        assert!(check.is_ok());
+        assert!(!check.unwrap());
+
+        let check = is_newer(&second_path, &first_path);
+        assert!(check.is_ok());
+        assert!(check.unwrap());
+
+        let does_not_exist = PathBuf::from("does").join("not").join("exist");
+        let check = is_newer(&does_not_exist, &first_path);
+        assert!(check.is_err());
+
+        let check = is_newer(&first_path, &does_not_exist);
+        assert!(check.is_ok());
+        assert!(check.unwrap());
+    }
+}
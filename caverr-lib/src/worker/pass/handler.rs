@@ -0,0 +1,35 @@
+use crate::worker::handler::KeyHandler;
+use crate::worker::pass::holder::PassKey;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Encrypts and decrypts files with a passphrase, without RSA keys.
+pub type PassHandler = KeyHandler<Arc<PassKey>>;
+
+impl PassHandler {
+    pub fn encryptor(passphrase: &str, target_root: &Path) -> anyhow::Result<Self> {
+        Self::new(Arc::new(PassKey::encryption(passphrase)?), target_root)
+    }
+
+    pub fn decryptor(passphrase: &str, target_root: &Path) -> anyhow::Result<Self> {
+        Self::new(Arc::new(PassKey::decryption(passphrase)), target_root)
+    }
+
+    /// Encrypts names in the target tree with a key stored there, protected by the passphrase.
+    pub fn with_encrypted_names(self) -> anyhow::Result<Self> {
+        self.encrypt_names(None)
+    }
+
+    /// Stores content in a repository of deduplicated chunks in the target tree,
+    /// with a key stored there, protected by the passphrase.
+    pub fn with_repository(self) -> anyhow::Result<Self> {
+        self.store_in_repository(None)
+    }
+
+    /// Keeps a journal in the target tree while encrypting, so a run started after an
+    /// interrupted one resumes where it stopped, with a key stored there, protected by the
+    /// passphrase. The journal is removed once the run is finished.
+    pub fn with_journal(self) -> anyhow::Result<Self> {
+        self.keep_journal(None)
+    }
+}
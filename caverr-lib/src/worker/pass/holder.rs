@@ -0,0 +1,152 @@
+use crate::cipher::DataKey;
+use crate::file::header::{
+    KeySlot, PassphraseSlot, MAX_ITERATIONS, MAX_MEMORY_KIB, MAX_PARALLELISM, SALT_SIZE,
+};
+use crate::worker::pass::{ITERATIONS, MEMORY_KIB, PARALLELISM};
+use crate::worker::{HandlerKey, KeyHolder};
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use anyhow::{anyhow, bail, Context};
+use argon2::{Algorithm, Argon2, Params, Version};
+use rand::{thread_rng, RngCore};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+const NONCE_SIZE: usize = 12;
+
+/// Salt and cost of deriving a key from the passphrase.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct KdfParams {
+    salt: [u8; SALT_SIZE],
+    memory_kib: u32,
+    iterations: u32,
+    parallelism: u32,
+}
+
+impl KdfParams {
+    fn generate() -> Self {
+        let mut salt = [0u8; SALT_SIZE];
+        thread_rng().fill_bytes(&mut salt);
+        Self {
+            salt,
+            memory_kib: MEMORY_KIB,
+            iterations: ITERATIONS,
+            parallelism: PARALLELISM,
+        }
+    }
+
+    fn of(slot: &PassphraseSlot) -> Self {
+        Self {
+            salt: slot.salt,
+            memory_kib: slot.memory_kib,
+            iterations: slot.iterations,
+            parallelism: slot.parallelism,
+        }
+    }
+
+    /// Derives the key encrypting data keys with Argon2id, unless it would cost more than
+    /// headers are allowed to ask for.
+    fn derive(&self, passphrase: &str) -> anyhow::Result<Aes256Gcm> {
+        if self.memory_kib > MAX_MEMORY_KIB
+            || self.iterations > MAX_ITERATIONS
+            || self.parallelism > MAX_PARALLELISM
+        {
+            bail!("Key derivation parameters are too costly");
+        }
+        let params = Params::new(self.memory_kib, self.iterations, self.parallelism, None)
+            .map_err(|e| anyhow!("Invalid key derivation parameters: {}", e))?;
+        let mut key = [0u8; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &self.salt, &mut key)
+            .map_err(|e| anyhow!("Unable to derive key from passphrase: {}", e))?;
+        Ok(Aes256Gcm::new(&key.into()))
+    }
+}
+
+/// Passphrase protecting data keys. Encryption derives a single key with a random salt
+/// for all files, unwrapping derives a key once for every salt it finds.
+pub struct PassKey {
+    passphrase: String,
+    encryption: Option<(KdfParams, Aes256Gcm)>,
+    derived: Mutex<HashMap<KdfParams, Arc<Aes256Gcm>>>,
+}
+
+impl PassKey {
+    pub fn encryption(passphrase: &str) -> anyhow::Result<Self> {
+        let params = KdfParams::generate();
+        let key = params.derive(passphrase)?;
+        Ok(Self {
+            encryption: Some((params, key)),
+            ..Self::decryption(passphrase)
+        })
+    }
+
+    pub fn decryption(passphrase: &str) -> Self {
+        Self {
+            passphrase: passphrase.to_string(),
+            encryption: None,
+            derived: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl HandlerKey for Arc<PassKey> {
+    type Holder<'a> = &'a PassKey;
+
+    fn holder(&self) -> &PassKey {
+        self
+    }
+}
+
+impl KeyHolder for PassKey {
+    fn is_encryptor(&self) -> bool {
+        self.encryption.is_some()
+    }
+
+    fn wrap_key(&self, data_key: &DataKey) -> anyhow::Result<KeySlot> {
+        let (params, key) = match &self.encryption {
+            Some((params, key)) => (params, key),
+            None => bail!("Unable to wrap data key when decrypting"),
+        };
+        let mut nonce = [0u8; NONCE_SIZE];
+        thread_rng().fill_bytes(&mut nonce);
+        let encrypted = key
+            .encrypt(Nonce::from_slice(&nonce), data_key.as_bytes().as_ref())
+            .map_err(|_| anyhow!("Unable to wrap data key"))?;
+        Ok(KeySlot::Passphrase(PassphraseSlot {
+            salt: params.salt,
+            memory_kib: params.memory_kib,
+            iterations: params.iterations,
+            parallelism: params.parallelism,
+            wrapped_key: [&nonce[..], &encrypted].concat(),
+        }))
+    }
+
+    fn unwrap_key(&self, slot: &KeySlot) -> anyhow::Result<DataKey> {
+        let slot = match slot {
+            KeySlot::Passphrase(slot) => slot,
+            KeySlot::Recipients(_) => bail!("File was encrypted with a key, not a passphrase"),
+        };
+        if slot.wrapped_key.len() < NONCE_SIZE {
+            bail!("Wrapped data key is too short");
+        }
+        let params = KdfParams::of(slot);
+        let key = {
+            let mut derived = self.derived.lock().unwrap();
+            match derived.get(&params) {
+                Some(key) => key.clone(),
+                None => {
+                    let key = Arc::new(params.derive(&self.passphrase)?);
+                    derived.insert(params, key.clone());
+                    key
+                }
+            }
+        };
+        let (nonce, encrypted) = slot.wrapped_key.split_at(NONCE_SIZE);
+        let data_key = key
+            .decrypt(Nonce::from_slice(nonce), encrypted)
+            .map_err(|_| anyhow!("Invalid data key"))
+            .with_context(|| "Unable to unwrap data key, wrong passphrase?")?;
+        Ok(DataKey::from_bytes(&data_key)?)
+    }
+}
@@ -0,0 +1,609 @@
+pub mod handler;
+pub mod holder;
+
+/// Argon2id memory cost in KiB.
+pub const MEMORY_KIB: u32 = 64 * 1024;
+pub const ITERATIONS: u32 = 3;
+pub const PARALLELISM: u32 = 4;
+
+#[cfg(test)]
+mod test {
+    use crate::compression::Compression;
+    use crate::file::header::Header;
+    use crate::padding::Padding;
+    use crate::path::without_root;
+    use crate::snapshot::Change;
+    use crate::verify::Comparison;
+    use crate::version::{parse_version, version_path};
+    use crate::worker::pass::handler::PassHandler;
+    use crate::worker::{Handler, SnapshotReader, StreamHandler, Transformed, Verifier};
+    use rand::{thread_rng, RngCore};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn should_encrypt_with_passphrase() {
+        let test_dir = tempfile::TempDir::new().expect("Unable to create temp dir");
+        let source_dir = test_dir.path().join("source");
+        fs::create_dir_all(&source_dir).expect("Unable to create source_dir");
+        let originals: Vec<Vec<u8>> = (0..3).map(|i| vec![i; 100_000 * i as usize]).collect();
+        for (i, original) in originals.iter().enumerate() {
+            fs::write(source_dir.join(i.to_string()), original).expect("Unable to write file");
+        }
+
+        let encrypted_dir = test_dir.path().join("encrypted");
+        fs::create_dir_all(&encrypted_dir).expect("Unable to create encrypted_dir");
+        let encryptor =
+            PassHandler::encryptor("secret", &encrypted_dir).expect("Unable to create encryptor");
+        let mut encrypted_paths = Vec::new();
+        for i in 0..originals.len() {
+            match encryptor.transform(&source_dir.join(i.to_string())) {
+                Ok(Transformed::Processed(_, path)) => encrypted_paths.push(path),
+                _ => panic!("Result is not 'processed'"),
+            }
+        }
+
+        let decrypted_dir = test_dir.path().join("decrypted");
+        fs::create_dir_all(&decrypted_dir).expect("Unable to create decrypted_dir");
+        let wrong = PassHandler::decryptor("wrong", &decrypted_dir).expect("No decryptor");
+        assert!(wrong.transform(&encrypted_paths[0]).is_err());
+        let decryptor = PassHandler::decryptor("secret", &decrypted_dir).expect("No decryptor");
+        for (original, encrypted_path) in originals.iter().zip(&encrypted_paths) {
+            let decrypted_path = match decryptor.transform(encrypted_path) {
+                Ok(Transformed::Processed(_, path)) => path,
+                _ => panic!("Result is not 'processed'"),
+            };
+            let decrypted = fs::read(decrypted_path).expect("Unable to read decrypted file");
+            assert_eq!(original, &decrypted);
+        }
+
+        // Decrypted files keep modification times of their sources, they are still up to date.
+        for encrypted_path in &encrypted_paths {
+            let result = decryptor.transform(encrypted_path);
+            assert!(matches!(result, Ok(Transformed::Skipped)));
+        }
+        thread::sleep(Duration::from_millis(10));
+        fs::write(source_dir.join("1"), b"changed").expect("Unable to write file");
+        let encrypted_path = match encryptor.transform(&source_dir.join("1")) {
+            Ok(Transformed::Processed(_, path)) => path,
+            _ => panic!("Result is not 'processed'"),
+        };
+        let decrypted_path = match decryptor.transform(&encrypted_path) {
+            Ok(Transformed::Processed(_, path)) => path,
+            _ => panic!("Result is not 'processed'"),
+        };
+        assert_eq!(
+            b"changed".to_vec(),
+            fs::read(decrypted_path).expect("No file")
+        );
+    }
+
+    #[test]
+    fn should_hide_size_with_padding() {
+        let test_dir = tempfile::TempDir::new().expect("Unable to create temp dir");
+        let encrypted_dir = test_dir.path().join("encrypted");
+        let decrypted_dir = test_dir.path().join("decrypted");
+        fs::create_dir_all(&encrypted_dir).expect("Unable to create encrypted_dir");
+        fs::create_dir_all(&decrypted_dir).expect("Unable to create decrypted_dir");
+        let encryptor = PassHandler::encryptor("secret", &encrypted_dir)
+            .expect("Unable to create encryptor")
+            .with_padding(Padding::Padme);
+        let decryptor = PassHandler::decryptor("secret", &decrypted_dir).expect("No decryptor");
+        let mut encrypted_lens = Vec::new();
+        for (i, len) in [1000, 1010, 200_000].into_iter().enumerate() {
+            let original = vec![i as u8 + 1; len];
+            let source = test_dir.path().join(i.to_string());
+            fs::write(&source, &original).expect("Unable to write file");
+            let encrypted_path = match encryptor.transform(&source) {
+                Ok(Transformed::Processed(_, path)) => path,
+                _ => panic!("Result is not 'processed'"),
+            };
+            encrypted_lens.push(encrypted_path.metadata().expect("No metadata").len());
+            let decrypted_path = match decryptor.transform(&encrypted_path) {
+                Ok(Transformed::Processed(_, path)) => path,
+                _ => panic!("Result is not 'processed'"),
+            };
+            let decrypted = fs::read(decrypted_path).expect("Unable to read decrypted file");
+            assert_eq!(original, decrypted);
+        }
+        assert_eq!(encrypted_lens[0], encrypted_lens[1]);
+        assert!(encrypted_lens[2] > Padding::Padme.padded_len(200_000));
+    }
+
+    #[test]
+    fn should_compress_content() {
+        let test_dir = tempfile::TempDir::new().expect("Unable to create temp dir");
+        let encrypted_dir = test_dir.path().join("encrypted");
+        let decrypted_dir = test_dir.path().join("decrypted");
+        fs::create_dir_all(&encrypted_dir).expect("Unable to create encrypted_dir");
+        fs::create_dir_all(&decrypted_dir).expect("Unable to create decrypted_dir");
+        let decryptor = PassHandler::decryptor("secret", &decrypted_dir).expect("No decryptor");
+        let original = b"caverr compresses text\n".repeat(10_000);
+        for padding in [Padding::None, Padding::PowerOfTwo] {
+            let encryptor = PassHandler::encryptor("secret", &encrypted_dir)
+                .expect("Unable to create encryptor")
+                .with_compression(Compression::Zstd)
+                .with_padding(padding);
+            for name in ["log.txt", "photo.JPG"] {
+                let source = test_dir.path().join(name);
+                fs::write(&source, &original).expect("Unable to write file");
+                let encrypted_path = match encryptor.transform(&source) {
+                    Ok(Transformed::Processed(_, path)) => path,
+                    _ => panic!("Result is not 'processed'"),
+                };
+                let encrypted = fs::read(&encrypted_path).expect("Unable to read encrypted file");
+                let header = Header::read(&mut encrypted.as_slice()).expect("No header");
+                if name == "log.txt" {
+                    assert_eq!(header.compression, Compression::Zstd);
+                    assert!(encrypted.len() < original.len() / 10);
+                } else {
+                    assert_eq!(header.compression, Compression::None);
+                    assert!(encrypted.len() > original.len());
+                }
+                let decrypted_path = match decryptor.transform(&encrypted_path) {
+                    Ok(Transformed::Processed(_, path)) => path,
+                    _ => panic!("Result is not 'processed'"),
+                };
+                let decrypted = fs::read(decrypted_path).expect("Unable to read decrypted file");
+                assert_eq!(original, decrypted);
+            }
+        }
+    }
+
+    #[test]
+    fn should_encrypt_names() {
+        let test_dir = tempfile::TempDir::new().expect("Unable to create temp dir");
+        let source_dir = test_dir.path().join("secret dir");
+        let long_name = "long ".repeat(40);
+        fs::create_dir_all(source_dir.join(&long_name)).expect("Unable to create source_dir");
+        let sources = [
+            source_dir.join("a.txt"),
+            source_dir.join(&long_name).join("b"),
+        ];
+        for (i, source) in sources.iter().enumerate() {
+            fs::write(source, vec![i as u8; 1000]).expect("Unable to write file");
+        }
+
+        let encrypted_dir = test_dir.path().join("encrypted");
+        fs::create_dir_all(&encrypted_dir).expect("Unable to create encrypted_dir");
+        let encryptor = PassHandler::encryptor("secret", &encrypted_dir)
+            .and_then(PassHandler::with_encrypted_names)
+            .expect("Unable to create encryptor");
+        for source in &sources {
+            let result = encryptor.transform(source);
+            assert!(matches!(result, Ok(Transformed::Processed(_, _))));
+        }
+        let encrypted = files(&encrypted_dir);
+        for path in &encrypted {
+            let path = path
+                .strip_prefix(&encrypted_dir)
+                .expect("Outside of target");
+            let path = path.to_string_lossy();
+            assert!(!path.contains("secret") && !path.contains("a.txt") && !path.contains("long "));
+        }
+
+        // Names are the same for the next run, so files are up to date.
+        let encryptor = PassHandler::encryptor("secret", &encrypted_dir)
+            .and_then(PassHandler::with_encrypted_names)
+            .expect("Unable to create encryptor");
+        for source in &sources {
+            let result = encryptor.transform(source);
+            assert!(matches!(result, Ok(Transformed::Skipped)));
+        }
+        assert!(PassHandler::encryptor("wrong", &encrypted_dir)
+            .and_then(PassHandler::with_encrypted_names)
+            .is_err());
+
+        let decrypted_dir = test_dir.path().join("decrypted");
+        fs::create_dir_all(&decrypted_dir).expect("Unable to create decrypted_dir");
+        let decryptor = PassHandler::decryptor("secret", &decrypted_dir).expect("No decryptor");
+        for path in encrypted {
+            decryptor.transform(&path).expect("Unable to decrypt");
+        }
+        let root = encrypted_dir.canonicalize().expect("No encrypted_dir");
+        let root = without_root(&root);
+        for (i, source) in sources.iter().enumerate() {
+            let source = source.canonicalize().expect("No source");
+            let decrypted_path = decrypted_dir.join(&root).join(without_root(&source));
+            let decrypted = fs::read(decrypted_path).expect("Unable to read decrypted file");
+            assert_eq!(vec![i as u8; 1000], decrypted);
+        }
+    }
+
+    #[test]
+    fn should_store_changes_in_repository() {
+        let test_dir = tempfile::TempDir::new().expect("Unable to create temp dir");
+        let source_dir = test_dir.path().join("source");
+        fs::create_dir_all(&source_dir).expect("Unable to create source_dir");
+        let source = source_dir.join("large");
+        let mut original = vec![0u8; 8 * 1024 * 1024];
+        thread_rng().fill_bytes(&mut original);
+        fs::write(&source, &original).expect("Unable to write file");
+
+        let encrypted_dir = test_dir.path().join("encrypted");
+        fs::create_dir_all(&encrypted_dir).expect("Unable to create encrypted_dir");
+        let encryptor = || {
+            PassHandler::encryptor("secret", &encrypted_dir)
+                .and_then(PassHandler::with_repository)
+                .expect("Unable to create encryptor")
+                .with_padding(Padding::Padme)
+        };
+        let encrypt = || {
+            let encryptor = encryptor();
+            let result = encryptor.transform(&source);
+            assert!(matches!(result, Ok(Transformed::Processed(_, _))));
+            encryptor.finish().expect("Unable to finish");
+        };
+        encrypt();
+        let stored = files_len(&encrypted_dir);
+        assert!(stored > original.len() as u64);
+
+        // Modification times are coarse, the change has to be newer than the encrypted file.
+        thread::sleep(Duration::from_millis(50));
+        original[5 * 1024 * 1024] ^= 1;
+        original.extend_from_slice(b"appended");
+        fs::write(&source, &original).expect("Unable to write file");
+        encrypt();
+        assert!(files_len(&encrypted_dir) - stored < 2 * 4 * 1024 * 1024 + 64 * 1024);
+
+        let decrypted_dir = test_dir.path().join("decrypted");
+        fs::create_dir_all(&decrypted_dir).expect("Unable to create decrypted_dir");
+        let decryptor = PassHandler::decryptor("secret", &decrypted_dir).expect("No decryptor");
+        let mut decrypted = Vec::new();
+        for path in files(&encrypted_dir) {
+            if let Transformed::Processed(_, path) =
+                decryptor.transform(&path).expect("Unable to decrypt")
+            {
+                decrypted.push(path);
+            }
+        }
+        assert_eq!(1, decrypted.len());
+        let content = fs::read(&decrypted[0]).expect("Unable to read decrypted file");
+        assert!(content == original);
+    }
+
+    #[test]
+    fn should_restore_snapshot() {
+        let test_dir = tempfile::TempDir::new().expect("Unable to create temp dir");
+        let source_dir = test_dir.path().join("source");
+        fs::create_dir_all(&source_dir).expect("Unable to create source_dir");
+        let sources = [source_dir.join("a"), source_dir.join("b")];
+        for source in &sources {
+            fs::write(source, b"first").expect("Unable to write file");
+        }
+        let encrypted_dir = test_dir.path().join("encrypted");
+        fs::create_dir_all(&encrypted_dir).expect("Unable to create encrypted_dir");
+        let encrypt = || {
+            let encryptor = PassHandler::encryptor("secret", &encrypted_dir)
+                .and_then(|encryptor| encryptor.with_versions().with_snapshot(&[&source_dir]))
+                .expect("Unable to create encryptor");
+            for source in &sources {
+                encryptor.transform(source).expect("Unable to encrypt");
+            }
+            encryptor.finish().expect("Unable to write snapshot");
+        };
+        encrypt();
+        thread::sleep(Duration::from_millis(50));
+        fs::write(&sources[1], b"second").expect("Unable to write file");
+        encrypt();
+
+        let decrypted_dir = test_dir.path().join("decrypted");
+        fs::create_dir_all(&decrypted_dir).expect("Unable to create decrypted_dir");
+        let decryptor = PassHandler::decryptor("secret", &decrypted_dir).expect("No decryptor");
+        let manifests = decryptor
+            .snapshots(&encrypted_dir)
+            .expect("Unable to read snapshots");
+        assert_eq!(2, manifests.len());
+        let source_dir = source_dir.canonicalize().expect("No source_dir");
+        assert_eq!(vec![source_dir.clone()], manifests[0].roots);
+        assert_eq!(
+            vec![Change::Modified(source_dir.join("b"))],
+            manifests[0].diff(&manifests[1])
+        );
+        let decrypted = |name| {
+            let path = decrypted_dir.join(without_root(&source_dir)).join(name);
+            fs::read(path).expect("Unable to read decrypted file")
+        };
+        for entry in &manifests[1].entries {
+            decryptor
+                .restore(&encrypted_dir, entry)
+                .expect("Unable to restore");
+        }
+        assert_eq!(b"first".to_vec(), decrypted("a"));
+        assert_eq!(b"second".to_vec(), decrypted("b"));
+
+        // The encrypted file from the first snapshot was replaced since, but kept as a version.
+        for entry in &manifests[0].entries {
+            decryptor
+                .restore(&encrypted_dir, entry)
+                .expect("Unable to restore");
+        }
+        assert_eq!(b"first".to_vec(), decrypted("a"));
+        assert_eq!(b"first".to_vec(), decrypted("b"));
+        let replaced = &manifests[0].entries[1];
+        let version = version_path(&encrypted_dir.join(&replaced.target), replaced.written);
+        assert!(parse_version(&version).is_some());
+        let result = decryptor.transform(&version);
+        assert!(matches!(result, Ok(Transformed::Skipped)));
+        fs::remove_file(version).expect("Unable to remove version");
+        assert!(decryptor.restore(&encrypted_dir, replaced).is_err());
+    }
+
+    #[test]
+    fn should_delete_stale_files() {
+        let test_dir = tempfile::TempDir::new().expect("Unable to create temp dir");
+        let source_dir = test_dir.path().join("source");
+        let long_name = "long ".repeat(40);
+        fs::create_dir_all(source_dir.join(&long_name)).expect("Unable to create source_dir");
+        for encrypted_names in [false, true] {
+            let sources = [
+                source_dir.join("a"),
+                source_dir.join(&long_name).join("b"),
+                source_dir.join("c"),
+            ];
+            for source in &sources {
+                fs::write(source, b"content").expect("Unable to write file");
+            }
+            let encrypted_dir = test_dir
+                .path()
+                .join(format!("encrypted {}", encrypted_names));
+            fs::create_dir_all(&encrypted_dir).expect("Unable to create encrypted_dir");
+            let encrypt = |sources: &[PathBuf]| {
+                let encryptor = PassHandler::encryptor("secret", &encrypted_dir)
+                    .and_then(|encryptor| match encrypted_names {
+                        true => encryptor.with_encrypted_names(),
+                        false => Ok(encryptor),
+                    })
+                    .and_then(|encryptor| {
+                        encryptor.with_versions().with_deletions(&[&source_dir], 1)
+                    })
+                    .expect("Unable to create encryptor");
+                for source in sources {
+                    encryptor.transform(source).expect("Unable to encrypt");
+                }
+                encryptor.delete_stale()
+            };
+            let deleted = encrypt(&sources).expect("Unable to delete");
+            assert!(deleted.is_empty());
+            let encrypted = files(&encrypted_dir);
+
+            fs::remove_file(&sources[1]).expect("Unable to remove source");
+            let deleted = encrypt(&[sources[0].clone(), sources[2].clone()]);
+            let deleted = deleted.expect("Unable to delete");
+            assert_eq!(1, deleted.len());
+            assert!(encrypted.contains(&deleted[0]) && !deleted[0].exists());
+            let kept = files(&encrypted_dir);
+            assert_eq!(encrypted.len(), kept.len());
+            assert!(kept
+                .iter()
+                .any(|path| parse_version(path).is_some_and(|(target, _)| target == deleted[0])));
+
+            // Too many deletions at once, nothing is deleted.
+            for source in [&sources[0], &sources[2]] {
+                fs::remove_file(source).expect("Unable to remove source");
+            }
+            assert!(encrypt(&[]).is_err());
+            assert_eq!(kept, files(&encrypted_dir));
+        }
+    }
+
+    #[test]
+    fn should_reuse_moved_files() {
+        let test_dir = tempfile::TempDir::new().expect("Unable to create temp dir");
+        let source_dir = test_dir.path().join("source");
+        fs::create_dir_all(source_dir.join("moved")).expect("Unable to create source_dir");
+        let original = source_dir.join("a");
+        fs::write(&original, b"content").expect("Unable to write file");
+        let encrypted_dir = test_dir.path().join("encrypted");
+        fs::create_dir_all(&encrypted_dir).expect("Unable to create encrypted_dir");
+        let index_file = test_dir.path().join("index");
+        let encryptor = || {
+            PassHandler::encryptor("secret", &encrypted_dir)
+                .and_then(|encryptor| encryptor.with_versions().with_index(&index_file, false))
+                .expect("Unable to create encryptor")
+        };
+        let encryptor_1 = encryptor();
+        let encrypted = match encryptor_1.transform(&original) {
+            Ok(Transformed::Processed(_, encrypted)) => encrypted,
+            other => panic!("Not encrypted: {:?}", other),
+        };
+        encryptor_1.finish().expect("Unable to write index");
+
+        let moved = source_dir.join("moved").join("b");
+        fs::rename(&original, &moved).expect("Unable to move");
+        let copied = source_dir.join("c");
+        fs::copy(&moved, &copied).expect("Unable to copy");
+        let modified = fs::metadata(&moved)
+            .and_then(|metadata| metadata.modified())
+            .expect("No modification time");
+        fs::File::options()
+            .write(true)
+            .open(&copied)
+            .and_then(|file| file.set_modified(modified))
+            .expect("Unable to set time");
+        let encryptor_2 = encryptor();
+        let mut reused = Vec::new();
+        for source in [&moved, &copied] {
+            match encryptor_2.transform(source) {
+                Ok(Transformed::Reused(target)) => reused.push(target),
+                other => panic!("Not reused: {:?}", other),
+            }
+        }
+        encryptor_2.finish().expect("Unable to write index");
+        assert!(!encrypted.exists());
+        assert_eq!(
+            1,
+            files(&encrypted_dir)
+                .iter()
+                .filter_map(|path| parse_version(path))
+                .count()
+        );
+
+        let decrypted_dir = test_dir.path().join("decrypted");
+        fs::create_dir_all(&decrypted_dir).expect("Unable to create decrypted_dir");
+        let decryptor = PassHandler::decryptor("secret", &decrypted_dir).expect("No decryptor");
+        for target in reused {
+            match decryptor.transform(&target) {
+                Ok(Transformed::Processed(_, decrypted)) => assert_eq!(
+                    b"content".to_vec(),
+                    fs::read(decrypted).expect("Unable to read decrypted file")
+                ),
+                other => panic!("Not decrypted: {:?}", other),
+            }
+        }
+
+        // Changed content isn't reused, even with the same size and modification time.
+        fs::write(&copied, b"CONTENT").expect("Unable to write file");
+        fs::File::options()
+            .write(true)
+            .open(&copied)
+            .and_then(|file| file.set_modified(modified))
+            .expect("Unable to set time");
+        fs::remove_file(encrypted_dir.join(without_root(&copied.canonicalize().expect("No file"))))
+            .expect("Unable to remove encrypted file");
+        assert!(matches!(
+            encryptor().transform(&copied),
+            Ok(Transformed::Processed(_, _))
+        ));
+    }
+
+    #[test]
+    fn should_transform_streams() {
+        let test_dir = tempfile::TempDir::new().expect("Unable to create temp dir");
+        let mut original = vec![0u8; 200_000];
+        thread_rng().fill_bytes(&mut original[..100_000]);
+        let decryptor = PassHandler::decryptor("secret", test_dir.path()).expect("No decryptor");
+        for (padding, compression) in [
+            (Padding::None, Compression::None),
+            (Padding::Padme, Compression::Zstd),
+        ] {
+            let encryptor = PassHandler::encryptor("secret", test_dir.path())
+                .expect("Unable to create encryptor")
+                .with_padding(padding)
+                .with_compression(compression);
+            let mut encrypted = Vec::new();
+            encryptor
+                .transform_stream(original.as_slice(), &mut encrypted)
+                .expect("Unable to encrypt");
+            let mut decrypted = Vec::new();
+            decryptor
+                .transform_stream(encrypted.as_slice(), &mut decrypted)
+                .expect("Unable to decrypt");
+            assert_eq!(original, decrypted);
+
+            // Streams are encrypted files, without a name.
+            let encrypted_path = test_dir.path().join("stream");
+            fs::write(&encrypted_path, &encrypted).expect("Unable to write file");
+            let decrypted_path = match decryptor.transform(&encrypted_path) {
+                Ok(Transformed::Processed(_, path)) => path,
+                other => panic!("Not decrypted: {:?}", other),
+            };
+            assert_eq!(original, fs::read(decrypted_path).expect("Unable to read"));
+        }
+
+        // The length isn't known upfront, so it can't be padded without compression.
+        let encryptor = PassHandler::encryptor("secret", test_dir.path())
+            .expect("Unable to create encryptor")
+            .with_padding(Padding::Padme);
+        assert!(encryptor
+            .transform_stream(original.as_slice(), Vec::new())
+            .is_err());
+    }
+
+    #[test]
+    fn should_verify_backup() {
+        let test_dir = tempfile::TempDir::new().expect("Unable to create temp dir");
+        let source_dir = test_dir.path().join("source");
+        let encrypted_dir = test_dir.path().join("encrypted");
+        fs::create_dir_all(source_dir.join("dir")).expect("Unable to create source_dir");
+        fs::create_dir_all(&encrypted_dir).expect("Unable to create encrypted_dir");
+        let sources = [source_dir.join("a"), source_dir.join("dir").join("b")];
+        for source in &sources {
+            let mut content = vec![0u8; 100_000];
+            thread_rng().fill_bytes(&mut content);
+            fs::write(source, content).expect("Unable to write file");
+        }
+        let encryptor = PassHandler::encryptor("secret", &encrypted_dir)
+            .and_then(|encryptor| encryptor.with_encrypted_names())
+            .and_then(|encryptor| encryptor.with_snapshot(&[&source_dir]))
+            .expect("Unable to create encryptor");
+        for source in &sources {
+            encryptor.transform(source).expect("Unable to encrypt");
+        }
+        encryptor.finish().expect("Unable to finish");
+
+        let verifier = PassHandler::decryptor("secret", &encrypted_dir).expect("No verifier");
+        let verify = |comparison| {
+            let mut verified = Vec::new();
+            for path in files(&encrypted_dir) {
+                match verifier.verify(&encrypted_dir, &path, comparison)? {
+                    Transformed::Processed(_, source) => verified.push(source),
+                    Transformed::Skipped => {}
+                    other => panic!("Not verified: {:?}", other),
+                }
+            }
+            verified.sort();
+            anyhow::Ok(verified)
+        };
+        let canonical: Vec<_> = sources
+            .iter()
+            .map(|source| source.canonicalize().expect("No source"))
+            .collect();
+        let root = Path::new("/");
+        for comparison in [
+            None,
+            Some((Comparison::Bytes, root)),
+            Some((Comparison::Hash, root)),
+        ] {
+            assert_eq!(canonical, verify(comparison).expect("Unable to verify"));
+        }
+        // Nothing was decrypted into the backup.
+        assert!(files(&encrypted_dir)
+            .iter()
+            .all(|path| !path.ends_with("a") && !path.ends_with("b")));
+
+        let mut content = fs::read(&sources[0]).expect("Unable to read");
+        content[50_000] ^= 1;
+        fs::write(&sources[0], content).expect("Unable to write file");
+        assert!(verify(None).is_ok());
+        assert!(verify(Some((Comparison::Bytes, root))).is_err());
+        assert!(verify(Some((Comparison::Hash, root))).is_err());
+
+        let moved = test_dir.path().join("moved");
+        fs::create_dir_all(&moved).expect("Unable to create dir");
+        assert!(verify(Some((Comparison::Bytes, &moved))).is_err());
+
+        // The largest file is the encrypted content of one of the sources.
+        let encrypted = files(&encrypted_dir)
+            .into_iter()
+            .max_by_key(|path| fs::metadata(path).expect("No metadata").len())
+            .expect("No encrypted file");
+        let mut sealed = fs::read(&encrypted).expect("Unable to read");
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        fs::write(&encrypted, sealed).expect("Unable to write file");
+        assert!(verify(None).is_err());
+    }
+
+    fn files_len(dir: &Path) -> u64 {
+        files(dir)
+            .iter()
+            .map(|path| fs::metadata(path).expect("No metadata").len())
+            .sum()
+    }
+
+    fn files(dir: &Path) -> Vec<PathBuf> {
+        let mut found = Vec::new();
+        for entry in fs::read_dir(dir).expect("Unable to read dir") {
+            let path = entry.expect("Unable to read entry").path();
+            if path.is_dir() {
+                found.extend(files(&path));
+            } else {
+                found.push(path);
+            }
+        }
+        found
+    }
+}
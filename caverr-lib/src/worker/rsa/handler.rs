@@ -1,113 +1,219 @@
-use crate::file::file_transform;
-use crate::path::build_relative_path;
+#[cfg(feature = "async")]
+use crate::async_io::{AsyncDecryptingReader, AsyncEncryptingWriter, BlockingPool};
+use crate::cipher::DATA_KEY_SIZE;
+use crate::file::adapter::{DecryptingReader, EncryptingWriter};
+use crate::key_file::read_or_create_key;
+use crate::worker::handler::KeyHandler;
 use crate::worker::rsa::holder::{RsaHolder, RsaKey};
-use anyhow::Context;
+use crate::worker::rsa::keys::{is_encrypted_private_key, is_private_key};
+use anyhow::{bail, Context};
 use rsa::pkcs8::{DecodePrivateKey, DecodePublicKey};
 use rsa::{RsaPrivateKey, RsaPublicKey};
-use std::io;
-use std::path::{Path, PathBuf};
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+#[cfg(feature = "async")]
+use std::path::PathBuf;
+#[cfg(feature = "async")]
+use tokio::io::{AsyncRead, AsyncWrite};
 
-#[derive(Clone)]
-pub struct RsaHandler {
-    key: RsaKey,
-    target_dir: PathBuf,
-}
+/// Kept at the path it had before passphrase handlers shared it.
+pub use crate::worker::Transformed;
+
+const PUBLIC_KEY_BEGIN: &str = "-----BEGIN PUBLIC KEY-----";
+const PUBLIC_KEY_END: &str = "-----END PUBLIC KEY-----";
+
+/// Recipients are counted with a single byte in the file header.
+const MAX_RECIPIENTS: usize = 255;
+
+/// Encrypts files for RSA public keys and decrypts them with a private key.
+pub type RsaHandler = KeyHandler<RsaKey>;
 
 impl RsaHandler {
     pub fn encryptor(public_key_file: &Path, target_root: &Path) -> anyhow::Result<Self> {
-        let target_dir = target_root
-            .canonicalize()
-            .with_context(|| "Target directory doesn't exist")?;
-        let key = Self::prepare_public_key(public_key_file)?;
-        Ok(Self { key, target_dir })
+        Self::encryptor_with_recipients(&[public_key_file], target_root)
+    }
+
+    /// Every file may contain one or more PEM encoded public keys, each of them becomes
+    /// a recipient able to decrypt the files.
+    pub fn encryptor_with_recipients<P: AsRef<Path>>(
+        public_key_files: &[P],
+        target_root: &Path,
+    ) -> anyhow::Result<Self> {
+        Self::new(Self::prepare_public_keys(public_key_files)?, target_root)
     }
 
     pub fn decryptor(private_key_file: &Path, target_root: &Path) -> anyhow::Result<Self> {
-        let target_dir = target_root
-            .canonicalize()
-            .with_context(|| "Target directory doesn't exist")?;
-        let key = Self::prepare_private_key(private_key_file)?;
-        Ok(Self { key, target_dir })
-    }
-
-    pub fn transform(&self, path: &Path) -> anyhow::Result<Transformed> {
-        let target_path = build_relative_path(path, &self.target_dir)?;
-        if is_newer(path, &target_path).unwrap_or(true) {
-            let rsa = RsaHolder::new(&self.key);
-            let bytes = file_transform(path, rsa, &target_path, self.key.message_len())?;
-            Ok(Transformed::Processed(bytes, target_path))
+        Self::new_decryptor(private_key_file, None, target_root)
+    }
+
+    /// Decryptor using a private key stored as encrypted PKCS#8.
+    pub fn decryptor_with_passphrase(
+        private_key_file: &Path,
+        passphrase: &str,
+        target_root: &Path,
+    ) -> anyhow::Result<Self> {
+        Self::new_decryptor(private_key_file, Some(passphrase), target_root)
+    }
+
+    fn new_decryptor(
+        private_key_file: &Path,
+        passphrase: Option<&str>,
+        target_root: &Path,
+    ) -> anyhow::Result<Self> {
+        let key = Self::prepare_private_key(private_key_file, passphrase)?;
+        Self::new(key, target_root)
+    }
+
+    /// Encrypts names in the target tree with the key from the file, created when missing.
+    /// The key is also stored in the tree, so the private key alone decrypts the names.
+    /// Public keys can't unwrap the stored key, so encryption needs the file.
+    pub fn with_encrypted_names(self, names_key_file: &Path) -> anyhow::Result<Self> {
+        self.encrypt_names(Some(read_or_create_key(names_key_file)?))
+    }
+
+    /// Stores content in a repository of deduplicated chunks in the target tree, with the key
+    /// from the file, created when missing. The key is also stored in the repository, like
+    /// the one of names.
+    pub fn with_repository(self, repository_key_file: &Path) -> anyhow::Result<Self> {
+        self.store_in_repository(Some(read_or_create_key(repository_key_file)?))
+    }
+
+    /// Keeps a journal in the target tree while encrypting, so a run started after an
+    /// interrupted one resumes where it stopped, with the key from the file, created when
+    /// missing. The journal is removed once the run is finished, the key is stored in it like
+    /// the one of names.
+    pub fn with_journal(self, journal_key_file: &Path) -> anyhow::Result<Self> {
+        self.keep_journal(Some(read_or_create_key(journal_key_file)?))
+    }
+
+    /// Encrypts everything written to it into the inner writer, as the handler encrypts
+    /// streams. [`EncryptingWriter::finish`] must be called once everything is written.
+    pub fn encrypting_writer<W: Write>(&self, inner: W) -> anyhow::Result<EncryptingWriter<W>> {
+        EncryptingWriter::with_encoding(inner, &RsaHolder::new(&self.key), self.encoding)
+    }
+
+    /// Decrypts the encrypted stream or file read from the inner reader as it's read.
+    pub fn decrypting_reader<R: Read>(&self, inner: R) -> anyhow::Result<DecryptingReader<R>> {
+        DecryptingReader::with_holder(inner, &RsaHolder::new(&self.key))
+    }
+
+    /// Reads either the public keys or the private key found in the file.
+    pub fn read_key(key_file: &Path, passphrase: Option<&str>) -> anyhow::Result<RsaKey> {
+        let private = is_private_key(key_file)
+            .with_context(|| format!("Unable to read key from file {:?}", key_file))?;
+        if private {
+            Self::prepare_private_key(key_file, passphrase)
         } else {
-            Ok(Transformed::Skipped)
+            Self::prepare_public_keys(&[key_file])
         }
     }
 
-    fn prepare_public_key(public_key_file: &Path) -> anyhow::Result<RsaKey> {
-        let public_key =
-            RsaPublicKey::read_public_key_pem_file(public_key_file).with_context(|| {
+    fn prepare_public_keys<P: AsRef<Path>>(public_key_files: &[P]) -> anyhow::Result<RsaKey> {
+        let mut public_keys = Vec::new();
+        for public_key_file in public_key_files {
+            let public_key_file = public_key_file.as_ref();
+            let keys = Self::read_public_keys(public_key_file).with_context(|| {
                 format!("Unable to read public key from file {:?}", public_key_file)
             })?;
-        Ok(RsaKey::PublicKey(public_key))
+            for key in keys {
+                if !public_keys.contains(&key) {
+                    public_keys.push(key);
+                }
+            }
+        }
+        if public_keys.is_empty() {
+            bail!("No public keys given");
+        } else if public_keys.len() > MAX_RECIPIENTS {
+            bail!("Too many public keys, at most {} allowed", MAX_RECIPIENTS);
+        }
+        let key = RsaKey::PublicKeys(public_keys);
+        if key.message_len() < DATA_KEY_SIZE {
+            bail!("Public key is too small to encrypt data keys");
+        }
+        Ok(key)
     }
 
-    fn prepare_private_key(private_key_file: &Path) -> anyhow::Result<RsaKey> {
-        let private_key =
-            RsaPrivateKey::read_pkcs8_pem_file(private_key_file).with_context(|| {
-                format!(
-                    "Unable to read private key from file {:?}",
-                    private_key_file
-                )
-            })?;
+    fn read_public_keys(public_key_file: &Path) -> anyhow::Result<Vec<RsaPublicKey>> {
+        let pem = fs::read_to_string(public_key_file)?;
+        let mut keys = Vec::new();
+        for block in pem.split_inclusive(PUBLIC_KEY_END) {
+            if let Some(start) = block.find(PUBLIC_KEY_BEGIN) {
+                keys.push(RsaPublicKey::from_public_key_pem(&block[start..])?);
+            }
+        }
+        if keys.is_empty() {
+            bail!("No PEM encoded public key found");
+        }
+        Ok(keys)
+    }
+
+    fn prepare_private_key(
+        private_key_file: &Path,
+        passphrase: Option<&str>,
+    ) -> anyhow::Result<RsaKey> {
+        let encrypted = is_encrypted_private_key(private_key_file).with_context(|| {
+            format!(
+                "Unable to read private key from file {:?}",
+                private_key_file
+            )
+        })?;
+        let private_key = match (encrypted, passphrase) {
+            (true, Some(passphrase)) => {
+                let pem = fs::read_to_string(private_key_file)?;
+                RsaPrivateKey::from_pkcs8_encrypted_pem(&pem, passphrase).with_context(|| {
+                    format!(
+                        "Unable to decrypt private key from file {:?}, wrong passphrase?",
+                        private_key_file
+                    )
+                })?
+            }
+            (true, None) => bail!(
+                "Private key in file {:?} is encrypted, passphrase required",
+                private_key_file
+            ),
+            (false, _) => {
+                RsaPrivateKey::read_pkcs8_pem_file(private_key_file).with_context(|| {
+                    format!(
+                        "Unable to read private key from file {:?}",
+                        private_key_file
+                    )
+                })?
+            }
+        };
         Ok(RsaKey::PrivateKey(private_key))
     }
 }
 
-#[derive(Debug)]
-pub enum Transformed {
-    Skipped,
-    Processed(u64, PathBuf),
-}
+#[cfg(feature = "async")]
+impl RsaHandler {
+    /// Encrypts or decrypts the file on a thread of the pool, so the runtime isn't blocked.
+    pub async fn transform_async(
+        &self,
+        pool: &BlockingPool,
+        path: PathBuf,
+    ) -> anyhow::Result<Transformed> {
+        let handler = self.clone();
+        pool.run(move || handler.transform(&path)).await
+    }
 
-fn is_newer(source: &Path, target: &Path) -> io::Result<bool> {
-    if !target.exists() {
-        Ok(true)
-    } else {
-        let source_time = source.metadata()?.modified()?;
-        let target_time = target.metadata()?.modified()?;
-        Ok(source_time > target_time)
+    /// Encrypts everything written to it into the inner writer on a thread of the pool, as
+    /// the handler encrypts streams.
+    pub fn async_encrypting_writer<W>(&self, pool: &BlockingPool, inner: W) -> AsyncEncryptingWriter
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let handler = self.clone();
+        AsyncEncryptingWriter::spawn(pool, inner, move |inner| handler.encrypting_writer(inner))
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use std::fs::write;
-    use std::thread::sleep;
-    use std::time::Duration;
-    use tempfile::TempDir;
-
-    #[test]
-    fn should_check_for_newer() {
-        let tmp = TempDir::new().expect("Unable to create TempDir");
-        let first_path = tmp.path().join("first");
-        write(&first_path, vec![1; 1024]).expect("Unable to write");
-
-        sleep(Duration::from_secs(2));
-        let second_path = tmp.path().join("second");
-        write(&second_path, vec![1; 1024]).expect("Unable to write");
-        let check = is_newer(&first_path, &second_path);
-        assert!(check.is_ok());
-        assert!(!check.unwrap());
-
-        let check = is_newer(&second_path, &first_path);
-        assert!(check.is_ok());
-        assert!(check.unwrap());
-
-        let does_not_exist = PathBuf::from("does").join("not").join("exist");
-        let check = is_newer(&does_not_exist, &first_path);
-        assert!(check.is_err());
-
-        let check = is_newer(&first_path, &does_not_exist);
-        assert!(check.is_ok());
-        assert!(check.unwrap());
+    /// Decrypts the encrypted stream or file read from the inner reader on a thread of the
+    /// pool.
+    pub fn async_decrypting_reader<R>(&self, pool: &BlockingPool, inner: R) -> AsyncDecryptingReader
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        let handler = self.clone();
+        AsyncDecryptingReader::spawn(pool, inner, move |inner| handler.decrypting_reader(inner))
     }
 }
@@ -1,4 +1,9 @@
-use crate::worker::rsa::{DECRYPTION_MESSAGE_SIZE, ENCRYPTION_MESSAGE_SIZE};
+use crate::cipher::DataKey;
+use crate::file::header::{KeySlot, Recipient};
+use crate::worker::rsa::keys::{fingerprint, fingerprint_to_hex, Fingerprint, ShowKeyError};
+use crate::worker::rsa::{decryption_message_len, encryption_message_len};
+use crate::worker::{HandlerKey, KeyHolder};
+use anyhow::{bail, Context};
 use rand::thread_rng;
 use rsa::{PaddingScheme, PublicKey, RsaPrivateKey, RsaPublicKey};
 use sha1::Sha1;
@@ -7,17 +12,35 @@ use sha2::Sha256;
 #[allow(clippy::large_enum_variant)]
 #[derive(Clone)]
 pub enum RsaKey {
-    PublicKey(RsaPublicKey),
+    /// Recipients of encrypted files, any of the matching private keys can decrypt them.
+    PublicKeys(Vec<RsaPublicKey>),
     PrivateKey(RsaPrivateKey),
 }
 
 impl RsaKey {
+    /// Bytes processed per RSA block: longest message fitting every public key,
+    /// or the size of an encrypted block for the private key.
     pub fn message_len(&self) -> usize {
         match self {
-            RsaKey::PublicKey(_) => ENCRYPTION_MESSAGE_SIZE,
-            RsaKey::PrivateKey(_) => DECRYPTION_MESSAGE_SIZE,
+            RsaKey::PublicKeys(keys) => keys.iter().map(encryption_message_len).min().unwrap_or(0),
+            RsaKey::PrivateKey(key) => decryption_message_len(key),
         }
     }
+
+    pub fn fingerprints(&self) -> Result<Vec<Fingerprint>, ShowKeyError> {
+        match self {
+            RsaKey::PublicKeys(keys) => keys.iter().map(fingerprint).collect(),
+            RsaKey::PrivateKey(key) => Ok(vec![fingerprint(&RsaPublicKey::from(key))?]),
+        }
+    }
+}
+
+impl HandlerKey for RsaKey {
+    type Holder<'a> = RsaHolder<'a>;
+
+    fn holder(&self) -> RsaHolder<'_> {
+        RsaHolder::new(self)
+    }
 }
 
 pub struct RsaHolder<'a> {
@@ -30,16 +53,74 @@ impl<'a> RsaHolder<'a> {
     }
 }
 
-impl RsaHolder<'_> {
-    pub(crate) fn work(&self, bytes: Vec<u8>) -> Result<Vec<u8>, rsa::errors::Error> {
+impl KeyHolder for RsaHolder<'_> {
+    fn is_encryptor(&self) -> bool {
+        matches!(self.key, RsaKey::PublicKeys(_))
+    }
+
+    /// Encrypts the data key for every recipient.
+    fn wrap_key(&self, data_key: &DataKey) -> anyhow::Result<KeySlot> {
+        let keys = match &self.key {
+            RsaKey::PublicKeys(keys) => keys,
+            RsaKey::PrivateKey(_) => bail!("Unable to wrap data key with a private key"),
+        };
         let mut rng = thread_rng();
+        let recipients = keys
+            .iter()
+            .map(|key| {
+                Ok(Recipient {
+                    fingerprint: fingerprint(key)?,
+                    wrapped_key: key.encrypt(&mut rng, padding(), data_key.as_bytes())?,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(KeySlot::Recipients(recipients))
+    }
+
+    /// Decrypts the data key wrapped for this private key.
+    fn unwrap_key(&self, slot: &KeySlot) -> anyhow::Result<DataKey> {
+        let key = match &self.key {
+            RsaKey::PrivateKey(key) => key,
+            RsaKey::PublicKeys(_) => bail!("Unable to unwrap data key with a public key"),
+        };
+        let recipients = match slot {
+            KeySlot::Recipients(recipients) => recipients,
+            KeySlot::Passphrase(_) => bail!("File was encrypted with a passphrase, not a key"),
+        };
+        let own = fingerprint(&RsaPublicKey::from(key))?;
+        let recipient = match recipients.iter().find(|r| r.fingerprint == own) {
+            Some(recipient) => recipient,
+            None => {
+                let expected: Vec<String> = recipients
+                    .iter()
+                    .map(|r| fingerprint_to_hex(&r.fingerprint))
+                    .collect();
+                bail!(
+                    "File was encrypted for keys [{}], but the given key is {}",
+                    expected.join(", "),
+                    fingerprint_to_hex(&own)
+                );
+            }
+        };
+        let data_key = key
+            .decrypt(padding(), &recipient.wrapped_key)
+            .with_context(|| "Unable to unwrap data key")?;
+        Ok(DataKey::from_bytes(&data_key)?)
+    }
+
+    fn legacy_block_len(&self) -> Option<usize> {
+        Some(self.key.message_len())
+    }
+
+    /// Decrypts a block of a file written before data keys, when content was encrypted with RSA.
+    fn decrypt_block(&self, bytes: Vec<u8>) -> anyhow::Result<Vec<u8>> {
         match &self.key {
-            RsaKey::PublicKey(key) => Ok(key.encrypt(&mut rng, padding(), &bytes)?),
             RsaKey::PrivateKey(key) => Ok(key.decrypt(padding(), bytes.as_ref())?),
+            RsaKey::PublicKeys(_) => bail!("Unable to decrypt block with a public key"),
         }
     }
 }
 
-fn padding() -> PaddingScheme {
+pub(crate) fn padding() -> PaddingScheme {
     PaddingScheme::new_oaep_with_mgf_hash::<Sha256, Sha1>()
 }
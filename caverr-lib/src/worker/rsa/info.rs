@@ -0,0 +1,64 @@
+use crate::worker::rsa::holder::RsaKey;
+use crate::worker::rsa::keys::{fingerprint, fingerprint_to_hex, Fingerprint, ShowKeyError};
+use rsa::{PublicKeyParts, RsaPublicKey};
+use std::fmt::{Display, Formatter};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyKind {
+    Public,
+    Private,
+}
+
+/// What is known about a key without using it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyInfo {
+    pub kind: KeyKind,
+    /// Size of the modulus.
+    pub bits: usize,
+    pub fingerprint: Fingerprint,
+}
+
+impl KeyInfo {
+    fn new(kind: KeyKind, public_key: &RsaPublicKey) -> Result<Self, ShowKeyError> {
+        Ok(Self {
+            kind,
+            bits: public_key.n().bits(),
+            fingerprint: fingerprint(public_key)?,
+        })
+    }
+
+    /// Tells whether both keys belong to the same key pair.
+    pub fn is_pair_of(&self, other: &KeyInfo) -> bool {
+        self.kind != other.kind && self.fingerprint == other.fingerprint
+    }
+}
+
+impl Display for KeyInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let kind = match self.kind {
+            KeyKind::Public => "public",
+            KeyKind::Private => "private",
+        };
+        write!(
+            f,
+            "RSA {} key, {} bits, SHA256:{}",
+            kind,
+            self.bits,
+            fingerprint_to_hex(&self.fingerprint)
+        )
+    }
+}
+
+/// Describes every key, a file with public keys may contain many of them.
+pub fn key_info(key: &RsaKey) -> Result<Vec<KeyInfo>, ShowKeyError> {
+    match key {
+        RsaKey::PublicKeys(keys) => keys
+            .iter()
+            .map(|key| KeyInfo::new(KeyKind::Public, key))
+            .collect(),
+        RsaKey::PrivateKey(key) => Ok(vec![KeyInfo::new(
+            KeyKind::Private,
+            &RsaPublicKey::from(key),
+        )?]),
+    }
+}
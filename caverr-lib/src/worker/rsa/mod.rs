@@ -1,34 +1,377 @@
 pub mod handler;
 pub mod holder;
+pub mod info;
 pub mod keys;
 
-pub const KEY_BITS: usize = 4096;
-pub const ENCRYPTION_MESSAGE_SIZE: usize = 256;
-pub const DECRYPTION_MESSAGE_SIZE: usize = 512;
+use rsa::PublicKeyParts;
+
+pub const DEFAULT_KEY_BITS: usize = 4096;
+pub const MIN_KEY_BITS: usize = 2048;
+pub const MAX_KEY_BITS: usize = 16384;
+
+/// OAEP with SHA-256 takes two hashes and two bytes of every block.
+const OAEP_OVERHEAD: usize = 2 * 32 + 2;
+
+/// Longest message that fits into a single block encrypted with the key.
+pub fn encryption_message_len<K: PublicKeyParts>(key: &K) -> usize {
+    key.size().saturating_sub(OAEP_OVERHEAD)
+}
+
+/// Size of a block encrypted with the key, i.e. the size of its modulus.
+pub fn decryption_message_len<K: PublicKeyParts>(key: &K) -> usize {
+    key.size()
+}
 
 #[cfg(test)]
 mod test {
-    use crate::worker::rsa::handler::{RsaHandler, Transformed};
-    use crate::worker::rsa::keys::{generate_keys, write_private_key, write_public_key};
+    use crate::cipher::{CipherSuite, TAG_SIZE};
+    use crate::file::file_transform;
+    use crate::file::header::Header;
+    use crate::worker::rsa::handler::RsaHandler;
+    use crate::worker::rsa::holder::{padding, RsaHolder, RsaKey};
+    use crate::worker::rsa::info::{key_info, KeyKind};
+    use crate::worker::rsa::keys::{
+        fingerprint, generate_keys, is_encrypted_private_key, write_encrypted_private_key,
+        write_keys_to_dir, write_private_key, write_public_key, ShowKeyError, PRIVATE_KEY_FILE,
+        PUBLIC_KEY_FILE,
+    };
+    use crate::worker::rsa::{decryption_message_len, encryption_message_len, DEFAULT_KEY_BITS};
+    use crate::worker::Transformed;
     use rand::thread_rng;
     use rand::RngCore;
+    use rsa::PublicKey;
     use std::fs;
     use std::fs::File;
     use std::io::Write;
     use std::time::Instant;
+    #[cfg(unix)]
+    use std::{fs::Permissions, os::unix::fs::PermissionsExt};
 
     #[test]
     fn should_encrypt_file() {
-        test_file(16 * 1024);
+        test_file(16 * 1024, CipherSuite::Aes256Gcm, DEFAULT_KEY_BITS);
+    }
+
+    #[test]
+    fn should_encrypt_multi_chunk_file_with_chacha() {
+        test_file(200 * 1024, CipherSuite::ChaCha20Poly1305, DEFAULT_KEY_BITS);
+    }
+
+    #[test]
+    fn should_encrypt_with_any_key_size() {
+        for bits in [2048, 3072] {
+            let (private_key, _) = generate_keys(bits).expect("Unable to create keys");
+            assert_eq!(decryption_message_len(&private_key), bits / 8);
+            assert_eq!(encryption_message_len(&private_key), bits / 8 - 66);
+            test_file(1000, CipherSuite::Aes256Gcm, bits);
+        }
+    }
+
+    #[test]
+    fn should_transform_file_with_holder() {
+        let (private_key, public_key) =
+            generate_keys(DEFAULT_KEY_BITS).expect("Unable to create keys");
+        let test_dir = tempfile::TempDir::new().expect("Unable to create temp dir");
+        let original = content(100_000);
+        let source = test_dir.path().join("source");
+        fs::write(&source, &original).expect("Unable to write");
+        let encrypted = test_dir.path().join("encrypted");
+        let decrypted = test_dir.path().join("decrypted");
+
+        let public_key = RsaKey::PublicKeys(vec![public_key]);
+        let bytes = file_transform(&source, RsaHolder::new(&public_key), &encrypted, 0)
+            .expect("Unable to encrypt");
+        assert_eq!(original.len() as u64, bytes);
+        let private_key = RsaKey::PrivateKey(private_key);
+        file_transform(&encrypted, RsaHolder::new(&private_key), &decrypted, 0)
+            .expect("Unable to decrypt");
+        assert_eq!(original, fs::read(&decrypted).expect("Unable to read"));
+    }
+
+    #[test]
+    fn should_decrypt_legacy_file() {
+        let (private_key, public_key) =
+            generate_keys(DEFAULT_KEY_BITS).expect("Unable to create keys");
+        let test_dir = tempfile::TempDir::new().expect("Unable to create temp dir");
+        let private_key_path = test_dir.path().join("private.key");
+        let mut private_key_file = File::create(&private_key_path).expect("Unable to create file");
+        write_private_key(&mut private_key_file, private_key).expect("Unable to write private key");
+        private_key_file.flush().expect("Unable to flush file");
+
+        // Raw RSA blocks, as written before data keys were introduced.
+        let original = content(1000);
+        let mut legacy = Vec::new();
+        for block in original.chunks(256) {
+            let encrypted = public_key
+                .encrypt(&mut thread_rng(), padding(), block)
+                .expect("Unable to encrypt block");
+            legacy.extend(encrypted);
+        }
+        let legacy_path = test_dir.path().join("legacy");
+        fs::write(&legacy_path, legacy).expect("Unable to write legacy file");
+
+        let decrypted_target_dir = test_dir.path().join("decrypted");
+        fs::create_dir_all(&decrypted_target_dir).expect("Unable to create decrypted_target_dir");
+        let decryptor = RsaHandler::decryptor(&private_key_path, &decrypted_target_dir)
+            .expect("Unable to create decryptor");
+        let result = decryptor
+            .transform(&legacy_path)
+            .expect("unable to transform");
+        let decrypted_path = if let Transformed::Processed(_, path) = result {
+            path
+        } else {
+            panic!("Result is not 'processed'");
+        };
+        let decrypted_content = fs::read(&decrypted_path).expect("Unable to read decrypted file");
+        assert_eq!(original, decrypted_content);
+    }
+
+    #[test]
+    fn should_read_encrypted_private_key() {
+        let (private_key, public_key) =
+            generate_keys(DEFAULT_KEY_BITS).expect("Unable to create keys");
+        let test_dir = tempfile::TempDir::new().expect("Unable to create temp dir");
+        let public_key_path = test_dir.path().join("public.key");
+        let mut public_key_file = File::create(&public_key_path).expect("Unable to create file");
+        write_public_key(&mut public_key_file, public_key).expect("Unable to write public key");
+        let private_key_path = test_dir.path().join("private.key");
+        let mut private_key_file = File::create(&private_key_path).expect("Unable to create file");
+        write_encrypted_private_key(&mut private_key_file, private_key, "secret")
+            .expect("Unable to write private key");
+        private_key_file.flush().expect("Unable to flush file");
+        assert!(is_encrypted_private_key(&private_key_path).expect("Unable to read key"));
+        assert!(!is_encrypted_private_key(&public_key_path).expect("Unable to read key"));
+
+        let original = content(1000);
+        let original_path = test_dir.path().join("original");
+        fs::write(&original_path, &original).expect("Unable to write file");
+        let encrypted_dir = test_dir.path().join("encrypted");
+        fs::create_dir_all(&encrypted_dir).expect("Unable to create encrypted_dir");
+        let encryptor = RsaHandler::encryptor(&public_key_path, &encrypted_dir)
+            .expect("Unable to create encryptor");
+        let encrypted_path = match encryptor.transform(&original_path) {
+            Ok(Transformed::Processed(_, path)) => path,
+            _ => panic!("Result is not 'processed'"),
+        };
+
+        let decrypted_dir = test_dir.path().join("decrypted");
+        fs::create_dir_all(&decrypted_dir).expect("Unable to create decrypted_dir");
+        assert!(RsaHandler::decryptor(&private_key_path, &decrypted_dir).is_err());
+        assert!(
+            RsaHandler::decryptor_with_passphrase(&private_key_path, "wrong", &decrypted_dir)
+                .is_err()
+        );
+        let decryptor =
+            RsaHandler::decryptor_with_passphrase(&private_key_path, "secret", &decrypted_dir)
+                .expect("Unable to create decryptor");
+        let decrypted_path = match decryptor.transform(&encrypted_path) {
+            Ok(Transformed::Processed(_, path)) => path,
+            _ => panic!("Result is not 'processed'"),
+        };
+        let decrypted = fs::read(decrypted_path).expect("Unable to read decrypted file");
+        assert_eq!(original, decrypted);
+    }
+
+    #[test]
+    fn should_decrypt_with_any_recipient_key() {
+        let test_dir = tempfile::TempDir::new().expect("Unable to create temp dir");
+        let mut private_key_paths = Vec::new();
+        let mut bundle = Vec::new();
+        let mut other_public_key_path = None;
+        for i in 0..3 {
+            let (private_key, public_key) =
+                generate_keys(DEFAULT_KEY_BITS).expect("Unable to create keys");
+            let private_key_path = test_dir.path().join(format!("private{}.key", i));
+            let mut private_key_file =
+                File::create(&private_key_path).expect("Unable to create file");
+            write_private_key(&mut private_key_file, private_key)
+                .expect("Unable to write private key");
+            private_key_paths.push(private_key_path);
+            if i == 2 {
+                let public_key_path = test_dir.path().join("other.key");
+                let mut file = File::create(&public_key_path).expect("Unable to create file");
+                write_public_key(&mut file, public_key).expect("Unable to write public key");
+                other_public_key_path = Some(public_key_path);
+            } else {
+                write_public_key(&mut bundle, public_key).expect("Unable to write public key");
+            }
+        }
+        // First two keys in a single recipients file, the third one given separately.
+        let bundle_path = test_dir.path().join("recipients.pem");
+        fs::write(&bundle_path, bundle).expect("Unable to write recipients");
+        let public_key_paths = [bundle_path, other_public_key_path.unwrap()];
+
+        let original = content(1000);
+        let original_path = test_dir.path().join("original");
+        fs::write(&original_path, &original).expect("Unable to write file");
+        let encrypted_dir = test_dir.path().join("encrypted");
+        fs::create_dir_all(&encrypted_dir).expect("Unable to create encrypted_dir");
+        let encryptor = RsaHandler::encryptor_with_recipients(&public_key_paths, &encrypted_dir)
+            .expect("Unable to create encryptor");
+        let encrypted_path = match encryptor.transform(&original_path) {
+            Ok(Transformed::Processed(_, path)) => path,
+            _ => panic!("Result is not 'processed'"),
+        };
+
+        for (i, private_key_path) in private_key_paths.iter().enumerate() {
+            let decrypted_dir = test_dir.path().join(format!("decrypted{}", i));
+            fs::create_dir_all(&decrypted_dir).expect("Unable to create decrypted_dir");
+            let decryptor = RsaHandler::decryptor(private_key_path, &decrypted_dir)
+                .expect("Unable to create decryptor");
+            let decrypted_path = match decryptor.transform(&encrypted_path) {
+                Ok(Transformed::Processed(_, path)) => path,
+                _ => panic!("Result is not 'processed'"),
+            };
+            let decrypted = fs::read(decrypted_path).expect("Unable to read decrypted file");
+            assert_eq!(original, decrypted);
+        }
+    }
+
+    #[test]
+    fn should_show_key_info() {
+        let test_dir = tempfile::TempDir::new().expect("Unable to create temp dir");
+        let mut infos = Vec::new();
+        for i in 0..2 {
+            let (private_key, public_key) = generate_keys(2048).expect("Unable to create keys");
+            let public_key_path = test_dir.path().join(format!("public{}.key", i));
+            let mut file = File::create(&public_key_path).expect("Unable to create file");
+            write_public_key(&mut file, public_key).expect("Unable to write public key");
+            let private_key_path = test_dir.path().join(format!("private{}.key", i));
+            let mut file = File::create(&private_key_path).expect("Unable to create file");
+            write_encrypted_private_key(&mut file, private_key, "secret")
+                .expect("Unable to write private key");
+            let public = RsaHandler::read_key(&public_key_path, None).expect("No public key");
+            let private =
+                RsaHandler::read_key(&private_key_path, Some("secret")).expect("No private key");
+            let public = key_info(&public).expect("No info").remove(0);
+            let private = key_info(&private).expect("No info").remove(0);
+            assert_eq!(public.kind, KeyKind::Public);
+            assert_eq!(private.kind, KeyKind::Private);
+            assert_eq!(public.bits, 2048);
+            assert_eq!(private.bits, 2048);
+            infos.push((public, private));
+        }
+        assert!(infos[0].0.is_pair_of(&infos[0].1));
+        assert!(infos[1].1.is_pair_of(&infos[1].0));
+        assert!(!infos[0].0.is_pair_of(&infos[1].1));
+        assert!(!infos[0].0.is_pair_of(&infos[0].0));
+    }
+
+    #[test]
+    fn should_write_keys_to_dir() {
+        let test_dir = tempfile::TempDir::new().expect("Unable to create temp dir");
+        let (private_key, public_key) = generate_keys(2048).expect("Unable to create keys");
+        let written = write_keys_to_dir(test_dir.path(), private_key.clone(), Some("secret"))
+            .expect("Unable to write keys");
+        assert_eq!(written, fingerprint(&public_key).expect("No fingerprint"));
+
+        let public_key_path = test_dir.path().join(PUBLIC_KEY_FILE);
+        let private_key_path = test_dir.path().join(PRIVATE_KEY_FILE);
+        #[cfg(unix)]
+        {
+            let mode = |path| {
+                fs::metadata(path)
+                    .expect("No metadata")
+                    .permissions()
+                    .mode()
+                    & 0o777
+            };
+            assert_eq!(mode(&public_key_path), 0o644);
+            assert_eq!(mode(&private_key_path), 0o600);
+        }
+        let private = RsaHandler::read_key(&private_key_path, Some("secret")).expect("No key");
+        assert_eq!(key_info(&private).expect("No info")[0].fingerprint, written);
+
+        let public_key_before = fs::read(&public_key_path).expect("Unable to read key");
+        let result = write_keys_to_dir(test_dir.path(), private_key, None);
+        assert!(matches!(result, Err(ShowKeyError::KeyFileExists(_))));
+        assert_eq!(
+            public_key_before,
+            fs::read(&public_key_path).expect("No key")
+        );
+        assert!(is_encrypted_private_key(&private_key_path).expect("Unable to read key"));
+    }
+
+    #[test]
+    fn should_reject_modified_files() {
+        let (private_key, public_key) =
+            generate_keys(DEFAULT_KEY_BITS).expect("Unable to create keys");
+        let test_dir = tempfile::TempDir::new().expect("Unable to create temp dir");
+        let public_key_path = test_dir.path().join("public.key");
+        let mut public_key_file = File::create(&public_key_path).expect("Unable to create file");
+        write_public_key(&mut public_key_file, public_key).expect("Unable to write public key");
+        public_key_file.flush().expect("Unable to flush file");
+        let private_key_path = test_dir.path().join("private.key");
+        let mut private_key_file = File::create(&private_key_path).expect("Unable to create file");
+        write_private_key(&mut private_key_file, private_key).expect("Unable to write private key");
+        private_key_file.flush().expect("Unable to flush file");
+
+        // Three full chunks and a partial one.
+        let original_path = test_dir.path().join("original");
+        fs::write(&original_path, content(3 * 65536 + 100)).expect("Unable to write file");
+        let encrypted_dir = test_dir.path().join("encrypted");
+        fs::create_dir_all(&encrypted_dir).expect("Unable to create encrypted_dir");
+        let encryptor = RsaHandler::encryptor(&public_key_path, &encrypted_dir)
+            .expect("Unable to create encryptor");
+        let encrypted_path = match encryptor.transform(&original_path) {
+            Ok(Transformed::Processed(_, path)) => path,
+            _ => panic!("Result is not 'processed'"),
+        };
+        let encrypted = fs::read(&encrypted_path).expect("Unable to read encrypted file");
+        let mut body = encrypted.as_slice();
+        Header::read(&mut body).expect("Unable to read header");
+        // Header followed by the length of sealed metadata and the metadata itself.
+        let metadata_len = u32::from_be_bytes(body[..4].try_into().expect("No metadata"));
+        let header_len = encrypted.len() - body.len() + 4 + metadata_len as usize;
+        let chunk_len = 65536 + TAG_SIZE;
+        let header = &encrypted[..header_len];
+        let chunk =
+            |i: usize| &encrypted[header_len + i * chunk_len..header_len + (i + 1) * chunk_len];
+
+        let truncated = encrypted[..header_len + 3 * chunk_len].to_vec();
+        let reordered = [
+            header,
+            chunk(1),
+            chunk(0),
+            chunk(2),
+            &encrypted[header_len + 3 * chunk_len..],
+        ]
+        .concat();
+        let header_only = header.to_vec();
+        let mut modified = encrypted.clone();
+        modified[header_len + 10] ^= 1;
+        let mut extended = encrypted.clone();
+        extended.extend_from_slice(chunk(2));
+
+        let decrypted_dir = test_dir.path().join("decrypted");
+        fs::create_dir_all(&decrypted_dir).expect("Unable to create decrypted_dir");
+        let decryptor = RsaHandler::decryptor(&private_key_path, &decrypted_dir)
+            .expect("Unable to create decryptor");
+        for (name, bytes) in [
+            ("truncated", truncated),
+            ("reordered", reordered),
+            ("header_only", header_only),
+            ("modified", modified),
+            ("extended", extended),
+        ] {
+            let path = test_dir.path().join(name);
+            fs::write(&path, bytes).expect("Unable to write file");
+            assert!(
+                decryptor.transform(&path).is_err(),
+                "{} file decrypted",
+                name
+            );
+        }
+        assert!(decryptor.transform(&encrypted_path).is_ok());
     }
 
-    fn test_file(len: u64) {
+    fn test_file(len: u64, cipher: CipherSuite, bits: usize) {
         const ORIGINAL_FILE_NAME: &str = "original.txt";
 
         let start = Instant::now();
 
         // Get keys.
-        let (private_key, public_key) = generate_keys().expect("Unable to create keys");
+        let (private_key, public_key) = generate_keys(bits).expect("Unable to create keys");
         println!("Generated keys after {:?}", start.elapsed());
         let test_dir = tempfile::TempDir::new().expect("Unable to create temp dir");
 
@@ -52,13 +395,17 @@ mod test {
             .write_all(&content(len))
             .expect("Unable to write to original file");
         original_file.flush().expect("Unable to flash file");
+        #[cfg(unix)]
+        fs::set_permissions(&original_file_path, Permissions::from_mode(0o750))
+            .expect("Unable to set permissions");
         println!("Created files after {:?}", start.elapsed());
 
         // Encrypt file.
         let target_dir = test_dir.path().join("target");
         fs::create_dir_all(&target_dir).expect("Unable to create target_dir");
         let encryptor = RsaHandler::encryptor(&public_key_path, &target_dir)
-            .expect("Unable to create encryptor");
+            .expect("Unable to create encryptor")
+            .with_cipher(cipher);
         println!("Created encryptor after {:?}", start.elapsed());
         let result = encryptor
             .transform(&original_file_path)
@@ -69,6 +416,8 @@ mod test {
             panic!("Result is not 'processed'");
         };
         assert!(encrypted.1.is_file());
+        let encrypted_len = encrypted.1.metadata().expect("No metadata").len();
+        assert!(encrypted_len < len + 1024 + len / 1024);
         println!("Encrypted after {:?}", start.elapsed());
 
         // Decrypt file.
@@ -94,6 +443,14 @@ mod test {
         let decrypted_content = fs::read(&decrypted_path).expect("Unable to read decrypted file");
         assert_eq!(origin_content, decrypted_content);
         assert_eq!(origin_content.len(), len as usize);
+        let origin_metadata = original_file_path.metadata().expect("No metadata");
+        let decrypted_metadata = decrypted_path.metadata().expect("No metadata");
+        #[cfg(unix)]
+        assert_eq!(decrypted_metadata.permissions().mode() & 0o7777, 0o750);
+        assert_eq!(
+            decrypted_metadata.modified().expect("No mtime"),
+            origin_metadata.modified().expect("No mtime")
+        );
         println!("Compared after {:?}", start.elapsed());
     }
 